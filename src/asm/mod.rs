@@ -6,20 +6,38 @@ pub enum ValidationError {
     MoreThanOneModStatement,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Module {
-    pub stmts: Vec<Statement>
+    pub stmts: Vec<Statement>,
+    /// Source locations of `stmts` (empty if the module wasn't parsed from source)
+    pub locations: Vec<Location>,
+    /// Name of the file the module was parsed from, if any
+    pub file: Option<String>,
+}
+
+/// Locations and the file name are debug metadata, so two modules are equal if their
+/// statements are equal.
+impl PartialEq for Module {
+    fn eq(&self, other: &Module) -> bool {
+        self.stmts == other.stmts
+    }
 }
 
 impl Module {
     pub fn new() -> Module {
         Module {
             stmts: Vec::new(),
+            locations: Vec::new(),
+            file: None,
         }
     }
 
     pub fn with_stmts(stmts: Vec<Statement>) -> Module {
-        Module { stmts: stmts }
+        Module { stmts: stmts, locations: vec![], file: None, }
+    }
+
+    pub fn with_located_stmts(stmts: Vec<Statement>, locations: Vec<Location>) -> Module {
+        Module { stmts: stmts, locations: locations, file: None, }
     }
 
     pub fn push_mod(&mut self, m: Mod) {
@@ -67,20 +85,85 @@ impl Module {
         Ok(())
     }
 
+    /// Converts all `Location::Remaining` locations recorded by the parser into line and
+    /// column locations within the given `source`.
+    pub fn resolve_locations(&mut self, source: &[u8]) {
+        let resolve = |locations: &mut Vec<Location>| {
+            for location in locations.iter_mut() {
+                *location = location.resolve(source);
+            }
+        };
+
+        resolve(&mut self.locations);
+
+        for stmt in self.stmts.iter_mut() {
+            stmt.each_block_mut(&mut |block| resolve(&mut block.locations));
+        }
+    }
+}
+
+/// Position of a statement in the source text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    /// Recorded by the parser: the number of bytes of input remaining when the statement began
+    Remaining(usize),
+    /// Line and column (both starting at 1) in the source text
+    Source { line: u32, column: u32 },
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Location {
+    fn resolve(&self, source: &[u8]) -> Location {
+        let remaining = match *self {
+            Location::Remaining(remaining) => remaining,
+            Location::Source { .. } => return *self,
+        };
+
+        let offset = source.len() - remaining;
+        let preceding = &source[0..offset];
+
+        let line = preceding.iter().filter(|b| **b == b'\n').count() + 1;
+        let line_start = match preceding.iter().rposition(|b| *b == b'\n') {
+            Some(idx) => idx + 1,
+            None => 0,
+        };
+
+        Location::Source {
+            line: line as u32,
+            column: (offset - line_start + 1) as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct BasicBlock {
-    pub stmts: Vec<Statement>
+    pub stmts: Vec<Statement>,
+    /// Source locations of `stmts` (empty if the block wasn't parsed from source)
+    pub locations: Vec<Location>,
+}
+
+/// As with `Module` the locations don't participate in equality.
+impl PartialEq for BasicBlock {
+    fn eq(&self, other: &BasicBlock) -> bool {
+        self.stmts == other.stmts
+    }
 }
 
 impl BasicBlock {
     fn new() -> BasicBlock {
-        BasicBlock { stmts: vec![], }
+        BasicBlock { stmts: vec![], locations: vec![], }
     }
 
     pub fn with_stmts(stmts: Vec<Statement>) -> BasicBlock {
-        BasicBlock { stmts: stmts, }
+        BasicBlock { stmts: stmts, locations: vec![], }
+    }
+
+    pub fn with_located_stmts(stmts: Vec<Statement>, locations: Vec<Location>) -> BasicBlock {
+        BasicBlock { stmts: stmts, locations: locations, }
+    }
+
+    /// Location of the statement at the given index, if it is known.
+    pub fn location(&self, idx: usize) -> Option<Location> {
+        self.locations.get(idx).cloned()
     }
 }
 
@@ -103,6 +186,62 @@ pub enum Statement {
     StatementWhile(While),
     StatementDo(Do),
     StatementBreak,
+    StatementSwitch(Switch),
+    StatementThrow(Throw),
+    StatementTry(Try),
+}
+
+impl Statement {
+    /// Calls `f` on every basic block nested anywhere within this statement.
+    fn each_block_mut<F: FnMut(&mut BasicBlock)>(&mut self, f: &mut F) {
+        fn visit_block<F: FnMut(&mut BasicBlock)>(block: &mut BasicBlock, f: &mut F) {
+            f(block);
+
+            for stmt in block.stmts.iter_mut() {
+                stmt.each_block_mut(f);
+            }
+        }
+
+        fn visit_value<F: FnMut(&mut BasicBlock)>(value: &mut Value, f: &mut F) {
+            if let Value::Fn(ref mut anonymous) = *value {
+                visit_block(&mut anonymous.body, f)
+            }
+        }
+
+        match *self {
+            Statement::StatementDefn(ref mut d)       => visit_block(&mut d.body, f),
+            Statement::StatementFn(ref mut anonymous) => visit_block(&mut anonymous.body, f),
+            Statement::StatementAssignment(ref mut a) => visit_value(&mut a.rvalue, f),
+            Statement::StatementReturn(ref mut r) => {
+                if let Some(ref mut value) = r.value {
+                    visit_value(value, f)
+                }
+            },
+            Statement::StatementIf(ref mut i) => {
+                visit_block(&mut i.condition, f);
+                visit_block(&mut i.then_sibling.body, f);
+
+                if let Some(ref mut else_sibling) = i.then_sibling.else_sibling {
+                    visit_block(&mut else_sibling.body, f)
+                }
+            },
+            Statement::StatementThrow(ref mut t) => visit_value(&mut t.value, f),
+            Statement::StatementTry(ref mut t) => {
+                visit_block(&mut t.body, f);
+                visit_block(&mut t.handler, f);
+            },
+            Statement::StatementSwitch(ref mut s) => {
+                for case in s.cases.iter_mut() {
+                    visit_block(&mut case.body, f)
+                }
+
+                if let Some(ref mut default) = s.default {
+                    visit_block(default, f)
+                }
+            },
+            _ => (),
+        }
+    }
 }
 
 /// Represents any node that can potentially act as a value in the assembly AST.
@@ -173,6 +312,11 @@ impl Path {
         self.segments.last().unwrap().starts_with("$")
     }
 
+    /// The name if the path is just one (eg. `f` rather than `a.f`).
+    pub fn as_name(&self) -> Option<&Name> {
+        if self.segments.len() == 1 { self.segments.first() } else { None }
+    }
+
     pub fn with_name(name: Name) -> Path {
         Path::new(vec![name]).unwrap()
     }
@@ -204,7 +348,7 @@ impl Mod {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Extern {
-    path: Path,
+    pub path: Path,
 }
 
 impl Extern {
@@ -218,6 +362,8 @@ pub struct Const {
     pub name: Name,
     pub constructor: Path,
     pub argument: Option<String>,
+    /// Whether other modules may use the const
+    pub export: bool,
 }
 
 impl Const {
@@ -226,18 +372,29 @@ impl Const {
             name: name,
             constructor: constructor,
             argument: argument,
+            export: false,
         }
     }
+
+    pub fn with_export(self, export: bool) -> Const {
+        Const { export: export, ..self }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Static {
     pub name: Name,
+    /// Whether other modules may use the static
+    pub export: bool,
 }
 
 impl Static {
     pub fn new(name: Name) -> Static {
-        Static { name: name }
+        Static { name: name, export: false, }
+    }
+
+    pub fn with_export(self, export: bool) -> Static {
+        Static { export: export, ..self }
     }
 }
 
@@ -290,7 +447,11 @@ impl Assignment {
 pub struct Defn {
     pub name: Name,
     pub parameters: Vec<Name>,
+    /// Parameter collecting any further arguments into a list (`...rest`)
+    pub rest: Option<Name>,
     pub body: BasicBlock,
+    /// Whether other modules may call the function
+    pub export: bool,
 }
 
 impl Defn {
@@ -298,27 +459,43 @@ impl Defn {
         Defn {
             name: name,
             parameters: parameters,
+            rest: None,
             body: body,
+            export: false,
         }
     }
+
+    pub fn with_rest(self, rest: Option<Name>) -> Defn {
+        Defn { rest: rest, ..self }
+    }
+
+    pub fn with_export(self, export: bool) -> Defn {
+        Defn { export: export, ..self }
+    }
 }
 
 /// Represents an anonymous function value.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Fn {
     pub parameters: Vec<Name>,
+    /// Parameter collecting any further arguments into a list (`...rest`)
+    pub rest: Option<Name>,
     pub body: BasicBlock,
 }
 
 impl Fn {
     pub fn new(parameters: Vec<Name>, body: BasicBlock) -> Fn {
-        Fn { parameters: parameters, body: body, }
+        Fn { parameters: parameters, rest: None, body: body, }
+    }
+
+    pub fn with_rest(self, rest: Option<Name>) -> Fn {
+        Fn { rest: rest, ..self }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Return {
-    value: Option<Value>,
+    pub value: Option<Value>,
 }
 
 impl Return {
@@ -364,6 +541,70 @@ pub struct Else {
     body: BasicBlock,
 }
 
+/// Multi-way branch on the value of a local. Each case compares the local to a const; the first
+/// identical one has its body run, otherwise the default body (if any) is run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Switch {
+    pub test: Name,
+    pub cases: Vec<Case>,
+    pub default: Option<BasicBlock>,
+}
+
+impl Switch {
+    pub fn new(test: Name, cases: Vec<Case>, default: Option<BasicBlock>) -> Switch {
+        Switch {
+            test: test,
+            cases: cases,
+            default: default,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    /// Path of the const the test value is compared against
+    pub value: Path,
+    pub body: BasicBlock,
+}
+
+impl Case {
+    pub fn new(value: Path, body: BasicBlock) -> Case {
+        Case { value: value, body: body, }
+    }
+}
+
+/// Throws a value as an exception; it's caught by the nearest enclosing `try`, which may be in
+/// a calling function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Throw {
+    pub value: Value,
+}
+
+impl Throw {
+    pub fn new(value: Value) -> Throw {
+        Throw { value: value }
+    }
+}
+
+/// Runs `body`; if it throws then the thrown value is assigned to the local `name` and
+/// `handler` is run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Try {
+    pub body: BasicBlock,
+    pub name: Name,
+    pub handler: BasicBlock,
+}
+
+impl Try {
+    pub fn new(body: BasicBlock, name: Name, handler: BasicBlock) -> Try {
+        Try {
+            body: body,
+            name: name,
+            handler: handler,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct While {
     body: BasicBlock,
@@ -0,0 +1,495 @@
+//! On-disk format for compiled modules (`.hbc` files).
+//!
+//! All integers are little-endian. A file is laid out as:
+//!
+//! - Magic number: `b"HBC\0"`
+//! - Format version: `u16`
+//! - Payload length: `u32`
+//! - Adler-32 checksum of the payload: `u32`
+//! - Payload: name, operand encoding, code, functions, consts, statics, externs, exports,
+//!   relocations, exception handlers, and debug information
+//!
+//! Strings and byte arrays are written as a `u32` length followed by their bytes, lists as a
+//! `u32` count followed by their items, and optional values as a `u8` tag (0 = none, 1 = some)
+//! followed by the value.
+
+use super::{
+    CompiledModule,
+    CompiledRelocationTarget,
+    DebugInfo,
+    Handler,
+    LineEntry,
+};
+
+use vm::bytecode::util::Encoding;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Cursor, Read, Write};
+
+pub const MAGIC: &'static [u8; 4] = b"HBC\0";
+pub const VERSION: u16 = 5;
+
+#[derive(Debug)]
+pub enum HbcError {
+    /// Error from the underlying reader or writer
+    Io(io::Error),
+    /// The file doesn't start with `MAGIC`
+    BadMagic,
+    /// The file was written in a format version this build can't read
+    UnsupportedVersion(u16),
+    /// The payload doesn't match its checksum
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// The file or payload ended early
+    Truncated,
+    /// A string in the payload isn't UTF-8
+    InvalidString,
+    /// Unknown tag for an optional value or relocation target
+    InvalidTag(u8),
+}
+
+pub type HbcResult<T> = Result<T, HbcError>;
+
+impl From<io::Error> for HbcError {
+    fn from(error: io::Error) -> HbcError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => HbcError::Truncated,
+            _ => HbcError::Io(error),
+        }
+    }
+}
+
+impl From<::byteorder::Error> for HbcError {
+    fn from(error: ::byteorder::Error) -> HbcError {
+        match error {
+            ::byteorder::Error::UnexpectedEOF => HbcError::Truncated,
+            ::byteorder::Error::Io(error) => HbcError::from(error),
+        }
+    }
+}
+
+/// Adler-32 checksum of the given bytes.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    for byte in bytes {
+        a = (a + *byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+
+    (b << 16) | a
+}
+
+/// Writes the parts of a payload into a buffer.
+struct PayloadWriter {
+    bytes: Vec<u8>,
+}
+
+impl PayloadWriter {
+    fn write_u8(&mut self, u: u8)   { self.bytes.push(u) }
+    fn write_u32(&mut self, u: u32) { self.bytes.write_u32::<LittleEndian>(u).unwrap() }
+    fn write_u64(&mut self, u: u64) { self.bytes.write_u64::<LittleEndian>(u).unwrap() }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn write_string(&mut self, s: &String) {
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn write_option_string(&mut self, s: &Option<String>) {
+        match s {
+            &Some(ref s) => { self.write_u8(1); self.write_string(s) },
+            &None        => self.write_u8(0),
+        }
+    }
+}
+
+/// Reads the parts of a payload back out of a buffer.
+struct PayloadReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn read_u8(&mut self) -> HbcResult<u8>   { Ok(try!(self.cursor.read_u8())) }
+    fn read_u32(&mut self) -> HbcResult<u32> { Ok(try!(self.cursor.read_u32::<LittleEndian>())) }
+    fn read_u64(&mut self) -> HbcResult<u64> { Ok(try!(self.cursor.read_u64::<LittleEndian>())) }
+
+    fn read_bytes(&mut self) -> HbcResult<Vec<u8>> {
+        let len = try!(self.read_u32()) as usize;
+        let remaining = self.cursor.get_ref().len() - self.cursor.position() as usize;
+
+        // Check before allocating so that a corrupt length can't request a huge buffer
+        if len > remaining {
+            return Err(HbcError::Truncated)
+        }
+
+        let mut bytes = vec![0; len];
+        try!(self.cursor.read_exact(&mut bytes));
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> HbcResult<String> {
+        let bytes = try!(self.read_bytes());
+        String::from_utf8(bytes).map_err(|_| HbcError::InvalidString)
+    }
+
+    fn read_option_string(&mut self) -> HbcResult<Option<String>> {
+        match try!(self.read_u8()) {
+            0   => Ok(None),
+            1   => Ok(Some(try!(self.read_string()))),
+            tag => Err(HbcError::InvalidTag(tag)),
+        }
+    }
+
+    fn read_list<T, F>(&mut self, mut read_item: F) -> HbcResult<Vec<T>>
+        where F: FnMut(&mut PayloadReader<'a>) -> HbcResult<T> {
+
+        let count = try!(self.read_u32());
+        let mut items = vec![];
+
+        for _ in 0..count {
+            items.push(try!(read_item(self)));
+        }
+
+        Ok(items)
+    }
+}
+
+const ENCODING_FIXED: u8 = 0;
+const ENCODING_COMPACT: u8 = 1;
+
+const RELOCATION_INTERNAL_ADDRESS: u8 = 0;
+const RELOCATION_EXTERNAL_FUNCTION_PATH: u8 = 1;
+const RELOCATION_CONST_PATH: u8 = 2;
+
+impl CompiledModule {
+    /// Write the module in the `.hbc` format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> HbcResult<()> {
+        let payload = self.payload();
+
+        try!(writer.write_all(MAGIC));
+        try!(writer.write_u16::<LittleEndian>(VERSION));
+        try!(writer.write_u32::<LittleEndian>(payload.len() as u32));
+        try!(writer.write_u32::<LittleEndian>(checksum(&payload)));
+        try!(writer.write_all(&payload));
+
+        Ok(())
+    }
+
+    /// Read a module in the `.hbc` format.
+    pub fn read_from<R: Read>(reader: &mut R) -> HbcResult<CompiledModule> {
+        let mut magic = [0; 4];
+        try!(reader.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(HbcError::BadMagic)
+        }
+
+        let version = try!(reader.read_u16::<LittleEndian>());
+        if version != VERSION {
+            return Err(HbcError::UnsupportedVersion(version))
+        }
+
+        let length = try!(reader.read_u32::<LittleEndian>()) as usize;
+        let expected_checksum = try!(reader.read_u32::<LittleEndian>());
+
+        let mut payload = vec![];
+        try!(reader.take(length as u64).read_to_end(&mut payload));
+        if payload.len() < length {
+            return Err(HbcError::Truncated)
+        }
+
+        let found_checksum = checksum(&payload);
+        if found_checksum != expected_checksum {
+            return Err(HbcError::ChecksumMismatch {
+                expected: expected_checksum,
+                found: found_checksum,
+            })
+        }
+
+        CompiledModule::from_payload(&payload)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut w = PayloadWriter { bytes: vec![], };
+
+        w.write_string(&self.name);
+        w.write_u8(match self.encoding {
+            Encoding::Fixed   => ENCODING_FIXED,
+            Encoding::Compact => ENCODING_COMPACT,
+        });
+        w.write_bytes(&self.code);
+
+        w.write_u32(self.functions.len() as u32);
+        for &(ref name, addr) in self.functions.iter() {
+            w.write_string(name);
+            w.write_u64(addr);
+        }
+
+        w.write_u32(self.consts.len() as u32);
+        for &(ref name, ref constructor, ref argument) in self.consts.iter() {
+            w.write_string(name);
+            w.write_string(constructor);
+            w.write_option_string(argument);
+        }
+
+        w.write_u32(self.statics.len() as u32);
+        for name in self.statics.iter() {
+            w.write_string(name);
+        }
+
+        w.write_u32(self.externs.len() as u32);
+        for name in self.externs.iter() {
+            w.write_string(name);
+        }
+
+        w.write_u32(self.exports.len() as u32);
+        for name in self.exports.iter() {
+            w.write_string(name);
+        }
+
+        w.write_u32(self.relocations.len() as u32);
+        for &(site, ref target) in self.relocations.iter() {
+            w.write_u64(site);
+
+            match target {
+                &CompiledRelocationTarget::InternalAddress(addr) => {
+                    w.write_u8(RELOCATION_INTERNAL_ADDRESS);
+                    w.write_u64(addr);
+                },
+                &CompiledRelocationTarget::ExternalFunctionPath(ref path) => {
+                    w.write_u8(RELOCATION_EXTERNAL_FUNCTION_PATH);
+                    w.write_string(path);
+                },
+                &CompiledRelocationTarget::ConstPath(ref path) => {
+                    w.write_u8(RELOCATION_CONST_PATH);
+                    w.write_string(path);
+                },
+            }
+        }
+
+        w.write_u32(self.handlers.len() as u32);
+        for handler in self.handlers.iter() {
+            w.write_u64(handler.start);
+            w.write_u64(handler.end);
+            w.write_u64(handler.target);
+        }
+
+        w.write_option_string(&self.debug.file);
+
+        w.write_u32(self.debug.lines.len() as u32);
+        for entry in self.debug.lines.iter() {
+            w.write_u64(entry.start);
+            w.write_u64(entry.end);
+            w.write_u32(entry.line);
+            w.write_u32(entry.column);
+            w.write_string(&entry.function);
+        }
+
+        w.write_u32(self.debug.locals.len() as u32);
+        for &(addr, ref names) in self.debug.locals.iter() {
+            w.write_u64(addr);
+            w.write_u32(names.len() as u32);
+            for name in names.iter() {
+                w.write_string(name);
+            }
+        }
+
+        w.bytes
+    }
+
+    fn from_payload(payload: &[u8]) -> HbcResult<CompiledModule> {
+        let mut r = PayloadReader { cursor: Cursor::new(payload), };
+
+        let name = try!(r.read_string());
+        let encoding = match try!(r.read_u8()) {
+            ENCODING_FIXED   => Encoding::Fixed,
+            ENCODING_COMPACT => Encoding::Compact,
+            tag              => return Err(HbcError::InvalidTag(tag)),
+        };
+        let code = try!(r.read_bytes());
+
+        let functions = try!(r.read_list(|r| {
+            Ok((try!(r.read_string()), try!(r.read_u64())))
+        }));
+
+        let consts = try!(r.read_list(|r| {
+            Ok((try!(r.read_string()), try!(r.read_string()), try!(r.read_option_string())))
+        }));
+
+        let statics = try!(r.read_list(|r| r.read_string()));
+
+        let externs = try!(r.read_list(|r| r.read_string()));
+
+        let exports = try!(r.read_list(|r| r.read_string()));
+
+        let relocations = try!(r.read_list(|r| {
+            let site = try!(r.read_u64());
+
+            let target = match try!(r.read_u8()) {
+                RELOCATION_INTERNAL_ADDRESS => {
+                    CompiledRelocationTarget::InternalAddress(try!(r.read_u64()))
+                },
+                RELOCATION_EXTERNAL_FUNCTION_PATH => {
+                    CompiledRelocationTarget::ExternalFunctionPath(try!(r.read_string()))
+                },
+                RELOCATION_CONST_PATH => {
+                    CompiledRelocationTarget::ConstPath(try!(r.read_string()))
+                },
+                tag => return Err(HbcError::InvalidTag(tag)),
+            };
+
+            Ok((site, target))
+        }));
+
+        let handlers = try!(r.read_list(|r| {
+            Ok(Handler {
+                start: try!(r.read_u64()),
+                end: try!(r.read_u64()),
+                target: try!(r.read_u64()),
+            })
+        }));
+
+        let file = try!(r.read_option_string());
+
+        let lines = try!(r.read_list(|r| {
+            Ok(LineEntry {
+                start: try!(r.read_u64()),
+                end: try!(r.read_u64()),
+                line: try!(r.read_u32()),
+                column: try!(r.read_u32()),
+                function: try!(r.read_string()),
+            })
+        }));
+
+        let locals = try!(r.read_list(|r| {
+            Ok((try!(r.read_u64()), try!(r.read_list(|r| r.read_string()))))
+        }));
+
+        Ok(CompiledModule {
+            name: name,
+            code: code,
+            functions: functions,
+            consts: consts,
+            statics: statics,
+            externs: externs,
+            exports: exports,
+            relocations: relocations,
+            debug: DebugInfo {
+                file: file,
+                lines: lines,
+                locals: locals,
+            },
+            encoding: encoding,
+            handlers: handlers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, HbcError};
+    use asm_compiler::{
+        CompiledModule,
+        CompiledRelocationTarget,
+        DebugInfo,
+        Handler,
+        LineEntry,
+    };
+    use vm::bytecode::util::Encoding;
+
+    fn module() -> CompiledModule {
+        CompiledModule {
+            name: "foo".to_owned(),
+            code: vec![0, 1, 2, 3],
+            functions: vec![("bar".to_owned(), 0)],
+            consts: vec![
+                ("@a".to_owned(), "_.std.string.new".to_owned(), Some("b".to_owned())),
+                ("@c".to_owned(), "_.std.null".to_owned(), None),
+            ],
+            statics: vec!["$d".to_owned()],
+            externs: vec!["baz".to_owned()],
+            exports: vec!["bar".to_owned(), "@a".to_owned()],
+            relocations: vec![
+                (1, CompiledRelocationTarget::InternalAddress(0)),
+                (2, CompiledRelocationTarget::ExternalFunctionPath("baz.qux".to_owned())),
+                (3, CompiledRelocationTarget::ConstPath("@a".to_owned())),
+            ],
+            debug: DebugInfo {
+                file: Some("foo.hasm".to_owned()),
+                lines: vec![
+                    LineEntry { start: 0, end: 4, line: 2, column: 3, function: "bar".to_owned() },
+                ],
+                locals: vec![(0, vec!["x".to_owned()])],
+            },
+            encoding: Encoding::Compact,
+            handlers: vec![Handler { start: 0, end: 2, target: 3, }],
+        }
+    }
+
+    fn write(module: &CompiledModule) -> Vec<u8> {
+        let mut bytes = vec![];
+        module.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trips_module() {
+        let bytes = write(&module());
+
+        assert_eq!(&bytes[0..4], b"HBC\0");
+
+        let read = CompiledModule::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, module());
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_version() {
+        let mut bytes = write(&module());
+        bytes[0] = b'X';
+        match CompiledModule::read_from(&mut &bytes[..]) {
+            Err(HbcError::BadMagic) => (),
+            other => panic!("Expected BadMagic, got {:?}", other),
+        }
+
+        let mut bytes = write(&module());
+        bytes[4] = 99;
+        match CompiledModule::read_from(&mut &bytes[..]) {
+            Err(HbcError::UnsupportedVersion(99)) => (),
+            other => panic!("Expected UnsupportedVersion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let bytes = write(&module());
+
+        for len in vec![2, 5, 12, bytes.len() - 1] {
+            match CompiledModule::read_from(&mut &bytes[0..len]) {
+                Err(HbcError::Truncated) => (),
+                other => panic!("Expected Truncated for length {}, got {:?}", len, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_payload() {
+        let mut bytes = write(&module());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        match CompiledModule::read_from(&mut &bytes[..]) {
+            Err(HbcError::ChecksumMismatch { .. }) => (),
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn computes_adler32() {
+        assert_eq!(checksum(b"Wikipedia"), 0x11E60398);
+    }
+}
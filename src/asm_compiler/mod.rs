@@ -2,11 +2,16 @@ use asm;
 use asm::Statement::*;
 use asm::AssignmentOp;
 use vm::bytecode::ops::*;
+use vm::bytecode::util::Encoding;
 
+use std::cell::Cell;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
+/// Reading and writing compiled modules as `.hbc` files.
+pub mod hbc;
+
 type ByteVec = Vec<u8>;
 pub type OpVec = Vec<Op>;
 
@@ -27,6 +32,9 @@ impl OpVecExt for OpVec {
 enum Op {
     Owned(BOp),
     Shared(Rc<BOp>),
+    /// Marks the start of the ops of the statement at the given line and column; doesn't
+    /// produce any bytecode.
+    Line(u32, u32),
 }
 
 /// Internal storage for all the locals in a LocalContext.
@@ -49,11 +57,11 @@ impl Locals {
     fn len(&self) -> usize { self.locals.len() }
 
     fn find(&self, local: String) -> Result<u16, String> {
-        let result = self.locals.binary_search(&local);
+        let result = self.locals.iter().position(|l| *l == local);
 
         match result {
-            Ok(idx) => Ok(idx as u16),
-            Err(_) => Err(format!("Local not found: {:?}", local)),
+            Some(idx) => Ok(idx as u16),
+            None => Err(format!("Local not found: {:?}", local)),
         }
     }
 }
@@ -62,6 +70,8 @@ impl Locals {
 /// own `LocalContext`.
 pub struct LocalContext {
     pub locals: Locals,
+    /// Number of `try` bodies enclosing the code being compiled
+    pub try_depth: Cell<usize>,
 }
 pub type LocalContextRef<'a> = Option<&'a LocalContext>;
 
@@ -82,7 +92,7 @@ pub enum RelocationTarget {
     InternalFunctionAddress(Rc<Function>),
     /// Absolute string version of the path to the external function
     ExternalFunctionPath(String),
-
+    /// Path to a const or function loaded as a value, or to a static (its name starts with `$`)
     ConstPath(String),
 }
 
@@ -94,6 +104,8 @@ pub enum RelocationTarget {
 pub struct Relocation {
     /// Site that must have its address relocated
     pub site: Rc<BOp>,
+    /// Which of the site's address fields to relocate (see `BOp::addr_field_offset`)
+    pub field: usize,
     /// Where this site should eventually point to
     pub target: RelocationTarget,
 }
@@ -109,6 +121,18 @@ pub enum FunctionName {
 pub struct Function {
     pub name: FunctionName,
     pub ops: OpVec,
+    /// Names of the function's local slots (parameters first)
+    pub locals: Vec<String>,
+}
+
+impl FunctionName {
+    /// Name to use for the function in debug information.
+    fn debug_name(&self) -> String {
+        match *self {
+            FunctionName::Named(ref name) => name.clone(),
+            FunctionName::Anonymous       => "<anonymous>".to_owned(),
+        }
+    }
 }
 
 /// 3-tuple of the name, constructor path, and optional argument.
@@ -123,6 +147,13 @@ pub struct Module {
     pub functions: Vec<Rc<Function>>,
     pub consts: Vec<CompiledConst>,
     pub statics: Vec<String>,
+    /// Names of the modules declared with `extern`
+    pub externs: Vec<String>,
+    /// Names of the functions, consts and statics other modules may use
+    pub exports: Vec<String>,
+    /// Exception handlers as the first op of the protected range, the op after its end, and
+    /// the handler's first op
+    pub handlers: Vec<(Rc<BOp>, Rc<BOp>, Rc<BOp>)>,
 }
 
 trait PointerPartialEq {
@@ -184,6 +215,9 @@ impl Module {
             functions: vec![],
             consts: vec![],
             statics: vec![],
+            externs: vec![],
+            exports: vec![],
+            handlers: vec![],
         }
     }
 
@@ -201,6 +235,7 @@ impl Module {
     fn add_function_relocation(&mut self, site: Rc<BOp>, target: Rc<Function>) {
         self.relocations.push(Relocation {
             site: site,
+            field: 0,
             target: RelocationTarget::InternalFunctionAddress(target),
         })
     }
@@ -208,6 +243,7 @@ impl Module {
     fn add_call_relocation(&mut self, site: Rc<BOp>, target: String) {
         self.relocations.push(Relocation {
             site: site,
+            field: 0,
             target: RelocationTarget::ExternalFunctionPath(target),
         })
     }
@@ -215,6 +251,16 @@ impl Module {
     fn add_branch_relocation(&mut self, site: Rc<BOp>, target: Rc<BOp>) {
         self.relocations.push(Relocation {
             site: site,
+            field: 0,
+            target: RelocationTarget::InternalBranchAddress(target),
+        })
+    }
+
+    /// Like `add_branch_relocation` for ops with more than one address field.
+    fn add_branch_relocation_at(&mut self, site: Rc<BOp>, field: usize, target: Rc<BOp>) {
+        self.relocations.push(Relocation {
+            site: site,
+            field: field,
             target: RelocationTarget::InternalBranchAddress(target),
         })
     }
@@ -222,24 +268,151 @@ impl Module {
     fn add_const_relocation(&mut self, site: Rc<BOp>, target: String) {
         self.relocations.push(Relocation {
             site: site,
+            field: 0,
             target: RelocationTarget::ConstPath(target),
         })
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum CompiledRelocationTarget {
     InternalAddress(u64),
     ExternalFunctionPath(String),
     ConstPath(String),
 }
 
+/// Maps a range of addresses in a module's bytecode back to the statement it was compiled from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineEntry {
+    /// First address of the range
+    pub start: u64,
+    /// Address immediately after the end of the range
+    pub end: u64,
+    pub line: u32,
+    pub column: u32,
+    /// Name of the function containing the statement
+    pub function: String,
+}
+
+/// Debugging information for a compiled module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugInfo {
+    /// File the module was compiled from, if known
+    pub file: Option<String>,
+    /// Line table ordered by address
+    pub lines: Vec<LineEntry>,
+    /// Entry address and local slot names of every function
+    pub locals: Vec<(u64, Vec<String>)>,
+}
+
+impl DebugInfo {
+    /// Find the line table entry whose range contains `addr`.
+    pub fn line_for(&self, addr: u64) -> Option<&LineEntry> {
+        self.lines.iter().find(|entry| entry.start <= addr && addr < entry.end)
+    }
+
+    /// Find the local slot names of the function whose entry is at `addr`.
+    pub fn locals_for(&self, addr: u64) -> Option<&Vec<String>> {
+        self.locals.iter().find(|&&(entry, _)| entry == addr).map(|&(_, ref names)| names)
+    }
+
+    /// Copy of the debug information with all addresses shifted by `base_addr`.
+    pub fn rebase(&self, base_addr: u64) -> DebugInfo {
+        DebugInfo {
+            file: self.file.clone(),
+            lines: self.lines.iter().map(|entry| {
+                LineEntry {
+                    start: base_addr + entry.start,
+                    end: base_addr + entry.end,
+                    .. entry.clone()
+                }
+            }).collect(),
+            locals: self.locals.iter().map(|&(addr, ref names)| {
+                (base_addr + addr, names.clone())
+            }).collect(),
+        }
+    }
+}
+
+/// Catches exceptions thrown by code in the range `start..end` and continues at `target` with
+/// the exception pushed onto the stack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handler {
+    pub start: u64,
+    pub end: u64,
+    pub target: u64,
+}
+
+impl Handler {
+    pub fn covers(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Copy of the handler with all addresses shifted by `base_addr`.
+    pub fn rebase(&self, base_addr: u64) -> Handler {
+        Handler {
+            start: base_addr + self.start,
+            end: base_addr + self.end,
+            target: base_addr + self.target,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct CompiledModule {
     pub name: String,
     pub code: Vec<u8>,
     pub functions: Vec<(String, u64)>,
     pub consts: Vec<CompiledConst>,
     pub statics: Vec<String>,
+    /// Modules that must be loaded before this one
+    pub externs: Vec<String>,
+    /// Names of the functions, consts and statics that other modules may use; the rest are
+    /// private to the module
+    pub exports: Vec<String>,
     pub relocations: Vec<(u64, CompiledRelocationTarget)>,
+    pub debug: DebugInfo,
+    /// Encoding of the operands in `code`
+    pub encoding: Encoding,
+    /// Exception handlers, innermost first
+    pub handlers: Vec<Handler>,
+}
+
+impl CompiledModule {
+    /// Whether the module defines a function, const or static with the given name.
+    pub fn defines(&self, name: &str) -> bool {
+        self.functions.iter().any(|&(ref function, _)| function == name) ||
+            self.consts.iter().any(|&(ref constant, _, _)| constant == name) ||
+            self.statics.iter().any(|stat| stat == name)
+    }
+}
+
+/// Line markers noted while ingesting ops: address, line, and column.
+pub type LineMarks = Vec<(u64, u32, u32)>;
+
+/// Turn the line markers of a run of code ending at `end` into line table entries; each entry
+/// extends until the next marker.
+fn line_entries(marks: LineMarks, end: u64, function: &str) -> Vec<LineEntry> {
+    let mut entries = vec![];
+
+    for (idx, &(start, line, column)) in marks.iter().enumerate() {
+        let entry_end = match marks.get(idx + 1) {
+            Some(&(next, _, _)) => next,
+            None => end,
+        };
+
+        if entry_end > start {
+            entries.push(LineEntry {
+                start: start,
+                end: entry_end,
+                line: line,
+                column: column,
+                function: function.to_owned(),
+            })
+        }
+    }
+
+    entries
 }
 
 use std::collections::HashMap;
@@ -250,26 +423,39 @@ pub type FunctionMap = HashMap<Rc<Function>, u64>;
 pub type CompiledRelocationVec = Vec<(u64, CompiledRelocationTarget)>;
 
 pub trait CompileModule {
-    fn compile(&self) -> CompiledModule;
+    /// Compile using the default (fixed-width) operand encoding.
+    fn compile(&self) -> CompiledModule {
+        self.compile_with_encoding(Encoding::default())
+    }
+
+    fn compile_with_encoding(&self, Encoding) -> CompiledModule;
 }
 
 impl CompileModule for asm::Module {
-    fn compile(&self) -> CompiledModule {
+    fn compile_with_encoding(&self, encoding: Encoding) -> CompiledModule {
         let mut module = Module::new();
 
         let mut op_map: OpMap                 = HashMap::new();
         let mut function_map: FunctionMap     = HashMap::new();
         let mut code: Vec<u8>                 = Vec::new();
         let mut functions: Vec<(String, u64)> = Vec::new();
+        let mut lines: Vec<LineEntry>         = Vec::new();
+        let mut locals                        = Vec::new();
 
         // Compile and ingest the top-level module statements
         {
             let mut module_ops = OpVec::new();
             let ref stmts = self.stmts;
-            for stmt in stmts {
+            for (idx, stmt) in stmts.iter().enumerate() {
+                if let Some(&asm::Location::Source { line, column }) = self.locations.get(idx) {
+                    module_ops.push(Op::Line(line, column))
+                }
                 module_ops.extend(stmt.compile(None, &mut module))
             }
-            self.ingest_ops(&mut code, module_ops, &mut op_map);
+
+            let mut marks = LineMarks::new();
+            self.ingest_ops(&mut code, module_ops, encoding, &mut op_map, &mut marks);
+            lines.extend(line_entries(marks, code.len() as u64, "<module>"));
         }
 
         // Ingest all the compiled functions; track their entry addresses in `function_map` and
@@ -284,10 +470,30 @@ impl CompileModule for asm::Module {
             }
 
             let function_ops = f.ops.clone();
-            self.ingest_ops(&mut code, function_ops, &mut op_map);
+            let mut marks = LineMarks::new();
+            self.ingest_ops(&mut code, function_ops, encoding, &mut op_map, &mut marks);
+
+            lines.extend(line_entries(marks, code.len() as u64, &f.name.debug_name()));
+            locals.push((addr, f.locals.clone()));
+        }
+
+        // Calls to the module's own functions, by bare or fully-qualified name, are resolved
+        // here rather than through the machine's symbol table
+        let mut own_functions: HashMap<String, u64> = HashMap::new();
+        for &(ref name, addr) in functions.iter() {
+            own_functions.insert(name.clone(), addr);
+            own_functions.insert(module.name.clone() + "." + name, addr);
         }
 
-        let relocations = self.resolve_relocations(module.relocations, &op_map, &function_map);
+        let relocations = self.resolve_relocations(module.relocations, &op_map, &function_map, &own_functions, encoding);
+
+        let handlers = module.handlers.iter().map(|&(ref start, ref end, ref target)| {
+            Handler {
+                start: op_map[start],
+                end: op_map[end],
+                target: op_map[target],
+            }
+        }).collect();
 
         CompiledModule {
             name: module.name,
@@ -295,25 +501,39 @@ impl CompileModule for asm::Module {
             functions: functions,
             consts: module.consts,
             statics: module.statics,
+            externs: module.externs,
+            exports: module.exports,
             relocations: relocations,
+            debug: DebugInfo {
+                file: self.file.clone(),
+                lines: lines,
+                locals: locals,
+            },
+            encoding: encoding,
+            handlers: handlers,
         }
     }
 } // impl CompileModule for asm::Module
 
 impl asm::Module {
     /// Take a vector of higher-level owned and shared `Op`s and compile them down to bytecode.
-    /// Also notes the module-local addresses of shared `Op`s for later relocation in an `OpMap`.
-    pub fn ingest_ops(&self, bytecode: &mut Vec<u8>, ops: OpVec, op_map: &mut OpMap) {
+    /// Also notes the module-local addresses of shared `Op`s for later relocation in an `OpMap`
+    /// and the addresses of line markers in `LineMarks`. Addresses and ids are only filled in at
+    /// load time, so the ops always fit in their encoding.
+    pub fn ingest_ops(&self, bytecode: &mut Vec<u8>, ops: OpVec, encoding: Encoding, op_map: &mut OpMap, marks: &mut LineMarks) {
         for op in ops {
             match op {
-                Op::Owned(op) => bytecode.extend(op.to_binary()),
+                Op::Owned(op) => bytecode.extend(op.to_binary(encoding).expect("Op with placeholder operands")),
+                Op::Line(line, column) => {
+                    marks.push((bytecode.len() as u64, line, column))
+                },
                 Op::Shared(shared) => {
                     // Length of the vec will be the first address of the op we're inserting
                     let addr = bytecode.len() as u64;
                     op_map.insert(shared.clone(), addr);
 
                     let op: &BOp = shared.borrow();
-                    bytecode.extend(op.clone().to_binary())
+                    bytecode.extend(op.clone().to_binary(encoding).expect("Op with placeholder operands"))
                 },
             }
         }
@@ -321,8 +541,8 @@ impl asm::Module {
 
     /// Resolves abstract relocations (`Relocation`) into a vector of concrete, address-based
     /// relocations (`CompiledRelocationVec`) suitable for loading and linking into a
-    /// virtual machine instance.
-    pub fn resolve_relocations(&self, relocations: Vec<Relocation>, op_map: &OpMap, function_map: &FunctionMap) -> CompiledRelocationVec {
+    /// virtual machine instance. Paths found in `own_functions` become internal addresses.
+    pub fn resolve_relocations(&self, relocations: Vec<Relocation>, op_map: &OpMap, function_map: &FunctionMap, own_functions: &HashMap<String, u64>, encoding: Encoding) -> CompiledRelocationVec {
         // Resolve all the relocations
         let mut compiled_relocations: CompiledRelocationVec = Vec::new();
 
@@ -334,8 +554,7 @@ impl asm::Module {
                 None => panic!("Site not found: {:?}", site),
             };
 
-            // Right now all ops have a max of 1 address
-            let site_address = site_base_address + site.addr_field_offset(0);
+            let site_address = site_base_address + site.addr_field_offset(relocation.field, encoding);
 
             let compiled = match relocation.target {
                 RelocationTarget::InternalBranchAddress(op) => {
@@ -353,10 +572,11 @@ impl asm::Module {
                     )
                 },
                 RelocationTarget::ExternalFunctionPath(path) => {
-                    (
-                        site_address,
-                        CompiledRelocationTarget::ExternalFunctionPath(path)
-                    )
+                    let target = match own_functions.get(&path) {
+                        Some(addr) => CompiledRelocationTarget::InternalAddress(*addr),
+                        None => CompiledRelocationTarget::ExternalFunctionPath(path),
+                    };
+                    (site_address, target)
                 },
                 RelocationTarget::ConstPath(path) => {
                     (
@@ -375,25 +595,55 @@ impl asm::Module {
 }// impl asm::Module
 
 impl asm::BasicBlock {
-    fn collect_locals(&self) -> Locals {
+    /// Collects the locals of a function body. The `parameters` of the function are added
+    /// first so that they occupy the first slots of the frame.
+    fn collect_locals(&self, parameters: &Vec<asm::Name>) -> Locals {
         let mut locals = Locals::new();
+
+        for parameter in parameters {
+            locals.add(parameter.clone()).unwrap();
+        }
+
+        self.collect_block_locals(&mut locals);
+        locals
+    }
+
+    /// Adds the locals allocated in this block and in the blocks of the `switch` and `try`
+    /// statements within it, since those run in the same frame.
+    fn collect_block_locals(&self, locals: &mut Locals) {
         let ref stmts = self.stmts;
 
         for stmt in stmts {
             match stmt {
                 &StatementAssignment(ref assg) => {
-                    if assg.operator == AssignmentOp::AllocateAndAssign {
+                    // Statics live in the machine rather than in a slot of the frame
+                    if assg.operator == AssignmentOp::AllocateAndAssign && !assg.lvalue.starts_with("$") {
                         locals.add(assg.lvalue.clone()).unwrap();
                     }
                 },
                 &StatementLocal(ref local) => {
                     locals.add(local.name.clone()).unwrap();
-                }
+                },
+                &StatementTry(ref t) => {
+                    t.body.collect_block_locals(locals);
+
+                    // Several handlers may reuse the same name for the exception
+                    if locals.find(t.name.clone()).is_err() {
+                        locals.add(t.name.clone()).unwrap();
+                    }
+                    t.handler.collect_block_locals(locals);
+                },
+                &StatementSwitch(ref switch) => {
+                    for case in switch.cases.iter() {
+                        case.body.collect_block_locals(locals);
+                    }
+                    if let Some(ref default) = switch.default {
+                        default.collect_block_locals(locals);
+                    }
+                },
                 _ => (),
             }
         }
-
-        locals
     }
 }
 
@@ -402,7 +652,10 @@ impl Compile for asm::BasicBlock {
         let ref stmts = self.stmts;
         let mut ops = OpVec::new();
 
-        for stmt in stmts {
+        for (idx, stmt) in stmts.iter().enumerate() {
+            if let Some(asm::Location::Source { line, column }) = self.location(idx) {
+                ops.push(Op::Line(line, column))
+            }
             ops.extend(stmt.compile(lc, m))
         }
 
@@ -413,10 +666,10 @@ impl Compile for asm::BasicBlock {
 impl Compile for asm::Statement {
     fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
         match *self {
-            StatementMod(_)             => vec![],
-            // StatementExtern(e)       => e.compile(),
+            StatementMod(ref mo)        => mo.compile(lc, m),
+            StatementExtern(ref e)      => e.compile(lc, m),
             StatementConst(ref c)       => c.compile(lc, m),
-            // StatementStatic(s)       => s.compile(),
+            StatementStatic(ref s)      => s.compile(lc, m),
             StatementLocal(_)           => vec![], // No-op since we'll have already collected locals
             StatementAssignment(ref a)  => a.compile(lc, m),
             StatementDefn(ref d)        => d.compile(lc, m),
@@ -424,6 +677,9 @@ impl Compile for asm::Statement {
             StatementReturn(ref r)      => r.compile(lc, m),
             StatementTest(ref t)        => t.compile(lc, m),
             StatementIf(ref i)          => i.compile(lc, m),
+            StatementSwitch(ref s)      => s.compile(lc, m),
+            StatementThrow(ref t)       => t.compile(lc, m),
+            StatementTry(ref t)         => t.compile(lc, m),
             StatementThen(_)            => vec![], // Both `then` and `else` are handled by `if`
             StatementElse(_)            => vec![],
             // StatementWhile(While),
@@ -434,6 +690,13 @@ impl Compile for asm::Statement {
     }
 }
 
+impl Compile for asm::Extern {
+    fn compile(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
+        m.externs.push(self.path.to_string());
+        vec![]
+    }
+}
+
 impl Compile for asm::Mod {
     fn compile(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
         let fully_qualified_name = self.path.to_string();
@@ -452,6 +715,9 @@ impl Compile for asm::Const {
     fn compile(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
         let compiled = (self.name.clone(), self.constructor.to_string(), self.argument.clone());
         m.consts.push(compiled);
+        if self.export {
+            m.exports.push(self.name.clone());
+        }
         vec![]
     }
 }
@@ -459,12 +725,20 @@ impl Compile for asm::Const {
 impl Compile for asm::Static {
     fn compile(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
         m.statics.push(self.name.clone());
+        if self.export {
+            m.exports.push(self.name.clone());
+        }
         vec![]
     }
 }
 
 impl asm::Value {
-    fn compile_name_to_value(&self, name: asm::Name, lc: LocalContextRef, _: &mut Module) -> OpVec {
+    fn compile_name_to_value(&self, name: asm::Name, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        // The module's own static; the loader qualifies the path with the module's name
+        if name.starts_with("$") {
+            return compile_symbol_access(BGetStatic { id: 0, }.into_op(), name, m)
+        }
+
         let idx = lc.unwrap().locals.find(name).unwrap();
 
         vec![Op::Owned(BGetLocal { idx: idx, }.into_op())]
@@ -483,24 +757,31 @@ impl CompileToValue for asm::Value {
     }
 }
 
+/// Paths to consts and functions load the symbol's value from the const pool once linked;
+/// paths to statics read the static's slot.
 impl CompileToValue for asm::Path {
     fn compile_to_value(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
         let op: BOp =
-            if self.ends_with_const() {
-                BLoadConst { id: 0, }.into_op()
+            if self.ends_with_static() {
+                BGetStatic { id: 0, }.into_op()
             } else {
-                panic!("Cannot compile Path to value: {:?}", self)
+                BLoadConst { id: 0, }.into_op()
             };
 
-        let shared_op = Rc::new(op);
-        m.add_const_relocation(shared_op.clone(), self.to_string());
-
-        let mut ops = OpVec::new();
-        ops.push_shared(shared_op);
-        ops
+        compile_symbol_access(op, self.to_string(), m)
     }
 }
 
+/// Op whose id operand is relocated to the const or static at `path` once linked.
+fn compile_symbol_access(op: BOp, path: String, m: &mut Module) -> OpVec {
+    let shared_op = Rc::new(op);
+    m.add_const_relocation(shared_op.clone(), path);
+
+    let mut ops = OpVec::new();
+    ops.push_shared(shared_op);
+    ops
+}
+
 impl Compile for asm::Call {
     fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
         let mut ops = self.compile_to_value(lc, m);
@@ -510,62 +791,155 @@ impl Compile for asm::Call {
 }
 impl CompileToValue for asm::Call {
     fn compile_to_value(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        let num_args = self.arguments.len() as u8;
+
+        match self.local_callee(lc) {
+            Some(idx) => {
+                let mut ops = self.compile_arguments(Some(idx), lc, m);
+                ops.push_owned(BInvoke { num_args: num_args, }.into_op());
+                ops
+            },
+            None => {
+                let mut ops = self.compile_arguments(None, lc, m);
+                let op = Rc::new(BCall { addr: 0, num_args: num_args, }.into_op());
+                m.add_call_relocation(op.clone(), self.path.to_string());
+                ops.push_shared(op);
+                ops
+            },
+        }
+    }
+}
+
+impl asm::Call {
+    /// Slot of the local the call is through if its path names one; the local's value is then
+    /// invoked rather than the call being linked to a function.
+    fn local_callee(&self, lc: LocalContextRef) -> Option<u16> {
+        match (self.path.as_name(), lc) {
+            (Some(name), Some(lc)) => lc.locals.find(name.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Push the function value being invoked (if any) and then the arguments.
+    fn compile_arguments(&self, callee: Option<u16>, lc: LocalContextRef, m: &mut Module) -> OpVec {
         let mut ops = OpVec::new();
-        let ref args = self.arguments;
 
-        for name in args {
-            let idx = lc.unwrap().locals.find(name.clone()).unwrap();
+        if let Some(idx) = callee {
+            ops.push_owned(BGetLocal { idx: idx, }.into_op());
+        }
+        for name in self.arguments.iter() {
+            if name.starts_with("$") {
+                ops.extend(compile_symbol_access(BGetStatic { id: 0, }.into_op(), name.clone(), m));
+                continue
+            }
 
+            let idx = lc.unwrap().locals.find(name.clone()).unwrap();
             ops.push_owned(BGetLocal { idx: idx, }.into_op());
         }
+        ops
+    }
 
+    /// Compiles a call in tail position (ie. `return call f(...)`). The callee replaces the
+    /// current frame so that it returns straight to our caller.
+    fn compile_tail_call(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
         let num_args = self.arguments.len() as u8;
-        let op = Rc::new(BCall { addr: 0, num_args: num_args, }.into_op());
-        m.add_call_relocation(op.clone(), self.path.to_string());
 
-        ops.push_shared(op);
-        ops
+        match self.local_callee(lc) {
+            Some(idx) => {
+                let mut ops = self.compile_arguments(Some(idx), lc, m);
+                ops.push_owned(BTailInvoke { num_args: num_args, }.into_op());
+                ops
+            },
+            None => {
+                let mut ops = self.compile_arguments(None, lc, m);
+                let op = Rc::new(BTailCall { addr: 0, num_args: num_args, }.into_op());
+                m.add_call_relocation(op.clone(), self.path.to_string());
+                ops.push_shared(op);
+                ops
+            },
+        }
     }
 }
 
 impl Compile for asm::Return {
-    fn compile(&self, _: LocalContextRef, _: &mut Module) -> OpVec {
-        vec![Op::Owned(BOp::Return)]
+    fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        // A tail call would replace the frame and lose the handlers of enclosing `try`s
+        let in_try = lc.map(|lc| lc.try_depth.get() > 0).unwrap_or(false);
+
+        match self.value {
+            Some(asm::Value::Call(ref c)) if !in_try => c.compile_tail_call(lc, m),
+            Some(ref value) => {
+                let mut ops = value.compile_to_value(lc, m);
+                ops.push_owned(BOp::Return);
+                ops
+            },
+            None => vec![Op::Owned(BOp::PushNull), Op::Owned(BOp::Return)],
+        }
     }
 }
 
 impl Compile for asm::Assignment {
     fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
-        let idx = lc.unwrap().locals.find(self.lvalue.clone()).unwrap();
         let mut ops: OpVec = vec![];
-
         ops.extend(self.rvalue.compile_to_value(lc, m));
-        ops.push_owned(BSetLocal { idx: idx, }.into_op());
+
+        if self.lvalue.starts_with("$") {
+            ops.extend(compile_symbol_access(BSetStatic { id: 0, }.into_op(), self.lvalue.clone(), m));
+        } else {
+            let idx = lc.unwrap().locals.find(self.lvalue.clone()).unwrap();
+            ops.push_owned(BSetLocal { idx: idx, }.into_op());
+        }
 
         ops
     }
 }
 
 /// Shared function used by `asm::Fn` and `asm::Defn` to compile their `BasicBlock` bodies.
-fn compile_function_body(body: &asm::BasicBlock, m: &mut Module) -> OpVec {
-    let locals = body.collect_locals();
-    let entry  = BFnEntry { num_locals: locals.len() as u16, };
+/// Returns the ops and the names of the function's local slots.
+fn compile_function_body(parameters: &Vec<asm::Name>, rest: &Option<asm::Name>, body: &asm::BasicBlock, m: &mut Module) -> (OpVec, Vec<String>) {
+    // The rest parameter's slot follows those of the other parameters
+    let mut all_parameters = parameters.clone();
+    all_parameters.extend(rest.clone());
+
+    let locals = body.collect_locals(&all_parameters);
+    let entry  = BFnEntry {
+        num_params: parameters.len() as u8,
+        num_locals: locals.len() as u16,
+    };
 
     let mut ops: OpVec = vec![];
     ops.push_owned(entry.into_op());
+    if rest.is_some() {
+        ops.push_owned(BCollectRest { idx: parameters.len() as u16, }.into_op());
+    }
 
-    let lc = LocalContext { locals: locals, };
+    let lc = LocalContext { locals: locals, try_depth: Cell::new(0), };
     ops.extend(body.compile(Some(&lc), m));
 
-    ops
+    // Don't let execution fall through into whatever code follows the function; falling off
+    // the end returns null
+    let ends_with_return = match body.stmts.last() {
+        Some(&StatementReturn(_)) => true,
+        _ => false,
+    };
+    if !ends_with_return {
+        ops.push_owned(BOp::PushNull);
+        ops.push_owned(BOp::Return);
+    }
+
+    (ops, lc.locals.locals)
 }
 
 impl Compile for asm::Defn {
     fn compile(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
-        let ops = compile_function_body(&self.body, m);
+        let (ops, locals) = compile_function_body(&self.parameters, &self.rest, &self.body, m);
+        if self.export {
+            m.exports.push(self.name.clone());
+        }
         m.add_defn(Function {
             name: FunctionName::Named(self.name.clone()),
             ops: ops,
+            locals: locals,
         });
 
         vec![]
@@ -574,10 +948,11 @@ impl Compile for asm::Defn {
 
 impl CompileToValue for asm::Fn {
     fn compile_to_value(&self, _: LocalContextRef, m: &mut Module) -> OpVec {
-        let ops  = compile_function_body(&self.body, m);
+        let (ops, locals) = compile_function_body(&self.parameters, &self.rest, &self.body, m);
         let fref = m.add_fn(Function {
             name: FunctionName::Anonymous,
             ops: ops,
+            locals: locals,
         });
 
         // Using `Rc` so that we have a shared pointer that we can use to look up the op later
@@ -632,6 +1007,114 @@ impl Compile for asm::Then {
     }
 }
 
+/// Compiles to a jump table:
+///
+/// ```text
+///   load_const CASE_0 ... load_const CASE_N
+///   get_local TEST
+///   switch DEFAULT, [TARGET_0, ..., TARGET_N]
+/// TARGET_0:
+///   BODY_0
+///   jump END
+///   ...
+/// DEFAULT:
+///   DEFAULT_BODY
+/// END:
+/// ```
+impl Compile for asm::Switch {
+    fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        let mut ops = OpVec::new();
+
+        for case in self.cases.iter() {
+            ops.extend(case.value.compile_to_value(lc, m));
+        }
+
+        let idx = lc.unwrap().locals.find(self.test.clone()).unwrap();
+        ops.push_owned(BGetLocal { idx: idx, }.into_op());
+
+        let switch = Rc::new(BSwitch { default: 0, targets: vec![0; self.cases.len()], }.into_op());
+        let default = Rc::new(BOp::Noop);
+        let end     = Rc::new(BOp::Noop);
+
+        ops.push_shared(switch.clone());
+        m.add_branch_relocation_at(switch.clone(), 0, default.clone());
+
+        for (idx, case) in self.cases.iter().enumerate() {
+            let target = Rc::new(BOp::Noop);
+            m.add_branch_relocation_at(switch.clone(), idx + 1, target.clone());
+
+            ops.push_shared(target);
+            ops.extend(case.body.compile(lc, m));
+
+            let jump = Rc::new(BJump { dest: 0, }.into_op());
+            m.add_branch_relocation(jump.clone(), end.clone());
+            ops.push_shared(jump);
+        }
+
+        ops.push_shared(default);
+        if let Some(ref body) = self.default {
+            ops.extend(body.compile(lc, m));
+        }
+        ops.push_shared(end);
+
+        ops
+    }
+}
+
+impl Compile for asm::Throw {
+    fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        let mut ops = self.value.compile_to_value(lc, m);
+        ops.push_owned(BOp::Throw);
+        ops
+    }
+}
+
+/// Compiles to the body followed by the handler, with a handler table entry covering the
+/// body:
+///
+/// ```text
+/// START:
+///   BODY
+/// END:
+///   jump AFTER
+/// TARGET:
+///   set_local NAME
+///   HANDLER
+/// AFTER:
+/// ```
+impl Compile for asm::Try {
+    fn compile(&self, lc: LocalContextRef, m: &mut Module) -> OpVec {
+        let context = lc.unwrap();
+        let mut ops = OpVec::new();
+
+        let start  = Rc::new(BOp::Noop);
+        let end    = Rc::new(BOp::Noop);
+        let target = Rc::new(BOp::Noop);
+        let after  = Rc::new(BOp::Noop);
+
+        ops.push_shared(start.clone());
+        context.try_depth.set(context.try_depth.get() + 1);
+        ops.extend(self.body.compile(lc, m));
+        context.try_depth.set(context.try_depth.get() - 1);
+        ops.push_shared(end.clone());
+
+        let jump = Rc::new(BJump { dest: 0, }.into_op());
+        m.add_branch_relocation(jump.clone(), after.clone());
+        ops.push_shared(jump);
+
+        let idx = context.locals.find(self.name.clone()).unwrap();
+        ops.push_shared(target.clone());
+        ops.push_owned(BSetLocal { idx: idx, }.into_op());
+        ops.extend(self.handler.compile(lc, m));
+        ops.push_shared(after);
+
+        // Inner `try`s are compiled (and so added) before this one
+        m.handlers.push((start, end, target));
+
+        ops
+    }
+}
+
 /// **Note**: Test pushes its value onto the stack to be consumed by its condition
 /// parent (if/while) node.
 impl Compile for asm::Test {
@@ -645,8 +1128,22 @@ impl Compile for asm::Test {
 
 #[cfg(test)]
 mod tests {
-    use super::{CompileModule};
-    use asm::{BasicBlock, Defn, Module, Return, Statement};
+    use super::{CompileModule, CompiledRelocationTarget};
+    use asm::{BasicBlock, Call, Defn, Mod, Module, Path, Return, Statement, Value};
+    use vm::bytecode::ops::BOp;
+    use vm::bytecode::util::Encoding;
+    use std::io::Cursor;
+
+    fn decode(code: &Vec<u8>) -> Vec<BOp> {
+        let mut cursor = Cursor::new(code);
+        let mut ops = vec![];
+
+        while (cursor.position() as usize) < code.len() {
+            ops.push(BOp::from_binary(&mut cursor, Encoding::Fixed).unwrap());
+        }
+
+        ops
+    }
 
     #[test]
     fn test_compile_module() {
@@ -664,4 +1161,198 @@ mod tests {
         assert!(compiled.code.len() > 0);
         assert_eq!(compiled.functions.len(), 1);
     }
+
+    #[test]
+    fn test_compile_tail_call() {
+        let module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["b".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                        Path::with_name("a".to_owned()),
+                        vec!["b".to_owned()]
+                    )))))
+                ])
+            )),
+        ]);
+        let compiled = module.compile();
+        let ops = decode(&compiled.code);
+
+        assert!(ops.iter().any(|op| if let &BOp::TailCall(ref c) = op { c.num_args == 1 } else { false }));
+        assert!(!ops.iter().any(|op| if let &BOp::Call(_) = op { true } else { false }));
+        assert_eq!(compiled.relocations.len(), 1);
+
+        // Calls through a local invoke its value in place of the current function
+        let module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["f".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                        Path::with_name("f".to_owned()),
+                        vec!["f".to_owned()]
+                    )))))
+                ])
+            )),
+        ]);
+        let compiled = module.compile();
+        let ops = decode(&compiled.code);
+
+        assert!(ops.iter().any(|op| if let &BOp::TailInvoke(ref i) = op { i.num_args == 1 } else { false }));
+        assert!(compiled.relocations.is_empty());
+    }
+
+    #[test]
+    fn test_compile_line_table() {
+        use asm::Location;
+
+        let body = BasicBlock::with_located_stmts(
+            vec![
+                Statement::StatementReturn(Return::new(Some(Value::with_name("b".to_owned())))),
+            ],
+            vec![
+                Location::Source { line: 2, column: 3 },
+            ]
+        );
+        let mut module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new("a".to_owned(), vec!["b".to_owned()], body)),
+        ]);
+        module.file = Some("a.hasm".to_owned());
+
+        let compiled = module.compile();
+        let ref debug = compiled.debug;
+
+        assert_eq!(debug.file, Some("a.hasm".to_owned()));
+        assert_eq!(debug.lines.len(), 1);
+
+        let ref entry = debug.lines[0];
+        assert_eq!((entry.line, entry.column), (2, 3));
+        assert_eq!(entry.function, "a");
+        assert_eq!(entry.end, compiled.code.len() as u64);
+
+        // The entry covers everything after the `FnEntry`
+        assert!(debug.line_for(0).is_none());
+        assert_eq!(debug.line_for(entry.start), Some(entry));
+
+        assert_eq!(debug.locals_for(0), Some(&vec!["b".to_owned()]));
+    }
+
+    #[test]
+    fn test_compile_switch() {
+        use asm::{Case, Switch};
+
+        let module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["b".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementSwitch(Switch::new(
+                        "b".to_owned(),
+                        vec![
+                            Case::new(Path::from_str("@c").unwrap(), BasicBlock::with_stmts(vec![])),
+                            Case::new(Path::from_str("@d").unwrap(), BasicBlock::with_stmts(vec![])),
+                        ],
+                        None
+                    )),
+                ])
+            )),
+        ]);
+        let compiled = module.compile();
+        let ops = decode(&compiled.code);
+
+        let switch = ops.iter().filter_map(|op| if let &BOp::Switch(ref s) = op { Some(s) } else { None }).next().unwrap();
+        assert_eq!(switch.targets.len(), 2);
+        assert_eq!(ops.iter().filter(|op| if let &BOp::Jump(_) = *op { true } else { false }).count(), 2);
+
+        // Two const loads, the default and both targets, and both jumps
+        assert_eq!(compiled.relocations.len(), 7);
+    }
+
+    #[test]
+    fn test_compile_try() {
+        use asm::{Throw, Try};
+
+        let module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["b".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementTry(Try::new(
+                        BasicBlock::with_stmts(vec![
+                            Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                                Path::with_name("a".to_owned()),
+                                vec!["b".to_owned()]
+                            ))))),
+                        ]),
+                        "e".to_owned(),
+                        BasicBlock::with_stmts(vec![
+                            Statement::StatementThrow(Throw::new(Value::with_name("e".to_owned()))),
+                        ])
+                    )),
+                ])
+            )),
+        ]);
+        let compiled = module.compile();
+        let ops = decode(&compiled.code);
+
+        assert_eq!(compiled.handlers.len(), 1);
+        let ref handler = compiled.handlers[0];
+        assert!(handler.start < handler.end && handler.end < handler.target);
+
+        // The call in the body can't be a tail call since it must stay inside the handler
+        assert!(ops.iter().any(|op| if let &BOp::Call(_) = op { true } else { false }));
+        assert!(!ops.iter().any(|op| if let &BOp::TailCall(_) = op { true } else { false }));
+        assert!(ops.iter().any(|op| if let &BOp::Throw = op { true } else { false }));
+        assert_eq!(compiled.debug.locals[0].1, vec!["b".to_owned(), "e".to_owned()]);
+    }
+
+    #[test]
+    fn test_compile_compact_encoding() {
+        let module = Module::with_stmts(vec![
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["b".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                        Path::with_name("a".to_owned()),
+                        vec!["b".to_owned()]
+                    )))))
+                ])
+            )),
+        ]);
+        let fixed = module.compile_with_encoding(Encoding::Fixed);
+        let compact = module.compile_with_encoding(Encoding::Compact);
+
+        assert_eq!(compact.encoding, Encoding::Compact);
+        assert!(compact.code.len() < fixed.code.len());
+        assert_eq!(compact.relocations.len(), fixed.relocations.len());
+        assert_eq!(compact.functions, fixed.functions);
+    }
+
+    #[test]
+    fn test_compile_resolves_own_functions() {
+        let calling = |path: &str| {
+            BasicBlock::with_stmts(vec![
+                Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                    Path::from_str(path).unwrap(),
+                    vec![]
+                )))))
+            ])
+        };
+        let module = Module::with_stmts(vec![
+            Statement::StatementMod(Mod::new(Path::from_str("foo").unwrap())),
+            Statement::StatementDefn(Defn::new("a".to_owned(), vec![], calling("b"))),
+            Statement::StatementDefn(Defn::new("b".to_owned(), vec![], calling("foo.a"))),
+            Statement::StatementDefn(Defn::new("c".to_owned(), vec![], calling("bar.a"))),
+        ]);
+        let compiled = module.compile();
+
+        let targets: Vec<CompiledRelocationTarget> = compiled.relocations.iter().map(|r| r.1.clone()).collect();
+        assert_eq!(targets, vec![
+            CompiledRelocationTarget::InternalAddress(compiled.functions[1].1),
+            CompiledRelocationTarget::InternalAddress(compiled.functions[0].1),
+            CompiledRelocationTarget::ExternalFunctionPath("bar.a".to_owned()),
+        ]);
+    }
 }
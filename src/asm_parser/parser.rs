@@ -4,22 +4,27 @@ use asm::{
     AssignmentOp,
     BasicBlock,
     Call,
+    Case,
     Const,
     Defn,
     Extern,
     Fn as AsmFn,
     Local,
+    Location,
     Mod,
     Module,
     Path,
     Return,
     Static,
     Statement,
+    Switch,
+    Throw,
+    Try,
     Value,
 };
 
 use nom::{
-    alpha, digit, eof, is_space, multispace, space,
+    digit, eof, is_space, multispace, space,
     Err as NomErr,
     ErrorKind,
     IResult,
@@ -29,24 +34,35 @@ use std::str;
 
 pub fn pmodule(input: &[u8]) -> IResult<&[u8], Module> {
     let result = chain!(input,
-        stmts: many0!(pstatement) ~
-        pterminal?                ,
+        stmts: many0!(plocated_statement) ~
+        pterminal?                        ,
 
-        ||{ Module::with_stmts(stmts) }
+        ||{
+            let (stmts, locations) = stmts.into_iter().unzip();
+            Module::with_located_stmts(stmts, locations)
+        }
     );
 
     match result {
-        IResult::Done(remaining, _) => {
+        IResult::Done(remaining, mut module) => {
             if remaining.len() > 0 {
                 IResult::Incomplete(Needed::Size(remaining.len()))
             } else {
-                result
+                module.resolve_locations(input);
+                IResult::Done(remaining, module)
             }
         },
         _ => result
     }
 }
 
+/// Parses a statement and notes where in the input it began (see `Location::Remaining`).
+fn plocated_statement(input: PBytes) -> PResult<(Statement, Location)> {
+    let input = gobble(input, is_space);
+
+    map!(input, pstatement, |stmt| { (stmt, Location::Remaining(input.len())) })
+}
+
 pub fn pstatement(input: PBytes) -> PResult<Statement> {
     let input = gobble(input, is_space);
 
@@ -57,31 +73,48 @@ pub fn pstatement(input: PBytes) -> PResult<Statement> {
         pstatic     => { |s| Statement::StatementStatic(s) } |
         plocal      => { |l| Statement::StatementLocal(l)  } |
         preturn     => { |r| Statement::StatementReturn(r) } |
+        pdefn_stmt  => { |d| Statement::StatementDefn(d)   } |
+        pcall       => { |c| Statement::StatementCall(c)   } |
+        pswitch     => { |s| Statement::StatementSwitch(s) } |
+        pthrow      => { |t| Statement::StatementThrow(t)  } |
+        ptry        => { |t| Statement::StatementTry(t)    } |
 
         // NOTE: Assignment must come last since it will consume any alphanumeric word.
         passignment => { |a| Statement::StatementAssignment(a) }
     )
 }
 
+/// Parses an identifier: a letter or `_` followed by any letters, digits and `_`s.
+fn pidentifier(input: PBytes) -> PResult<PBytes> {
+    let len = input.iter().enumerate().take_while(|&(idx, &c)| {
+        c == b'_' || (c as char).is_ascii_alphabetic() || (idx > 0 && (c as char).is_ascii_digit())
+    }).count();
+
+    if len == 0 {
+        return IResult::Error(NomErr::Position(ErrorKind::Alpha, input))
+    }
+    IResult::Done(&input[len..], &input[..len])
+}
+
 fn plocal_name(input: PBytes) -> PResult<String> {
-    map!(input, alpha, |name| { to_s(name) })
+    map!(input, pidentifier, |name| { to_s(name) })
 }
 
 fn pstatic_name(input: PBytes) -> PResult<String> {
     map!(input,
-        preceded!(tag!("$"), alpha),
+        preceded!(tag!("$"), pidentifier),
         |name| { "$".to_string() + &to_s(name) }
     )
 }
 
 fn pconst_name(input: PBytes) -> PResult<String> {
     map!(input,
-        preceded!(tag!("@"), alpha),
+        preceded!(tag!("@"), pidentifier),
         |name| { "@".to_string() + &to_s(name) }
     )
 }
 
-/// Parses a path like "a.b.c"
+/// Parses a path like "a.b.c"; builtin paths start with "_" (eg. "_.std.null")
 pub fn ppath(input: PBytes) -> PResult<Path> {
     named!(name<PBytes, String>,
         alt!(plocal_name | pstatic_name | pconst_name)
@@ -116,15 +149,21 @@ pub fn plocal(input: &[u8]) -> IResult<&[u8], Local> {
     )
 }
 
-/// Parses `static $NAME`
+/// Parses the `export` modifier of module-level definitions.
+fn pexport(input: PBytes) -> PResult<bool> {
+    map!(input, opt!(terminated!(tag!("export"), space)), |export: Option<_>| { export.is_some() })
+}
+
+/// Parses `static $NAME` (optionally preceded by `export`)
 pub fn pstatic(input: &[u8]) -> IResult<&[u8], Static> {
     chain!(input,
+        export: pexport    ~
         tag!("static")     ~
         space              ~
         name: pstatic_name ~
         pterminal          ,
 
-        ||{ Static::new(name) }
+        ||{ Static::new(name).with_export(export) }
     )
 }
 
@@ -147,17 +186,17 @@ pub fn pconst_constructor(input: PBytes) -> PResult<(Path, Option<String>)> {
     }
 
     chain!(input,
-        cons: ppath     ~ space ~
-        arg:  maybe_arg ~
-        pterminal       ,
+        cons: ppath     ~ space? ~
+        arg:  maybe_arg ,
 
         ||{ (cons, arg) }
     )
 }
 
-/// Parses `const @NAME = CONSTRUCTOR ARGUMENT?`
+/// Parses `const @NAME = CONSTRUCTOR ARGUMENT?` (optionally preceded by `export`)
 pub fn pconst(input: &[u8]) -> IResult<&[u8], Const> {
     chain!(input,
+        export: pexport             ~
         tag!("const")               ~ space ~
         name: pconst_name           ~ space ~
         tag!("=")                   ~ space? ~
@@ -168,7 +207,7 @@ pub fn pconst(input: &[u8]) -> IResult<&[u8], Const> {
             let path = cons.0.clone();
             let arg  = cons.1.clone();
 
-            Const::new(name, path, arg)
+            Const::new(name, path, arg).with_export(export)
         }
     )
 }
@@ -215,14 +254,29 @@ fn ppidentifier(input: PBytes) -> PResult<String> {
 /// Values types can be:
 ///
 /// - An anonymous function (`fn(ARGS) BLOCK`)
+/// - A call (`call PATH(ARGS)`)
+/// - A path to storage in another module (`a.@const`)
 /// - An identifier (`local`, `@static`, or `$const`)
 pub fn pvalue(input: PBytes) -> PResult<Value> {
     try_each(input, vec![
         Box::new(|i| map!(i, pfn, |f| Value::Fn(f))),
+        Box::new(|i| map!(i, pcall_value, |c| Value::Call(c))),
+        Box::new(|i| map!(i, pqualified_path, |p| Value::Path(p))),
         Box::new(|i| map!(i, ppidentifier, |i| Value::with_name(i)))
     ])
 }
 
+/// Parses a path of more than one segment, such as "a.@b"
+fn pqualified_path(input: PBytes) -> PResult<Path> {
+    match ppath(input) {
+        IResult::Done(remaining, ref path) if path.to_string().contains('.') => {
+            IResult::Done(remaining, path.clone())
+        },
+        IResult::Done(_, _) => IResult::Error(NomErr::Position(ErrorKind::Tag, input)),
+        other => other,
+    }
+}
+
 /// Parses assignments
 ///
 /// Assignments can have the following forms:
@@ -266,15 +320,19 @@ pub fn pterminal(input: PBytes) -> PResult<()> {
 /// Parses a block: `{ STATEMENTS }`.
 fn pbasicblock(input: PBytes) -> PResult<BasicBlock> {
     chain!(input,
-        tag!("{")                 ~ multispace? ~
-        stmts: many0!(pstatement) ~ multispace? ~
+        tag!("{")                         ~ multispace? ~
+        stmts: many0!(plocated_statement) ~ multispace? ~
         tag!("}") ,
 
-        ||{ BasicBlock::with_stmts(stmts) }
+        ||{
+            let (stmts, locations) = stmts.into_iter().unzip();
+            BasicBlock::with_located_stmts(stmts, locations)
+        }
     )
 }
 
-fn ppfunction_parameters(input: PBytes) -> PResult<Vec<String>> {
+/// Parses a parameter list, which may end with a `...rest` parameter for further arguments.
+fn ppfunction_parameters(input: PBytes) -> PResult<(Vec<String>, Option<String>)> {
     // Comma separator between parameters
     named!(comma<&[u8], ()>,
         chain!(
@@ -283,27 +341,46 @@ fn ppfunction_parameters(input: PBytes) -> PResult<Vec<String>> {
         )
     );
 
+    named!(rest<&[u8], String>,
+        chain!(
+            tag!("...") ~
+            name: plocal_name,
+            ||{ name }
+        )
+    );
+
     chain!(input,
         tag!("(")                                  ~ space? ~
-        args: separated_list!(comma, ppidentifier) ~ space? ~
+        args: separated_list!(comma, ppidentifier) ~
+        rest: opt!(chain!(
+            cond!(!args.is_empty(), comma) ~
+            name: rest                     ,
+            ||{ name }
+        ))                                         ~ space? ~
         tag!(")")                                  ,
 
-        ||{ args }
+        ||{ (args, rest) }
     )
 }
 
-/// Parses the `defn` statement syntax for defined functions.
+/// Parses the `defn` statement syntax for defined functions (optionally preceded by `export`).
 pub fn pdefn(input: PBytes) -> PResult<Defn> {
     chain!(input,
+        export: pexport                   ~
         tag!("defn")                      ~ space ~
-        name: alpha                       ~
+        name: pidentifier                 ~
         parameters: ppfunction_parameters ~ space? ~
         body: pbasicblock                 ,
 
-        ||{ Defn::new(to_s(name), parameters, body) }
+        ||{ Defn::new(to_s(name), parameters.0, body).with_rest(parameters.1).with_export(export) }
     )
 }
 
+/// Parses a `defn` followed by a terminal so that it can appear in a list of statements.
+fn pdefn_stmt(input: PBytes) -> PResult<Defn> {
+    terminated!(input, pdefn, pterminal)
+}
+
 /// Parses the `fn` value syntax for anonymous functions.
 pub fn pfn(input: PBytes) -> PResult<AsmFn> {
     chain!(input,
@@ -311,7 +388,7 @@ pub fn pfn(input: PBytes) -> PResult<AsmFn> {
         parameters: ppfunction_parameters ~ space? ~
         body: pbasicblock                 ,
 
-        ||{ AsmFn::new(parameters, body) }
+        ||{ AsmFn::new(parameters.0, body).with_rest(parameters.1) }
     )
 }
 
@@ -332,7 +409,19 @@ pub fn preturn(input: PBytes) -> PResult<Return> {
     )
 }
 
+/// Parses `call PATH(ARGS)` as a statement.
 pub fn pcall(input: PBytes) -> PResult<Call> {
+    chain!(input,
+        call: pcall_value ~
+        pterminal         ,
+
+        ||{ call }
+    )
+}
+
+/// Parses `call PATH(ARGS)` without a terminal so that it can be used as a value (eg. in
+/// `return call f(a)` or `a := call f()`).
+pub fn pcall_value(input: PBytes) -> PResult<Call> {
     fn arguments(input: PBytes) -> PResult<Vec<String>> {
         named!(comma<PBytes, ()>,
             chain!(opt!(space) ~ tag!(",") ~ opt!(space), ||{ () })
@@ -361,17 +450,102 @@ pub fn pcall(input: PBytes) -> PResult<Call> {
     chain!(input,
         tag!("call")    ~ space  ~
         path: path      ~ space? ~
-        args: arguments ~
-        pterminal       ,
+        args: arguments ,
 
         ||{ Call::new(path, args) }
     )
 }
 
+/// Parses a switch over a local:
+///
+/// ```text
+/// switch NAME {
+///   case CONST BLOCK
+///   ...
+///   default BLOCK
+/// }
+/// ```
+///
+/// The `default` clause is optional.
+pub fn pswitch(input: PBytes) -> PResult<Switch> {
+    fn pcase(input: PBytes) -> PResult<Case> {
+        chain!(input,
+            tag!("case")      ~ space   ~
+            value: pconst_path ~ space? ~
+            body: pbasicblock ~ multispace? ,
+
+            ||{ Case::new(value, body) }
+        )
+    }
+
+    fn pdefault(input: PBytes) -> PResult<BasicBlock> {
+        chain!(input,
+            tag!("default")   ~ space?      ~
+            body: pbasicblock ~ multispace? ,
+
+            ||{ body }
+        )
+    }
+
+    fn maybe_default(input: PBytes) -> PResult<Option<BasicBlock>> {
+        try(input, Box::new(|i| pdefault(i)))
+    }
+
+    chain!(input,
+        tag!("switch")         ~ space       ~
+        test: plocal_name      ~ space?      ~
+        tag!("{")              ~ multispace? ~
+        cases: many0!(pcase)   ~
+        default: maybe_default ~
+        tag!("}")              ~
+        pterminal              ,
+
+        ||{ Switch::new(test, cases, default) }
+    )
+}
+
+/// Parses `throw VALUE`
+pub fn pthrow(input: PBytes) -> PResult<Throw> {
+    chain!(input,
+        tag!("throw")  ~ space ~
+        value: pvalue  ~
+        pterminal      ,
+
+        ||{ Throw::new(value) }
+    )
+}
+
+/// Parses `try BLOCK catch NAME BLOCK`
+pub fn ptry(input: PBytes) -> PResult<Try> {
+    chain!(input,
+        tag!("try")          ~ space?      ~
+        body: pbasicblock    ~ multispace? ~
+        tag!("catch")        ~ space       ~
+        name: plocal_name    ~ space?      ~
+        handler: pbasicblock ~
+        pterminal            ,
+
+        ||{ Try::new(body, name, handler) }
+    )
+}
+
+/// Parses a path that ends with a const (eg. `@a` or `foo.@a`).
+fn pconst_path(input: PBytes) -> PResult<Path> {
+    let result = ppath(input);
+
+    match result {
+        IResult::Done(_, ref p) if !p.ends_with_const() => {
+            IResult::Error(NomErr::Position(ErrorKind::Tag, input))
+        },
+        _ => result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        passignment, pbasicblock, pcall, pconst, pdefn, plocal, ppath, pmodule, preturn, pstatic
+        passignment, pbasicblock, pcall, pconst, pdefn, pfn, plocal, ppath, pmodule, preturn,
+        pstatic, pswitch, pthrow, ptry, pvalue
     };
     use super::super::util::{PBytes};
     use nom::{Err as NomErr, ErrorKind, IResult};
@@ -396,7 +570,11 @@ mod tests {
     fn parse_path() {
         assert_eq!(ppath(b"a"), IResult::Done(EMPTY, Path::from_str("a").unwrap()));
 
-        assert_eq!(ppath(b"b.c"), IResult::Done(EMPTY, Path::from_str("b.c").unwrap()))
+        assert_eq!(ppath(b"b.c"), IResult::Done(EMPTY, Path::from_str("b.c").unwrap()));
+
+        assert_eq!(ppath(b"_.std.int.from_string"), IResult::Done(EMPTY, Path::from_str("_.std.int.from_string").unwrap()));
+        assert_eq!(ppath(b"a2.@b_c"), IResult::Done(EMPTY, Path::from_str("a2.@b_c").unwrap()));
+        assert!(ppath(b"2a").is_err())
     }
 
     #[test]
@@ -523,6 +701,81 @@ mod tests {
         assert_eq!(parsed_return, IResult::Done(EMPTY, expected_return))
     }
 
+    #[test]
+    fn parse_return_with_call() {
+        let parsed_return   = preturn(b"return call foo(bar)");
+        let expected_call   = Call::new(Path::from_str("foo").unwrap(), vec!["bar".to_owned()]);
+        let expected_return = Return::new(Some(Value::Call(expected_call)));
+
+        assert_eq!(parsed_return, done(expected_return))
+    }
+
+    #[test]
+    fn parse_switch() {
+        let parsed_switch = pswitch(b"switch a {\n  case @b {\n    return\n  }\n  case foo.@c {\n  }\n  default {\n    return a\n  }\n}");
+
+        let expected_switch = Switch::new(
+            "a".to_owned(),
+            vec![
+                Case::new(Path::from_str("@b").unwrap(), BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(None)),
+                ])),
+                Case::new(Path::from_str("foo.@c").unwrap(), BasicBlock::with_stmts(vec![])),
+            ],
+            Some(BasicBlock::with_stmts(vec![
+                Statement::StatementReturn(Return::new(Some(Value::with_name("a".to_owned())))),
+            ]))
+        );
+
+        assert_eq!(parsed_switch, done(expected_switch));
+
+        assert!(pswitch(b"switch a {\n  case b {\n  }\n}").is_err());
+    }
+
+    #[test]
+    fn parse_try_and_throw() {
+        let parsed_try = ptry(b"try {\n  throw a\n} catch e {\n  return e\n}");
+
+        let expected_try = Try::new(
+            BasicBlock::with_stmts(vec![
+                Statement::StatementThrow(Throw::new(Value::with_name("a".to_owned()))),
+            ]),
+            "e".to_owned(),
+            BasicBlock::with_stmts(vec![
+                Statement::StatementReturn(Return::new(Some(Value::with_name("e".to_owned())))),
+            ])
+        );
+
+        assert_eq!(parsed_try, done(expected_try));
+        assert_eq!(pthrow(b"throw @a"), done(Throw::new(Value::with_name("@a".to_owned()))));
+    }
+
+    #[test]
+    fn parse_export_modifiers() {
+        let body = BasicBlock::with_stmts(vec![]);
+
+        assert_eq!(pdefn(b"export defn foo() {}"), done(Defn::new("foo".to_owned(), vec![], body).with_export(true)));
+        assert_eq!(pstatic(b"export static $a"), done(Static::new("$a".to_owned()).with_export(true)));
+        assert_eq!(pconst(b"export const @a = b.c"), done(Const::new("@a".to_owned(), Path::from_str("b.c").unwrap(), None).with_export(true)));
+        assert_eq!(pconst(b"const @a = b.c"), done(Const::new("@a".to_owned(), Path::from_str("b.c").unwrap(), None)));
+    }
+
+    #[test]
+    fn parse_path_values() {
+        assert_eq!(pvalue(b"a.@b"), done(Value::Path(Path::from_str("a.@b").unwrap())));
+        assert_eq!(pvalue(b"_.std.null"), done(Value::Path(Path::from_str("_.std.null").unwrap())));
+        assert_eq!(pvalue(b"a"), done(Value::with_name("a".to_owned())));
+    }
+
+    #[test]
+    fn parse_rest_parameters() {
+        let body = BasicBlock::with_stmts(vec![]);
+
+        assert_eq!(pdefn(b"defn foo(a, ...rest) {}"), done(Defn::new("foo".to_owned(), vec!["a".to_owned()], body.clone()).with_rest(Some("rest".to_owned()))));
+        assert_eq!(pfn(b"fn(...rest) {}"), done(Fn::new(vec![], body.clone()).with_rest(Some("rest".to_owned()))));
+        assert_eq!(pfn(b"fn(a, b) {}"), done(Fn::new(vec!["a".to_owned(), "b".to_owned()], body)));
+    }
+
     #[test]
     fn parse_return_without_argument() {
         let parsed_return   = preturn(b"return");
@@ -602,6 +855,22 @@ mod tests {
         )
     }
 
+    #[test]
+    fn records_statement_locations() {
+        let module = unwrap_iresult(pmodule(b"mod foo\ndefn bar() {\n  baz := qux\n}"));
+
+        assert_eq!(module.locations, vec![
+            Location::Source { line: 1, column: 1 },
+            Location::Source { line: 2, column: 1 },
+        ]);
+
+        let defn = match module.stmts[1] {
+            Statement::StatementDefn(ref d) => d.clone(),
+            _ => panic!("Expected a defn"),
+        };
+        assert_eq!(defn.body.locations, vec![Location::Source { line: 3, column: 3 }]);
+    }
+
     #[test]
     fn tolerates_whitespace_before_statements() {
         let m = Mod::new(Path::with_name("foo".to_string()));
@@ -1,4 +1,6 @@
 extern crate byteorder;
+extern crate num_bigint;
+extern crate num_traits;
 
 #[macro_use]
 extern crate nom;
@@ -0,0 +1,70 @@
+//! `_.std.fn`: calling function values (defns, anonymous functions and primitives alike).
+
+use super::arg;
+use super::super::interpreter::VmErrorKind;
+use super::super::machine::{Frame, Machine};
+use super::super::value::Value;
+
+use std::rc::Rc;
+
+/// Push the result of calling `function`, or raise the exception it didn't catch. Anything
+/// else that stops the call (eg. `function` not being a function) is raised as an error string.
+fn call_function(m: &mut Machine, function: Value, args: &[Value]) {
+    match m.call_value(function, args) {
+        Ok(value) => m.stack.push(value),
+        Err(error) => match error.kind {
+            VmErrorKind::UncaughtException(value) => m.raise(value),
+            VmErrorKind::TypeError(ref type_error) => m.raise_error(format!("{}", type_error)),
+            _ => m.raise_error(format!("{}", error)),
+        },
+    }
+}
+
+/// Call the first argument with the rest of the arguments.
+fn builtin_call(m: &mut Machine, f: &Frame) {
+    let args = f.args.get(1..).unwrap_or(&[]);
+
+    call_function(m, arg(f, 0), args)
+}
+
+/// Call the first argument with the items of the list in the second as its arguments.
+fn builtin_apply(m: &mut Machine, f: &Frame) {
+    let args = try_raise!(m, m.downcast::<Vec<Value>>(&arg(f, 1))).clone();
+
+    call_function(m, arg(f, 0), &args)
+}
+
+pub fn add_function(m: &mut Machine) {
+    m.add_native(&"_.std.fn.call".to_owned(),  Rc::new(builtin_call));
+    m.add_native(&"_.std.fn.apply".to_owned(), Rc::new(builtin_apply));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::machine::Machine;
+    use super::super::super::value::Value;
+    use super::super::test_support::error;
+
+    #[test]
+    fn calls_primitive_function_values() {
+        let mut machine = Machine::new();
+        let add = Value::Native(machine.natives.id_for(&"_.std.int.add".to_owned()).unwrap());
+        let args = machine.alloc(vec![Value::Int(2), Value::Int(3)]);
+
+        assert_eq!(machine.call("_.std.fn.call", &[add, Value::Int(1), Value::Int(2)]), Ok(Value::Int(3)));
+        assert_eq!(machine.call("_.std.fn.apply", &[add, args]), Ok(Value::Int(5)));
+
+        // Exceptions raised by the function are raised again by the builtin
+        assert_eq!(error(&mut machine, "_.std.fn.call", &[add, Value::True]), "Expected int, got true");
+    }
+
+    #[test]
+    fn raises_type_errors() {
+        let mut machine = Machine::new();
+        let list = machine.alloc(vec![]);
+
+        assert_eq!(error(&mut machine, "_.std.fn.call", &[Value::Int(1)]), "Expected fn, got int");
+        assert_eq!(error(&mut machine, "_.std.fn.apply", &[Value::Null, list]), "Expected fn, got null");
+        assert_eq!(error(&mut machine, "_.std.fn.apply", &[Value::Addr(0), Value::Int(1)]), "Expected list, got int");
+    }
+}
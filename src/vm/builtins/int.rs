@@ -0,0 +1,257 @@
+//! `_.std.int`: integers of any size. Ints that fit in 64 bits are immediate `Value::Int`s and
+//! larger ones are `BigInt` objects on the heap. Results are always normalized to the immediate
+//! form when they fit, so every int has exactly one representation.
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use super::arg;
+use super::super::machine::{Frame, Machine};
+use super::super::value::{Trace, TypeError, Value};
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+impl Trace for BigInt {
+    fn trace(&self, _: &mut Vec<Value>) {}
+
+    fn type_name() -> &'static str { "int" }
+}
+
+/// Read an int of either representation.
+fn to_big(m: &Machine, value: &Value) -> Result<BigInt, TypeError> {
+    match *value {
+        Value::Int(n) => Ok(BigInt::from(n)),
+        _ => m.downcast::<BigInt>(value).map(|n| n.clone()),
+    }
+}
+
+/// Make the value for an int: immediate if it fits in 64 bits, otherwise on the heap.
+pub fn int_value(m: &mut Machine, n: BigInt) -> Value {
+    match n.to_i64() {
+        Some(n) => Value::Int(n),
+        None => m.alloc(n),
+    }
+}
+
+/// Push the result of an operation on the first two arguments. `small` is tried first when
+/// both are immediates; if it overflows (returns `None`) then `big` is used instead.
+fn binary<S, B>(m: &mut Machine, f: &Frame, small: S, big: B)
+    where S: Fn(i64, i64) -> Option<i64>,
+          B: Fn(&BigInt, &BigInt) -> BigInt {
+
+    let (a, b) = (arg(f, 0), arg(f, 1));
+
+    if let (Value::Int(a), Value::Int(b)) = (a, b) {
+        if let Some(result) = small(a, b) {
+            return m.stack.push(Value::Int(result))
+        }
+    }
+
+    let a = try_raise!(m, to_big(m, &a));
+    let b = try_raise!(m, to_big(m, &b));
+    let result = int_value(m, big(&a, &b));
+    m.stack.push(result);
+}
+
+/// Push the canonical truth value of `test` applied to the ordering of the first two arguments.
+fn comparison<F: Fn(Ordering) -> bool>(m: &mut Machine, f: &Frame, test: F) {
+    let ordering = try_raise!(m, compare(m, f));
+
+    m.stack.push(Value::from_bool(test(ordering)));
+}
+
+fn compare(m: &Machine, f: &Frame) -> Result<Ordering, TypeError> {
+    match (arg(f, 0), arg(f, 1)) {
+        (Value::Int(a), Value::Int(b)) => Ok(a.cmp(&b)),
+        (a, b) => Ok(try!(to_big(m, &a)).cmp(&try!(to_big(m, &b)))),
+    }
+}
+
+/// Raises unless the second argument is non-zero. Zero is always immediate.
+fn check_divisor(m: &mut Machine, f: &Frame) -> bool {
+    if arg(f, 1) == Value::Int(0) {
+        m.raise_error("Division by zero".to_owned());
+        return false
+    }
+    true
+}
+
+/// Const constructor: parse the decimal string argument (eg. `"-42"`).
+fn builtin_from_string(m: &mut Machine, f: &Frame) {
+    let parsed = {
+        let string = try_raise!(m, m.downcast::<String>(&arg(f, 0)));
+        string.parse::<BigInt>().map_err(|_| format!("Invalid int: {:?}", string))
+    };
+
+    let n = try_raise!(m, parsed);
+    let value = int_value(m, n);
+    m.stack.push(value);
+}
+
+/// Decimal representation of an int of either representation.
+pub fn to_decimal(m: &Machine, value: &Value) -> Result<String, TypeError> {
+    match *value {
+        Value::Int(n) => Ok(n.to_string()),
+        _ => m.downcast::<BigInt>(value).map(|n| n.to_string()),
+    }
+}
+
+fn builtin_to_string(m: &mut Machine, f: &Frame) {
+    let string = try_raise!(m, to_decimal(m, &arg(f, 0)));
+
+    let value = m.alloc(string);
+    m.stack.push(value);
+}
+
+fn builtin_add(m: &mut Machine, f: &Frame) {
+    binary(m, f, i64::checked_add, |a, b| a + b)
+}
+
+fn builtin_sub(m: &mut Machine, f: &Frame) {
+    binary(m, f, i64::checked_sub, |a, b| a - b)
+}
+
+fn builtin_mul(m: &mut Machine, f: &Frame) {
+    binary(m, f, i64::checked_mul, |a, b| a * b)
+}
+
+/// Division rounding toward zero.
+fn builtin_div(m: &mut Machine, f: &Frame) {
+    if check_divisor(m, f) {
+        binary(m, f, i64::checked_div, |a, b| a / b)
+    }
+}
+
+/// Remainder of `div`; it has the sign of the dividend.
+fn builtin_mod(m: &mut Machine, f: &Frame) {
+    if check_divisor(m, f) {
+        binary(m, f, i64::checked_rem, |a, b| a % b)
+    }
+}
+
+fn builtin_neg(m: &mut Machine, f: &Frame) {
+    let value = match arg(f, 0) {
+        Value::Int(n) if n != i64::min_value() => Value::Int(-n),
+        other => {
+            let n = try_raise!(m, to_big(m, &other));
+            int_value(m, -n)
+        },
+    };
+
+    m.stack.push(value);
+}
+
+/// Push -1, 0 or 1 as the first argument is less than, equal to or greater than the second.
+fn builtin_compare(m: &mut Machine, f: &Frame) {
+    let ordering = try_raise!(m, compare(m, f));
+
+    m.stack.push(Value::Int(ordering as i64));
+}
+
+fn builtin_eq(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o == Ordering::Equal) }
+fn builtin_ne(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o != Ordering::Equal) }
+fn builtin_lt(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o == Ordering::Less) }
+fn builtin_le(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o != Ordering::Greater) }
+fn builtin_gt(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o == Ordering::Greater) }
+fn builtin_ge(m: &mut Machine, f: &Frame) { comparison(m, f, |o| o != Ordering::Less) }
+
+pub fn add_int(m: &mut Machine) {
+    m.add_native(&"_.std.int.from_string".to_owned(), Rc::new(builtin_from_string));
+    m.add_native(&"_.std.int.to_string".to_owned(),   Rc::new(builtin_to_string));
+    m.add_native(&"_.std.int.add".to_owned(),         Rc::new(builtin_add));
+    m.add_native(&"_.std.int.sub".to_owned(),         Rc::new(builtin_sub));
+    m.add_native(&"_.std.int.mul".to_owned(),         Rc::new(builtin_mul));
+    m.add_native(&"_.std.int.div".to_owned(),         Rc::new(builtin_div));
+    m.add_native(&"_.std.int.mod".to_owned(),         Rc::new(builtin_mod));
+    m.add_native(&"_.std.int.neg".to_owned(),         Rc::new(builtin_neg));
+    m.add_native(&"_.std.int.compare".to_owned(),     Rc::new(builtin_compare));
+    m.add_native(&"_.std.int.eq".to_owned(),          Rc::new(builtin_eq));
+    m.add_native(&"_.std.int.ne".to_owned(),          Rc::new(builtin_ne));
+    m.add_native(&"_.std.int.lt".to_owned(),          Rc::new(builtin_lt));
+    m.add_native(&"_.std.int.le".to_owned(),          Rc::new(builtin_le));
+    m.add_native(&"_.std.int.gt".to_owned(),          Rc::new(builtin_gt));
+    m.add_native(&"_.std.int.ge".to_owned(),          Rc::new(builtin_ge));
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+
+    use super::super::super::machine::Machine;
+    use super::super::super::value::Value;
+    use super::super::test_support::{error, string};
+
+    fn parse(machine: &mut Machine, digits: &str) -> Value {
+        let digits = machine.alloc(digits.to_owned());
+        machine.call("_.std.int.from_string", &[digits]).unwrap()
+    }
+
+    #[test]
+    fn promotes_overflowing_results_to_big_ints() {
+        let mut machine = Machine::new();
+        let max = Value::Int(i64::max_value());
+
+        assert_eq!(machine.call("_.std.int.add", &[Value::Int(2), Value::Int(3)]), Ok(Value::Int(5)));
+
+        let big = machine.call("_.std.int.add", &[max, Value::Int(1)]).unwrap();
+        assert_eq!(machine.downcast::<BigInt>(&big), Ok(&(BigInt::from(i64::max_value()) + 1)));
+        assert_eq!(big.type_name(&machine.heap), "int");
+
+        // Results that fit are immediate again
+        assert_eq!(machine.call("_.std.int.sub", &[big, Value::Int(1)]), Ok(max));
+
+        let min = Value::Int(i64::min_value());
+        let negated = machine.call("_.std.int.neg", &[min]).unwrap();
+        assert_eq!(machine.call("_.std.int.eq", &[negated, big]), Ok(Value::True));
+        assert_eq!(machine.call("_.std.int.div", &[min, Value::Int(-1)]).unwrap().type_name(&machine.heap), "int");
+    }
+
+    #[test]
+    fn converts_to_and_from_strings() {
+        let mut machine = Machine::new();
+        let digits = "-123456789012345678901234567890";
+
+        let n = parse(&mut machine, digits);
+        let squared = machine.call("_.std.int.mul", &[n, n]).unwrap();
+        let root = machine.call("_.std.int.div", &[squared, n]).unwrap();
+        let string_value = machine.call("_.std.int.to_string", &[root]).unwrap();
+        assert_eq!(string(&machine, string_value), digits);
+
+        assert_eq!(parse(&mut machine, "42"), Value::Int(42));
+        let invalid = machine.alloc("4x".to_owned());
+        assert_eq!(error(&mut machine, "_.std.int.from_string", &[invalid]), "Invalid int: \"4x\"");
+    }
+
+    #[test]
+    fn divides_toward_zero() {
+        let mut machine = Machine::new();
+
+        assert_eq!(machine.call("_.std.int.div", &[Value::Int(-7), Value::Int(2)]), Ok(Value::Int(-3)));
+        assert_eq!(machine.call("_.std.int.mod", &[Value::Int(-7), Value::Int(2)]), Ok(Value::Int(-1)));
+        assert_eq!(error(&mut machine, "_.std.int.div", &[Value::Int(1), Value::Int(0)]), "Division by zero");
+        assert_eq!(error(&mut machine, "_.std.int.mod", &[Value::Int(1), Value::Int(0)]), "Division by zero");
+    }
+
+    #[test]
+    fn compares_ints_of_either_size() {
+        let mut machine = Machine::new();
+        let big = parse(&mut machine, "100000000000000000000");
+
+        assert_eq!(machine.call("_.std.int.lt", &[Value::Int(1), big]), Ok(Value::True));
+        assert_eq!(machine.call("_.std.int.ge", &[Value::Int(1), big]), Ok(Value::Null));
+        assert_eq!(machine.call("_.std.int.le", &[Value::Int(1), Value::Int(1)]), Ok(Value::True));
+        assert_eq!(machine.call("_.std.int.ne", &[Value::Int(1), Value::Int(1)]), Ok(Value::Null));
+        assert_eq!(machine.call("_.std.int.compare", &[big, Value::Int(1)]), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn raises_type_errors() {
+        let mut machine = Machine::new();
+        let text = machine.alloc("1".to_owned());
+
+        assert_eq!(error(&mut machine, "_.std.int.add", &[Value::Int(1), text]), "Expected int, got string");
+        assert_eq!(error(&mut machine, "_.std.int.neg", &[]), "Expected int, got null");
+        assert_eq!(error(&mut machine, "_.std.int.gt", &[Value::True, Value::Int(1)]), "Expected int, got true");
+    }
+}
@@ -0,0 +1,25 @@
+//! Libraries of primitive functions under `_.std`.
+
+use super::machine::Frame;
+use super::value::Value;
+
+/// Unwrap a result in a primitive, raising the error (as a string) and returning if it failed.
+macro_rules! try_raise {
+    ($m:expr, $result:expr) => (
+        match $result {
+            Ok(value) => value,
+            Err(error) => return $m.raise_error(format!("{}", error)),
+        }
+    )
+}
+
+pub mod function;
+pub mod int;
+pub mod string;
+#[cfg(test)]
+mod test_support;
+
+/// Argument at `idx` of a primitive's frame, or null if it wasn't passed.
+pub fn arg(f: &Frame, idx: usize) -> Value {
+    f.args.get(idx).cloned().unwrap_or(Value::Null)
+}
@@ -0,0 +1,267 @@
+//! `_.std.string`: immutable strings. Lengths and indices count chars, except for
+//! `byte_length`.
+
+use super::arg;
+use super::int::to_decimal;
+use super::super::machine::{Frame, Machine};
+use super::super::value::{TypeError, Value};
+
+use std::rc::Rc;
+
+fn string_arg<'a>(m: &'a Machine, f: &Frame, idx: usize) -> Result<&'a String, TypeError> {
+    m.downcast::<String>(&arg(f, idx))
+}
+
+/// Read a char index; `None` if it can't index anything (it's negative or too big).
+fn index_arg(m: &Machine, f: &Frame, idx: usize) -> Result<Option<usize>, TypeError> {
+    match arg(f, idx) {
+        Value::Int(n) if n >= 0 => Ok(Some(n as usize)),
+        Value::Int(_) => Ok(None),
+        other => to_decimal(m, &other).map(|_| None),
+    }
+}
+
+/// Byte offset of the char at `idx`; the end of the string counts as a char boundary.
+fn byte_offset(string: &str, idx: usize) -> Option<usize> {
+    string.char_indices().map(|(offset, _)| offset).chain(Some(string.len())).nth(idx)
+}
+
+/// Push a new string made from the string argument at `idx`.
+fn map_string<F: Fn(&str) -> String>(m: &mut Machine, f: &Frame, idx: usize, map: F) {
+    let string = map(try_raise!(m, string_arg(m, f, idx)));
+
+    let value = m.alloc(string);
+    m.stack.push(value);
+}
+
+/// Const constructor: the argument is already a string.
+fn builtin_new(m: &mut Machine, f: &Frame) {
+    let arg1 = arg(f, 0);
+
+    try_raise!(m, m.downcast::<String>(&arg1));
+    m.stack.push(arg1);
+}
+
+/// Concatenate every argument.
+fn builtin_concat(m: &mut Machine, f: &Frame) {
+    let mut string = String::new();
+    for idx in 0..f.args.len() {
+        string.push_str(try_raise!(m, string_arg(m, f, idx)));
+    }
+
+    let value = m.alloc(string);
+    m.stack.push(value);
+}
+
+fn builtin_length(m: &mut Machine, f: &Frame) {
+    let length = try_raise!(m, string_arg(m, f, 0)).chars().count();
+
+    m.stack.push(Value::Int(length as i64));
+}
+
+fn builtin_byte_length(m: &mut Machine, f: &Frame) {
+    let length = try_raise!(m, string_arg(m, f, 0)).len();
+
+    m.stack.push(Value::Int(length as i64));
+}
+
+/// The chars from the start index up to (but excluding) the end index; the end defaults to
+/// the end of the string.
+fn builtin_slice(m: &mut Machine, f: &Frame) {
+    let slice = {
+        let string = try_raise!(m, string_arg(m, f, 0));
+        let start = try_raise!(m, index_arg(m, f, 1));
+        let end = match arg(f, 2) {
+            Value::Null => Some(string.chars().count()),
+            _ => try_raise!(m, index_arg(m, f, 2)),
+        };
+
+        match (start.and_then(|start| byte_offset(string, start)), end.and_then(|end| byte_offset(string, end))) {
+            (Some(start), Some(end)) if start <= end => Ok(string[start..end].to_owned()),
+            _ => Err(format!("Slice out of range of a string of length {}", string.chars().count())),
+        }
+    };
+
+    let string = try_raise!(m, slice);
+    let value = m.alloc(string);
+    m.stack.push(value);
+}
+
+/// Index of the first occurrence of the second argument in the first, or null if there's none.
+fn builtin_index_of(m: &mut Machine, f: &Frame) {
+    let index = {
+        let string = try_raise!(m, string_arg(m, f, 0));
+        let needle = try_raise!(m, string_arg(m, f, 1));
+
+        string.find(&needle[..]).map(|offset| string[..offset].chars().count())
+    };
+
+    m.stack.push(index.map_or(Value::Null, |index| Value::Int(index as i64)));
+}
+
+/// Split the first argument at every occurrence of the (non-empty) separator into a list.
+fn builtin_split(m: &mut Machine, f: &Frame) {
+    let parts: Vec<String> = {
+        let string = try_raise!(m, string_arg(m, f, 0));
+        let separator = try_raise!(m, string_arg(m, f, 1));
+
+        if separator.is_empty() {
+            return m.raise_error("Empty separator".to_owned())
+        }
+        string.split(&separator[..]).map(|part| part.to_owned()).collect()
+    };
+
+    let items = parts.into_iter().map(|part| m.alloc(part)).collect::<Vec<Value>>();
+    let list = m.alloc(items);
+    m.stack.push(list);
+}
+
+/// Join a list of strings with the separator between each of them.
+fn builtin_join(m: &mut Machine, f: &Frame) {
+    let joined = {
+        let list = try_raise!(m, m.downcast::<Vec<Value>>(&arg(f, 0)));
+        let separator = try_raise!(m, string_arg(m, f, 1));
+
+        let mut parts = vec![];
+        for item in list.iter() {
+            parts.push(&try_raise!(m, m.downcast::<String>(item))[..]);
+        }
+        parts.join(separator)
+    };
+
+    let value = m.alloc(joined);
+    m.stack.push(value);
+}
+
+/// Push true if the strings have the same contents, otherwise null.
+fn builtin_eq(m: &mut Machine, f: &Frame) {
+    let equal = try_raise!(m, string_arg(m, f, 0)) == try_raise!(m, string_arg(m, f, 1));
+
+    m.stack.push(Value::from_bool(equal));
+}
+
+/// Push -1, 0 or 1 as the first string sorts before, the same as or after the second.
+fn builtin_compare(m: &mut Machine, f: &Frame) {
+    let ordering = try_raise!(m, string_arg(m, f, 0)).cmp(try_raise!(m, string_arg(m, f, 1)));
+
+    m.stack.push(Value::Int(ordering as i64));
+}
+
+fn builtin_to_upper(m: &mut Machine, f: &Frame) {
+    map_string(m, f, 0, str::to_uppercase)
+}
+
+fn builtin_to_lower(m: &mut Machine, f: &Frame) {
+    map_string(m, f, 0, str::to_lowercase)
+}
+
+/// Remove leading and trailing whitespace.
+fn builtin_trim(m: &mut Machine, f: &Frame) {
+    map_string(m, f, 0, |string| string.trim().to_owned())
+}
+
+/// Decimal representation of an int.
+fn builtin_from_int(m: &mut Machine, f: &Frame) {
+    let string = try_raise!(m, to_decimal(m, &arg(f, 0)));
+
+    let value = m.alloc(string);
+    m.stack.push(value);
+}
+
+pub fn add_string(m: &mut Machine) {
+    m.add_native(&"_.std.string.new".to_owned(),         Rc::new(builtin_new));
+    m.add_native(&"_.std.string.concat".to_owned(),      Rc::new(builtin_concat));
+    m.add_native(&"_.std.string.length".to_owned(),      Rc::new(builtin_length));
+    m.add_native(&"_.std.string.byte_length".to_owned(), Rc::new(builtin_byte_length));
+    m.add_native(&"_.std.string.slice".to_owned(),       Rc::new(builtin_slice));
+    m.add_native(&"_.std.string.index_of".to_owned(),    Rc::new(builtin_index_of));
+    m.add_native(&"_.std.string.split".to_owned(),       Rc::new(builtin_split));
+    m.add_native(&"_.std.string.join".to_owned(),        Rc::new(builtin_join));
+    m.add_native(&"_.std.string.eq".to_owned(),          Rc::new(builtin_eq));
+    m.add_native(&"_.std.string.compare".to_owned(),     Rc::new(builtin_compare));
+    m.add_native(&"_.std.string.to_upper".to_owned(),    Rc::new(builtin_to_upper));
+    m.add_native(&"_.std.string.to_lower".to_owned(),    Rc::new(builtin_to_lower));
+    m.add_native(&"_.std.string.trim".to_owned(),        Rc::new(builtin_trim));
+    m.add_native(&"_.std.string.from_int".to_owned(),    Rc::new(builtin_from_int));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::machine::Machine;
+    use super::super::super::value::Value;
+    use super::super::test_support::{error, string};
+
+    fn call_string(machine: &mut Machine, path: &str, args: &[Value]) -> String {
+        let value = machine.call(path, args).unwrap();
+        string(machine, value)
+    }
+
+    #[test]
+    fn counts_and_slices_chars() {
+        let mut machine = Machine::new();
+        let s = machine.alloc("héllo wörld".to_owned());
+        let o = machine.alloc("o".to_owned());
+
+        assert_eq!(machine.call("_.std.string.length", &[s]), Ok(Value::Int(11)));
+        assert_eq!(machine.call("_.std.string.byte_length", &[s]), Ok(Value::Int(13)));
+        assert_eq!(call_string(&mut machine, "_.std.string.slice", &[s, Value::Int(1), Value::Int(4)]), "éll");
+        assert_eq!(call_string(&mut machine, "_.std.string.slice", &[s, Value::Int(6)]), "wörld");
+        assert_eq!(call_string(&mut machine, "_.std.string.slice", &[s, Value::Int(11)]), "");
+        assert_eq!(machine.call("_.std.string.index_of", &[s, o]), Ok(Value::Int(4)));
+        assert_eq!(machine.call("_.std.string.index_of", &[o, s]), Ok(Value::Null));
+
+        let message = "Slice out of range of a string of length 11";
+        assert_eq!(error(&mut machine, "_.std.string.slice", &[s, Value::Int(12)]), message);
+        assert_eq!(error(&mut machine, "_.std.string.slice", &[s, Value::Int(4), Value::Int(2)]), message);
+        assert_eq!(error(&mut machine, "_.std.string.slice", &[s, Value::Int(-1)]), message);
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        let mut machine = Machine::new();
+        let s = machine.alloc("a,b,,c".to_owned());
+        let comma = machine.alloc(",".to_owned());
+        let dash = machine.alloc("-".to_owned());
+        let empty = machine.alloc("".to_owned());
+
+        let parts = machine.call("_.std.string.split", &[s, comma]).unwrap();
+        let strings: Vec<String> = machine.downcast::<Vec<Value>>(&parts).unwrap().iter().map(|part| string(&machine, *part)).collect();
+        assert_eq!(strings, vec!["a", "b", "", "c"]);
+        assert_eq!(call_string(&mut machine, "_.std.string.join", &[parts, dash]), "a-b--c");
+        assert_eq!(error(&mut machine, "_.std.string.split", &[s, empty]), "Empty separator");
+
+        let mixed = machine.alloc(vec![s, Value::Int(1)]);
+        assert_eq!(error(&mut machine, "_.std.string.join", &[mixed, dash]), "Expected string, got int");
+    }
+
+    #[test]
+    fn transforms_and_compares_strings() {
+        let mut machine = Machine::new();
+        let a = machine.alloc("  Abc ".to_owned());
+        let b = machine.alloc("abd".to_owned());
+        let copy = machine.alloc("abd".to_owned());
+
+        assert_eq!(call_string(&mut machine, "_.std.string.trim", &[a]), "Abc");
+        assert_eq!(call_string(&mut machine, "_.std.string.to_upper", &[a]), "  ABC ");
+        assert_eq!(call_string(&mut machine, "_.std.string.to_lower", &[a]), "  abc ");
+        assert_eq!(call_string(&mut machine, "_.std.string.concat", &[b, a, b]), "abd  Abc abd");
+        assert_eq!(call_string(&mut machine, "_.std.string.from_int", &[Value::Int(-12)]), "-12");
+
+        assert_eq!(machine.call("_.std.string.eq", &[b, copy]), Ok(Value::True));
+        assert_eq!(machine.call("_.std.string.eq", &[a, b]), Ok(Value::Null));
+        assert_eq!(machine.call("_.std.string.compare", &[a, b]), Ok(Value::Int(-1)));
+        assert_eq!(machine.call("_.std.string.compare", &[b, copy]), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn raises_type_errors() {
+        let mut machine = Machine::new();
+        let s = machine.alloc("a".to_owned());
+
+        assert_eq!(error(&mut machine, "_.std.string.concat", &[s, Value::Int(1)]), "Expected string, got int");
+        assert_eq!(error(&mut machine, "_.std.string.length", &[]), "Expected string, got null");
+        assert_eq!(error(&mut machine, "_.std.string.slice", &[s, Value::True]), "Expected int, got true");
+        assert_eq!(error(&mut machine, "_.std.string.from_int", &[s]), "Expected int, got string");
+        assert_eq!(error(&mut machine, "_.std.string.new", &[Value::Int(1)]), "Expected string, got int");
+    }
+}
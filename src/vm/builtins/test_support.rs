@@ -0,0 +1,18 @@
+//! Helpers shared by the tests of the builtin libraries.
+
+use super::super::interpreter::VmErrorKind;
+use super::super::machine::Machine;
+use super::super::value::Value;
+
+/// Contents of the string the value refers to.
+pub fn string(machine: &Machine, value: Value) -> String {
+    machine.downcast::<String>(&value).unwrap().clone()
+}
+
+/// Message of the exception calling the function raises.
+pub fn error(machine: &mut Machine, path: &str, args: &[Value]) -> String {
+    match machine.call(path, args).map_err(|error| error.kind) {
+        Err(VmErrorKind::UncaughtException(value)) => string(machine, value),
+        other => panic!("Expected an exception: {:?}", other),
+    }
+}
@@ -3,184 +3,189 @@
 use super::types::*;
 use super::util::*;
 
-use std::io::{Cursor, Write};
+use std::io::Cursor;
 
 pub type BBytes<'a> = &'a Vec<u8>;
 
+/// Bytecode that couldn't be decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    /// Address of the op that couldn't be decoded
+    pub addr: Addr,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeErrorKind {
+    /// The code ended in the middle of an op
+    UnexpectedEnd,
+    /// The byte at the start of the op isn't the opcode of any op
+    InvalidOpcode(u8),
+    /// An operand doesn't fit in its field
+    OperandTooLarge,
+}
+
+/// An op that couldn't be encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncodeError {
+    /// The value doesn't fit in its field in the encoding (eg. an address of 2^35 or more in
+    /// the compact encoding)
+    OperandTooLarge(u64),
+}
+
 /// Defines interface for reading and writing a ops (instructions) to/from bytecode. All ops in
 /// this module must implement this trait so that the VM can decode its instruction sequence
 /// well-known op structures.
-pub trait BinarySerializable {
-    fn from_binary(&mut Cursor<BBytes>) -> Self;
-    fn to_binary(&self) -> Vec<u8>;
+pub trait BinarySerializable: Sized {
+    fn from_binary(&mut Cursor<BBytes>, Encoding) -> DecodeResult<Self>;
+    fn to_binary(&self, Encoding) -> EncodeResult<Vec<u8>>;
 }
 
+/// `BGetArg` index that gets the number of arguments rather than an argument.
+pub const ARG_COUNT: u8 = 255;
+
 pub trait IntoOpConvertable {
     fn into_op(self) -> BOp;
 }
 
-#[derive(Clone, Debug)]
-pub enum BOp {
-    FnEntry(BFnEntry),
-    GetLocal(BGetLocal),
-    SetLocal(BSetLocal),
-    Call(BCall),
-    Invoke(BInvoke),
-    PushAddress(BPushAddress),
-    LoadConst(BLoadConst),
-    BranchIf(BBranchIf),
-    BranchIfNot(BBranchIfNot),
-    Return,
-    Pop,
-    Noop,
-}
-impl BOp {
-    pub fn to_binary(self) -> Vec<u8> {
-        let mut bytes = vec![self.opcode()];
-
-        match self {
-            BOp::FnEntry(e)     => bytes.write(&e.to_binary()).unwrap(),
-            BOp::GetLocal(g)    => bytes.write(&g.to_binary()).unwrap(),
-            BOp::SetLocal(s)    => bytes.write(&s.to_binary()).unwrap(),
-            BOp::Call(c)        => bytes.write(&c.to_binary()).unwrap(),
-            BOp::Invoke(i)      => bytes.write(&i.to_binary()).unwrap(),
-            BOp::PushAddress(a) => bytes.write(&a.to_binary()).unwrap(),
-            BOp::LoadConst(e)  => bytes.write(&e.to_binary()).unwrap(),
-            BOp::BranchIf(b)    => bytes.write(&b.to_binary()).unwrap(),
-            BOp::BranchIfNot(b) => bytes.write(&b.to_binary()).unwrap(),
-            BOp::Return         => 0,
-            BOp::Pop            => 0,
-            BOp::Noop           => 0,
-        };
-
-        bytes
+define_ops! {
+    /// No-op entry to a function that sets up the local slots for the function. Must always be
+    /// first op in a function.
+    0 => FnEntry(BFnEntry, "fn_entry") {
+        /// Number of parameters; these occupy the first local slots and are filled from the
+        /// frame's arguments
+        num_params: u8 = count,
+        /// Defines the number of local slots (including the parameters)
+        num_locals: u16 = size,
     }
 
-    /// Take a vector of ops and convert them to a binary op sequence.
-    pub fn compile_ops(ops: Vec<BOp>) -> Vec<u8> {
-        ops.into_iter().flat_map(|op| op.to_binary()).collect()
+    /// Get the value of a local variable.
+    1 => GetLocal(BGetLocal, "get_local") {
+        idx: Local = local,
     }
 
-    pub fn opcode(&self) -> u8 {
-        match self {
-            &BOp::FnEntry(_)     => 0,
-            &BOp::GetLocal(_)    => 1,
-            &BOp::SetLocal(_)    => 2,
-            &BOp::Call(_)        => 3,
-            &BOp::Invoke(_)      => 4,
-            &BOp::Return         => 5,
-            &BOp::PushAddress(_) => 6,
-            &BOp::LoadConst(_)  => 7,
-            &BOp::BranchIf(_)    => 8,
-            &BOp::BranchIfNot(_) => 9,
-            &BOp::Pop            => 10,
-            &BOp::Noop           => 11,
-        }
+    /// Set the value of a local variable to that of the given argument.
+    2 => SetLocal(BSetLocal, "set_local") {
+        idx: Local = local,
     }
 
-    pub fn from_binary(input: &mut Cursor<BBytes>) -> Self {
-        let op = input.read_hu8();
-
-        match op {
-            0  => BOp::FnEntry(BFnEntry::from_binary(input)),
-            1  => BOp::GetLocal(BGetLocal::from_binary(input)),
-            2  => BOp::SetLocal(BSetLocal::from_binary(input)),
-            3  => BOp::Call(BCall::from_binary(input)),
-            4  => BOp::Invoke(BInvoke::from_binary(input)),
-            5  => BOp::Return,
-            6  => BOp::PushAddress(BPushAddress::from_binary(input)),
-            7  => BOp::BranchIf(BBranchIf::from_binary(input)),
-            8  => BOp::BranchIfNot(BBranchIfNot::from_binary(input)),
-            9  => BOp::Pop,
-            10 => BOp::Noop,
-            _  => panic!("Invalid opcode: {:?}"),
-        }
+    /// Call a function at a specific address in the virtual machine.
+    3 => Call(BCall, "call") {
+        /// Address of the function to be called
+        addr: Addr = addr,
+        /// Number of arguments that have been pushed to the stack.
+        num_args: u8 = count,
     }
 
-    /// Returns the offset of the given address field in the op's compiled bytecode
-    #[allow(unused_variables)]
-    pub fn addr_field_offset(&self, idx: u8) -> u64 {
-        let offset = match self {
-            &BOp::Call(_)        => 0,
-            &BOp::BranchIf(_)    => 0,
-            &BOp::BranchIfNot(_) => 0,
-            &BOp::LoadConst(_)   => 0,
-            _                    => panic!("Op has no address fields: {:?}", self),
-        };
-
-        // 1 byte needed for the actual opcode
-        1 + offset
+    /// Consume an address off the stack and call the function at that address.
+    4 => Invoke(BInvoke, "invoke") {
+        num_args: u8 = count,
     }
-}
 
-/// Call a function at a specific address in the virtual machine.
-#[derive(Clone, Debug)]
-pub struct BCall {
-    /// Address of the function to be called
-    pub addr: Addr,
-    /// Number of arguments that have been pushed to the stack.
-    pub num_args: u8,
-}
-// addr:u64 num_args:u8
-impl BinarySerializable for BCall {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BCall {
-        let addr     = input.read_addr();
-        let num_args = input.read_hu8();
-        BCall { addr: addr, num_args: num_args, }
+    6 => PushAddress(BPushAddress, "push_address") {
+        addr: Addr = addr,
     }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_addr(self.addr);
-        bytes.write_hu8(self.num_args);
-        bytes
+
+    7 => LoadConst(BLoadConst, "load_const") {
+        id: u32 = id,
     }
-}
-impl IntoOpConvertable for BCall {
-    fn into_op(self) -> BOp {
-        BOp::Call(self)
+
+    8 => BranchIf(BBranchIf, "branch_if") {
+        dest: Addr = addr,
     }
-}
 
-/// Consume an address off the stack and call the function at that address.
-#[derive(Clone, Debug)]
-pub struct BInvoke {
-    pub num_args: u8,
-}
-impl BinarySerializable for BInvoke {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BInvoke {
-        let num_args = input.read_hu8();
-        BInvoke { num_args: num_args, }
+    9 => BranchIfNot(BBranchIfNot, "branch_if_not") {
+        dest: Addr = addr,
     }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_hu8(self.num_args);
-        bytes
+
+    /// Call a function at a specific address in place of the current function. The current
+    /// frame is replaced by the callee's frame, so the callee returns directly to our caller.
+    12 => TailCall(BTailCall, "tail_call") {
+        /// Address of the function to be called
+        addr: Addr = addr,
+        /// Number of arguments that have been pushed to the stack.
+        num_args: u8 = count,
     }
-}
-impl IntoOpConvertable for BInvoke {
-    fn into_op(self) -> BOp {
-        BOp::Invoke(self)
+
+    /// Consume an address off the stack and call the function at that address in place of the
+    /// current function (see `BTailCall`).
+    13 => TailInvoke(BTailInvoke, "tail_invoke") {
+        num_args: u8 = count,
     }
-}
 
-/// Call a native function.
-pub struct BCallNative {
-    /// Internal identifier of the function
-    id: u32,
-    num_args: u8,
-}
+    /// Call a native (primitive) function. The loader rewrites calls to primitives into these.
+    14 => CallNative(BCallNative, "call_native") {
+        /// Id of the function in the machine's `NativeRegistry`
+        id: u32 = native,
+        num_args: u8 = count,
+    }
+
+    /// Call a native function and then return from the current function (the native
+    /// counterpart of `BTailCall`).
+    15 => TailCallNative(BTailCallNative, "tail_call_native") {
+        /// Id of the function in the machine's `NativeRegistry`
+        id: u32 = native,
+        num_args: u8 = count,
+    }
+
+    /// Continue execution at `dest`.
+    16 => Jump(BJump, "jump") {
+        dest: Addr = addr,
+    }
+
+    /// Pop a test value and then one value per target. If the test value is identical to one
+    /// of the popped values then continue at the corresponding target, otherwise continue at
+    /// `default`. The first value popped after the test corresponds to the last target.
+    17 => Switch(BSwitch, "switch") {
+        default: Addr = addr,
+        targets: Vec<Addr> = addr_table,
+    }
+
+    /// Collect the arguments after the first `idx` into a list stored in local `idx`. Follows
+    /// the `FnEntry` of functions with a rest parameter.
+    19 => CollectRest(BCollectRest, "collect_rest") {
+        idx: Local = local,
+    }
+
+    /// Get an argument from the stack frame of the current function; null if fewer arguments
+    /// were passed.
+    21 => GetArg(BGetArg, "get_arg") {
+        /// Index of the argument, pass `ARG_COUNT` to get the total number of arguments passed
+        idx: u8 = count,
+    }
 
-impl BinarySerializable for BCallNative {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BCallNative {
-        let id       = input.read_hu32();
-        let num_args = input.read_hu8();
-        BCallNative { id: id, num_args: num_args, }
+    /// Push the value of a static variable.
+    22 => GetStatic(BGetStatic, "get_static") {
+        /// Slot of the static in the machine's `statics`
+        id: u32 = id,
     }
-    fn to_binary(&self) -> Vec<u8> {
+
+    /// Pop a value and store it in a static variable.
+    23 => SetStatic(BSetStatic, "set_static") {
+        /// Slot of the static in the machine's `statics`
+        id: u32 = id,
+    }
+
+    ;
+
+    /// Return from a function.
+    5  => Return("return"),
+    10 => Pop("pop"),
+    11 => Noop("noop"),
+    /// Pop a value and throw it as an exception (see `Machine::unwind`).
+    18 => Throw("throw"),
+    /// Push the null value.
+    20 => PushNull("push_null"),
+}
+
+impl BOp {
+    /// Take a vector of ops and convert them to a binary op sequence.
+    pub fn compile_ops(ops: Vec<BOp>, encoding: Encoding) -> EncodeResult<Vec<u8>> {
         let mut bytes = vec![];
-        bytes.write_hu32(self.id);
-        bytes.write_hu8(self.num_args);
-        bytes
+        for op in ops {
+            bytes.extend(try!(op.to_binary(encoding)));
+        }
+        Ok(bytes)
     }
 }
 
@@ -196,171 +201,106 @@ impl BinarySerializable for BCallNative {
 //     }
 // }
 
-/// Set the value of a local variable to that of the given argument.
-#[derive(Clone, Debug)]
-pub struct BSetLocal {
-    pub idx: Local,
-}
-impl BinarySerializable for BSetLocal {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BSetLocal {
-        let idx = input.read_local();
-        BSetLocal { idx: idx, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_local(self.idx);
-        bytes
-    }
-}
-impl IntoOpConvertable for BSetLocal {
-    fn into_op(self) -> BOp {
-        BOp::SetLocal(self)
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::bytecode::util::Encoding;
+    use std::io::Cursor;
 
-/// Get the value of a local variable.
-#[derive(Clone, Debug)]
-pub struct BGetLocal {
-    pub idx: Local,
-}
-impl BinarySerializable for BGetLocal {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BGetLocal {
-        let idx = input.read_local();
-        BGetLocal { idx: idx, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_local(self.idx);
-        bytes
+    fn all_ops() -> Vec<BOp> {
+        vec![
+            BFnEntry { num_params: 2, num_locals: 300, }.into_op(),
+            BGetLocal { idx: 1, }.into_op(),
+            BSetLocal { idx: 200, }.into_op(),
+            BCall { addr: 0x1234, num_args: 2, }.into_op(),
+            BInvoke { num_args: 3, }.into_op(),
+            BPushAddress { addr: 42, }.into_op(),
+            BLoadConst { id: 7, }.into_op(),
+            BBranchIf { dest: 9, }.into_op(),
+            BBranchIfNot { dest: 10, }.into_op(),
+            BTailCall { addr: 0x5678, num_args: 1, }.into_op(),
+            BTailInvoke { num_args: 4, }.into_op(),
+            BCallNative { id: 2, num_args: 1, }.into_op(),
+            BTailCallNative { id: 3, num_args: 0, }.into_op(),
+            BJump { dest: 11, }.into_op(),
+            BSwitch { default: 12, targets: vec![13, 14, 0x1_0000], }.into_op(),
+            BCollectRest { idx: 2, }.into_op(),
+            BGetArg { idx: ARG_COUNT, }.into_op(),
+            BGetStatic { id: 4, }.into_op(),
+            BSetStatic { id: 0x1_0000, }.into_op(),
+            BOp::Return,
+            BOp::Pop,
+            BOp::Noop,
+            BOp::Throw,
+            BOp::PushNull,
+        ]
     }
-}
-impl IntoOpConvertable for BGetLocal {
-    fn into_op(self) -> BOp {
-        BOp::GetLocal(self)
-    }
-}
 
-/// Get an argument from the stack frame of the current function.
-pub struct BGetArg {
-    /// Index of the argument, pass 255 to get the total number of arguments passed
-    idx: u8,
-}
-impl BinarySerializable for BGetArg {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BGetArg {
-        let idx = input.read_hu8();
-        BGetArg { idx: idx, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_hu8(self.idx);
-        bytes
-    }
-}
+    #[test]
+    fn round_trips_every_op() {
+        for &encoding in [Encoding::Fixed, Encoding::Compact].iter() {
+            for op in all_ops() {
+                let bytes = op.clone().to_binary(encoding).unwrap();
+                let mut cursor = Cursor::new(&bytes);
+                let decoded = BOp::from_binary(&mut cursor, encoding).unwrap();
 
-/// No-op entry to a function that sets up the local slots for the function. Must always be first
-/// op in a function.
-#[derive(Clone, Debug)]
-pub struct BFnEntry {
-    /// Defines the number of local slots
-    pub num_locals: u16,
-}
-impl BinarySerializable for BFnEntry {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BFnEntry {
-        let num_locals = input.read_hu16();
-        BFnEntry { num_locals: num_locals, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_hu16(self.num_locals);
-        bytes
-    }
-}
-impl IntoOpConvertable for BFnEntry {
-    fn into_op(self) -> BOp {
-        BOp::FnEntry(self)
+                assert_eq!(cursor.position(), bytes.len() as u64);
+                assert_eq!(decoded.opcode(), op.opcode());
+                assert_eq!(decoded.mnemonic(), op.mnemonic());
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", op));
+            }
+        }
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct BPushAddress {
-    pub addr: Addr,
-}
-impl BinarySerializable for BPushAddress {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BPushAddress {
-        let addr = input.read_addr();
-        BPushAddress { addr: addr, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_addr(self.addr);
-        bytes
-    }
-}
-impl IntoOpConvertable for BPushAddress {
-    fn into_op(self) -> BOp {
-        BOp::PushAddress(self)
+    #[test]
+    fn opcodes_are_unique() {
+        let mut opcodes: Vec<u8> = all_ops().iter().map(|op| op.opcode()).collect();
+        opcodes.sort();
+        opcodes.dedup();
+        assert_eq!(opcodes.len(), all_ops().len());
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct BLoadConst {
-    pub id: u32,
-}
-impl BinarySerializable for BLoadConst {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BLoadConst {
-        let id = input.read_hu32();
-        BLoadConst { id: id, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_hu32(self.id);
-        bytes
-    }
-}
-impl IntoOpConvertable for BLoadConst {
-    fn into_op(self) -> BOp {
-        BOp::LoadConst(self)
-    }
-}
+    #[test]
+    fn finds_relocatable_field_offsets() {
+        let call = BCall { addr: 5, num_args: 1, }.into_op();
+        assert_eq!(call.addr_field_offset(0, Encoding::Fixed), 1);
+        assert_eq!(call.addr_field_offset(0, Encoding::Compact), 1);
 
-#[derive(Clone, Debug)]
-pub struct BBranchIf {
-    pub dest: Addr,
-}
-impl BinarySerializable for BBranchIf {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BBranchIf {
-        let addr = input.read_addr();
-        BBranchIf { dest: addr, }
-    }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_addr(self.dest);
-        bytes
-    }
-}
-impl IntoOpConvertable for BBranchIf {
-    fn into_op(self) -> BOp {
-        BOp::BranchIf(self)
-    }
-}
+        let load = BLoadConst { id: 3, }.into_op();
+        assert_eq!(load.addr_field_offset(0, Encoding::Compact), 1);
 
-#[derive(Clone, Debug)]
-pub struct BBranchIfNot {
-    pub dest: Addr,
-}
-impl BinarySerializable for BBranchIfNot {
-    fn from_binary(input: &mut Cursor<BBytes>) -> BBranchIfNot {
-        let addr = input.read_addr();
-        BBranchIfNot { dest: addr, }
+        // Default, then the table length, then each target
+        let switch = BSwitch { default: 1, targets: vec![2, 3], }.into_op();
+        assert_eq!(switch.addr_field_offset(0, Encoding::Fixed), 1);
+        assert_eq!(switch.addr_field_offset(1, Encoding::Fixed), 11);
+        assert_eq!(switch.addr_field_offset(2, Encoding::Fixed), 19);
+        assert_eq!(switch.addr_field_offset(2, Encoding::Compact), 12);
     }
-    fn to_binary(&self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.write_addr(self.dest);
-        bytes
+
+    #[test]
+    fn native_calls_have_the_same_size_as_calls() {
+        for &encoding in [Encoding::Fixed, Encoding::Compact].iter() {
+            let call = BCall { addr: 1, num_args: 2, }.into_op().to_binary(encoding).unwrap();
+            let native = BCallNative { id: 1, num_args: 2, }.into_op().to_binary(encoding).unwrap();
+            assert_eq!(call.len(), native.len());
+        }
     }
-}
-impl IntoOpConvertable for BBranchIfNot {
-    fn into_op(self) -> BOp {
-        BOp::BranchIfNot(self)
+
+    #[test]
+    fn reports_undecodable_ops() {
+        let code = vec![BOp::Return.opcode(), 0xff];
+        let mut cursor = Cursor::new(&code);
+        assert!(BOp::from_binary(&mut cursor, Encoding::Fixed).is_ok());
+        assert_eq!(
+            BOp::from_binary(&mut cursor, Encoding::Fixed).unwrap_err(),
+            DecodeError { addr: 1, kind: DecodeErrorKind::InvalidOpcode(0xff), }
+        );
+
+        let code = BOp::compile_ops(vec![BCall { addr: 1, num_args: 0, }.into_op()], Encoding::Fixed).unwrap();
+        let truncated = code[..4].to_vec();
+        assert_eq!(
+            BOp::from_binary(&mut Cursor::new(&truncated), Encoding::Fixed).unwrap_err(),
+            DecodeError { addr: 0, kind: DecodeErrorKind::UnexpectedEnd, }
+        );
     }
 }
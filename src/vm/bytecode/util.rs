@@ -1,63 +1,533 @@
+use super::ops::{DecodeErrorKind, EncodeError};
 use super::types::*;
 
-use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io;
 
-/// Adds host-native read and write methods, ie. `read_hu64` ("read host unsigned 64").
-pub trait NativeEndianReadExt: io::Read + ReadBytesExt {
-    fn read_hu8(&mut self) -> u8   { self.read_u8().unwrap() }
-    fn read_hu16(&mut self) -> u16 { self.read_u16::<NativeEndian>().unwrap() }
-    fn read_hu32(&mut self) -> u32 { self.read_u32::<NativeEndian>().unwrap() }
-    fn read_hu64(&mut self) -> u64 { self.read_u64::<NativeEndian>().unwrap() }
+pub type DecodeResult<T> = Result<T, DecodeErrorKind>;
+pub type EncodeResult<T> = Result<T, EncodeError>;
+
+/// The only way reading from in-memory bytecode can fail is by running out of bytes.
+fn unexpected_end<E>(_: E) -> DecodeErrorKind {
+    DecodeErrorKind::UnexpectedEnd
+}
+
+/// Adds little-endian read and write methods, ie. `read_lu64` ("read little-endian unsigned 64").
+/// Bytecode is always little-endian so that it can be moved between hosts.
+pub trait LittleEndianReadExt: io::Read + ReadBytesExt {
+    fn read_lu8(&mut self) -> DecodeResult<u8>   { self.read_u8().map_err(unexpected_end) }
+    fn read_lu16(&mut self) -> DecodeResult<u16> { self.read_u16::<LittleEndian>().map_err(unexpected_end) }
+    fn read_lu32(&mut self) -> DecodeResult<u32> { self.read_u32::<LittleEndian>().map_err(unexpected_end) }
+    fn read_lu64(&mut self) -> DecodeResult<u64> { self.read_u64::<LittleEndian>().map_err(unexpected_end) }
+}
+impl<R: io::Read + ReadBytesExt> LittleEndianReadExt for R {}
+
+pub trait LittleEndianWriteExt: io::Write + WriteBytesExt {
+    fn write_lu8(&mut self, u: u8)   { self.write_u8(u).unwrap() }
+    fn write_lu16(&mut self, u: u16) { self.write_u16::<LittleEndian>(u).unwrap() }
+    fn write_lu32(&mut self, u: u32) { self.write_u32::<LittleEndian>(u).unwrap() }
+    fn write_lu64(&mut self, u: u64) { self.write_u64::<LittleEndian>(u).unwrap() }
 }
-impl<R: io::Read + ReadBytesExt> NativeEndianReadExt for R {}
+impl<R: io::Write + WriteBytesExt> LittleEndianWriteExt for R {}
 
-pub trait NativeEndianWriteExt: io::Write + WriteBytesExt {
-    fn write_hu8(&mut self, u: u8)   { self.write_u8(u).unwrap() }
-    fn write_hu16(&mut self, u: u16) { self.write_u16::<NativeEndian>(u).unwrap() }
-    fn write_hu32(&mut self, u: u32) { self.write_u32::<NativeEndian>(u).unwrap() }
-    fn write_hu64(&mut self, u: u64) { self.write_u64::<NativeEndian>(u).unwrap() }
+/// How the operands of ops are encoded in bytecode.
+///
+/// - `Fixed`: every operand is written at its full width (eg. addresses are 8 bytes).
+/// - `Compact`: numbers are written as unsigned LEB128. Fields that are patched by relocations
+///   at load time (addresses and ids) are padded to a fixed width of `PADDED_LEB_WIDTH` bytes
+///   so that they can be rewritten in place.
+///
+/// Small functions' code is roughly 30% smaller in the compact encoding (eg. 28 bytes instead of
+/// 40 for two functions with locals and a tail call).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Fixed,
+    Compact,
 }
-impl<R: io::Write + WriteBytesExt> NativeEndianWriteExt for R {}
 
-/// Extension to `NativeEndianReadWriteExt` to add type-specific reading functions to work with
+impl Default for Encoding {
+    fn default() -> Encoding { Encoding::Fixed }
+}
+
+/// Number of bytes used for relocatable fields in the compact encoding; enough for 35 bits.
+pub const PADDED_LEB_WIDTH: u64 = 5;
+
+/// Largest value that fits in a padded LEB128 field.
+pub const MAX_PADDED_ULEB: u64 = (1 << (7 * PADDED_LEB_WIDTH)) - 1;
+
+impl Encoding {
+    /// Number of bytes taken by an address field.
+    pub fn addr_width(&self) -> u64 {
+        match *self {
+            Encoding::Fixed   => 8,
+            Encoding::Compact => PADDED_LEB_WIDTH,
+        }
+    }
+
+    /// Largest address that can be written into an address field.
+    pub fn max_addr(&self) -> Addr {
+        match *self {
+            Encoding::Fixed   => ::std::u64::MAX,
+            Encoding::Compact => MAX_PADDED_ULEB,
+        }
+    }
+
+    /// Number of bytes taken by an id (eg. const id) field.
+    pub fn id_width(&self) -> u64 {
+        match *self {
+            Encoding::Fixed   => 4,
+            Encoding::Compact => PADDED_LEB_WIDTH,
+        }
+    }
+}
+
+/// Reading and writing of unsigned LEB128 numbers.
+pub trait LebReadExt: io::Read + ReadBytesExt {
+    fn read_uleb(&mut self) -> DecodeResult<u64> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = try!(self.read_u8().map_err(unexpected_end));
+            let bits = (byte & 0x7f) as u64;
+
+            if shift >= 64 || (bits << shift) >> shift != bits {
+                return Err(DecodeErrorKind::OperandTooLarge)
+            }
+            result |= bits << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(result)
+            }
+            shift += 7;
+        }
+    }
+
+    /// Read a LEB128 number that must fit in `max`.
+    fn read_uleb_max(&mut self, max: u64) -> DecodeResult<u64> {
+        let u = try!(self.read_uleb());
+
+        if u > max {
+            Err(DecodeErrorKind::OperandTooLarge)
+        } else {
+            Ok(u)
+        }
+    }
+}
+impl<R: io::Read + ReadBytesExt> LebReadExt for R {}
+
+pub trait LebWriteExt: io::Write + WriteBytesExt {
+    fn write_uleb(&mut self, mut u: u64) {
+        loop {
+            let byte = (u & 0x7f) as u8;
+            u >>= 7;
+
+            if u == 0 {
+                self.write_u8(byte).unwrap();
+                return
+            }
+            self.write_u8(byte | 0x80).unwrap();
+        }
+    }
+
+    /// Write `u` as LEB128 padded out to exactly `PADDED_LEB_WIDTH` bytes. Nothing is written
+    /// if it doesn't fit.
+    fn write_padded_uleb(&mut self, u: u64) -> EncodeResult<()> {
+        if u > MAX_PADDED_ULEB {
+            return Err(EncodeError::OperandTooLarge(u))
+        }
+
+        for idx in 0..PADDED_LEB_WIDTH {
+            let byte = ((u >> (7 * idx)) & 0x7f) as u8;
+            let continuation = if idx < PADDED_LEB_WIDTH - 1 { 0x80 } else { 0 };
+            self.write_u8(byte | continuation).unwrap();
+        }
+        Ok(())
+    }
+}
+impl<W: io::Write + WriteBytesExt> LebWriteExt for W {}
+
+/// Extension to `LittleEndianReadExt` to add type-specific reading functions to work with
 /// the correct size of the types in the bytecode.
-pub trait ReadTypesExt: NativeEndianReadExt {
-    fn read_addr(&mut self) -> u64  { self.read_hu64() }
-    fn read_local(&mut self) -> u16 { self.read_hu16() }
+pub trait ReadTypesExt: LittleEndianReadExt + LebReadExt {
+    fn read_addr(&mut self, encoding: Encoding) -> DecodeResult<Addr> {
+        match encoding {
+            Encoding::Fixed   => self.read_lu64(),
+            Encoding::Compact => self.read_uleb(),
+        }
+    }
+
+    fn read_local(&mut self, encoding: Encoding) -> DecodeResult<Local> {
+        match encoding {
+            Encoding::Fixed   => self.read_lu16(),
+            Encoding::Compact => self.read_uleb_max(Local::max_value() as u64).map(|u| u as Local),
+        }
+    }
+
+    /// Read an id that may be patched by a relocation.
+    fn read_id(&mut self, encoding: Encoding) -> DecodeResult<u32> {
+        match encoding {
+            Encoding::Fixed   => self.read_lu32(),
+            Encoding::Compact => self.read_uleb_max(u32::max_value() as u64).map(|u| u as u32),
+        }
+    }
+
+    /// Read the id of a native function. These take as much space as an address so that the
+    /// loader can rewrite a call to a primitive into a native call in place.
+    fn read_native_id(&mut self, encoding: Encoding) -> DecodeResult<u32> {
+        let id = try!(self.read_addr(encoding));
+
+        if id > u32::max_value() as u64 {
+            Err(DecodeErrorKind::OperandTooLarge)
+        } else {
+            Ok(id as u32)
+        }
+    }
+
+    /// Read a list of addresses prefixed by its length.
+    fn read_addr_table(&mut self, encoding: Encoding) -> DecodeResult<Vec<Addr>> {
+        let len = try!(self.read_size(encoding));
+        let mut table = vec![];
+
+        for _ in 0..len {
+            table.push(try!(self.read_addr(encoding)));
+        }
+
+        Ok(table)
+    }
+
+    /// Read a small number such as an argument count.
+    fn read_count(&mut self, encoding: Encoding) -> DecodeResult<u8> {
+        match encoding {
+            Encoding::Fixed   => self.read_lu8(),
+            Encoding::Compact => self.read_uleb_max(u8::max_value() as u64).map(|u| u as u8),
+        }
+    }
+
+    /// Read a number such as a count of local slots.
+    fn read_size(&mut self, encoding: Encoding) -> DecodeResult<u16> {
+        match encoding {
+            Encoding::Fixed   => self.read_lu16(),
+            Encoding::Compact => self.read_uleb_max(u16::max_value() as u64).map(|u| u as u16),
+        }
+    }
 }
-impl<R: NativeEndianReadExt> ReadTypesExt for R {}
+impl<R: LittleEndianReadExt + LebReadExt> ReadTypesExt for R {}
 
 /// Extension to add type-specific writing functions for the various types in the bytecode.
-pub trait WriteTypesExt {
-    fn write_addr(&mut self, Addr);
-    fn write_local(&mut self, Local);
-}
-/// Enable writing bytecode types to `Vec<u8>`.
-impl WriteTypesExt for Vec<u8> {
-    fn write_addr(&mut self, addr: Addr) {
-        self.write_u64::<NativeEndian>(addr).unwrap()
+pub trait WriteTypesExt: LittleEndianWriteExt + LebWriteExt {
+    fn write_addr(&mut self, addr: Addr, encoding: Encoding) -> EncodeResult<()> {
+        match encoding {
+            Encoding::Fixed   => Ok(self.write_lu64(addr)),
+            Encoding::Compact => self.write_padded_uleb(addr),
+        }
     }
 
-    fn write_local(&mut self, local: Local) {
-        self.write_u16::<NativeEndian>(local).unwrap()
+    fn write_local(&mut self, local: Local, encoding: Encoding) {
+        match encoding {
+            Encoding::Fixed   => self.write_lu16(local),
+            Encoding::Compact => self.write_uleb(local as u64),
+        }
+    }
+
+    fn write_id(&mut self, id: u32, encoding: Encoding) -> EncodeResult<()> {
+        match encoding {
+            Encoding::Fixed   => Ok(self.write_lu32(id)),
+            Encoding::Compact => self.write_padded_uleb(id as u64),
+        }
+    }
+
+    fn write_native_id(&mut self, id: u32, encoding: Encoding) -> EncodeResult<()> {
+        self.write_addr(id as u64, encoding)
+    }
+
+    fn write_addr_table(&mut self, table: &Vec<Addr>, encoding: Encoding) -> EncodeResult<()> {
+        try!(self.write_table_len(table.len(), encoding));
+
+        for addr in table.iter() {
+            try!(self.write_addr(*addr, encoding));
+        }
+        Ok(())
+    }
+
+    fn write_count(&mut self, count: u8, encoding: Encoding) {
+        match encoding {
+            Encoding::Fixed   => self.write_lu8(count),
+            Encoding::Compact => self.write_uleb(count as u64),
+        }
+    }
+
+    fn write_size(&mut self, size: u16, encoding: Encoding) {
+        match encoding {
+            Encoding::Fixed   => self.write_lu16(size),
+            Encoding::Compact => self.write_uleb(size as u64),
+        }
+    }
+
+    /// Write the number of entries of a table as a size, failing if it doesn't fit in one.
+    fn write_table_len(&mut self, len: usize, encoding: Encoding) -> EncodeResult<()> {
+        if len > u16::max_value() as usize {
+            return Err(EncodeError::OperandTooLarge(len as u64))
+        }
+
+        Ok(self.write_size(len as u16, encoding))
     }
 }
+/// Enable writing bytecode types to `Vec<u8>` and to cursors over existing code.
+impl<W: LittleEndianWriteExt + LebWriteExt> WriteTypesExt for W {}
 
-macro_rules! serialize {
+/// Read an operand of the given kind (see `define_ops!`).
+macro_rules! read_operand {
+    ($input:expr, addr,  $encoding:expr) => ($input.read_addr($encoding));
+    ($input:expr, local, $encoding:expr) => ($input.read_local($encoding));
+    ($input:expr, id,    $encoding:expr) => ($input.read_id($encoding));
+    ($input:expr, count, $encoding:expr) => ($input.read_count($encoding));
+    ($input:expr, size,  $encoding:expr) => ($input.read_size($encoding));
+    ($input:expr, native, $encoding:expr) => ($input.read_native_id($encoding));
+    ($input:expr, addr_table, $encoding:expr) => ($input.read_addr_table($encoding));
+}
+
+/// Write an operand of the given kind (see `define_ops!`), returning early with the error if
+/// it doesn't fit in its field.
+macro_rules! write_operand {
+    ($output:expr, addr,  $value:expr, $encoding:expr) => (try!($output.write_addr($value, $encoding)));
+    ($output:expr, local, $value:expr, $encoding:expr) => ($output.write_local($value, $encoding));
+    ($output:expr, id,    $value:expr, $encoding:expr) => (try!($output.write_id($value, $encoding)));
+    ($output:expr, count, $value:expr, $encoding:expr) => ($output.write_count($value, $encoding));
+    ($output:expr, size,  $value:expr, $encoding:expr) => ($output.write_size($value, $encoding));
+    ($output:expr, native, $value:expr, $encoding:expr) => (try!($output.write_native_id($value, $encoding)));
+    ($output:expr, addr_table, $value:expr, $encoding:expr) => (try!($output.write_addr_table(&$value, $encoding)));
+}
+
+/// Lay out an operand of the given kind, returning the offsets in `$output` of the fields in
+/// it that can be patched by relocations at load time. Those fields have a fixed width, so
+/// only space is left for them.
+macro_rules! write_operand_noting_relocatable {
+    ($output:expr, addr_table, $value:expr, $encoding:expr) => ({
+        try!($output.write_table_len($value.len(), $encoding));
+        $value.iter().map(|_| {
+            let offset = $output.len() as u64;
+            $output.extend(vec![0; $encoding.addr_width() as usize]);
+            offset
+        }).collect::<Vec<u64>>()
+    });
+    ($output:expr, addr, $value:expr, $encoding:expr) => ({
+        let offset = $output.len() as u64;
+        $output.extend(vec![0; $encoding.addr_width() as usize]);
+        vec![offset]
+    });
+    ($output:expr, native, $value:expr, $encoding:expr) => ({
+        let offset = $output.len() as u64;
+        $output.extend(vec![0; $encoding.addr_width() as usize]);
+        vec![offset]
+    });
+    ($output:expr, id, $value:expr, $encoding:expr) => ({
+        let offset = $output.len() as u64;
+        $output.extend(vec![0; $encoding.id_width() as usize]);
+        vec![offset]
+    });
+    ($output:expr, $kind:ident, $value:expr, $encoding:expr) => ({
+        write_operand!($output, $kind, $value, $encoding);
+        vec![]
+    });
+}
+
+/// Defines the ops of the bytecode from a single table. Each op with operands is written as
+///
+/// ```text
+/// OPCODE => Variant(StructName, "mnemonic") { field: type = kind, ... }
+/// ```
+///
+/// where `kind` is one of `addr`, `local`, `id`, `native`, `count`, `size` or `addr_table` and
+/// decides how the field is encoded (see `ReadTypesExt`). Ops without operands follow a `;` as `OPCODE => Variant("mnemonic")`.
+///
+/// From that it generates the operand structs with their `BinarySerializable` and
+/// `IntoOpConvertable` impls, the `BOp` enum, and `BOp`'s encoder, decoder, opcode, mnemonic and
+/// relocatable field offsets.
+macro_rules! define_ops {
     (
-        $name:ident,
-        from($from_arg:ident) $from_block:block,
-        to($to_arg:ident) $to_block:expr
+        $(
+            $(#[$attr:meta])*
+            $opcode:literal => $variant:ident($name:ident, $mnemonic:expr) {
+                $( $(#[$field_attr:meta])* $field:ident : $ty:ty = $kind:ident ),* $(,)*
+            }
+        )*
+        ;
+        $(
+            $(#[$simple_attr:meta])*
+            $simple_opcode:literal => $simple_variant:ident($simple_mnemonic:expr)
+        ),* $(,)*
     ) => {
-        impl BinarySerializable for $name {
-            fn from_binary($from_arg: &mut Cursor<BBytes>) -> $name $from_block
+        $(
+            $(#[$attr])*
+            #[derive(Clone, Debug)]
+            pub struct $name {
+                $( $(#[$field_attr])* pub $field: $ty, )*
+            }
+
+            impl BinarySerializable for $name {
+                fn from_binary(input: &mut Cursor<BBytes>, encoding: Encoding) -> DecodeResult<$name> {
+                    $( let $field = try!(read_operand!(input, $kind, encoding)); )*
+                    Ok($name { $( $field: $field, )* })
+                }
+                fn to_binary(&self, encoding: Encoding) -> EncodeResult<Vec<u8>> {
+                    let mut bytes = vec![];
+                    $( write_operand!(bytes, $kind, self.$field, encoding); )*
+                    Ok(bytes)
+                }
+            }
+
+            impl IntoOpConvertable for $name {
+                fn into_op(self) -> BOp {
+                    BOp::$variant(self)
+                }
+            }
+
+            impl $name {
+                /// Offsets of the relocatable fields within the encoded operands.
+                fn relocatable_offsets(&self, encoding: Encoding) -> EncodeResult<Vec<u64>> {
+                    let mut bytes: Vec<u8> = vec![];
+                    let offsets: Vec<Vec<u64>> = vec![
+                        $( write_operand_noting_relocatable!(bytes, $kind, self.$field, encoding), )*
+                    ];
+                    Ok(offsets.concat())
+                }
+            }
+        )*
+
+        #[derive(Clone, Debug)]
+        pub enum BOp {
+            $( $variant($name), )*
+            $( $(#[$simple_attr])* $simple_variant, )*
+        }
+
+        impl BOp {
+            pub fn to_binary(self, encoding: Encoding) -> EncodeResult<Vec<u8>> {
+                let mut bytes = vec![self.opcode()];
+
+                match self {
+                    $( BOp::$variant(ref op) => bytes.extend(try!(op.to_binary(encoding))), )*
+                    $( BOp::$simple_variant => {}, )*
+                }
+
+                Ok(bytes)
+            }
+
+            /// Decode the op at the cursor's position, leaving the cursor after it.
+            pub fn from_binary(input: &mut Cursor<BBytes>, encoding: Encoding) -> Result<BOp, DecodeError> {
+                let addr = input.position();
+
+                BOp::decode(input, encoding).map_err(|kind| DecodeError { addr: addr, kind: kind, })
+            }
+
+            fn decode(input: &mut Cursor<BBytes>, encoding: Encoding) -> DecodeResult<BOp> {
+                let opcode = try!(input.read_lu8());
 
-            fn to_binary(&self) -> Vec<u8> {
-                let $to_arg: Vec<u8> = vec![];
-                $to_block;
-                $to_arg
+                match opcode {
+                    $( $opcode => Ok(BOp::$variant(try!($name::from_binary(input, encoding)))), )*
+                    $( $simple_opcode => Ok(BOp::$simple_variant), )*
+                    _ => Err(DecodeErrorKind::InvalidOpcode(opcode)),
+                }
+            }
+
+            pub fn opcode(&self) -> u8 {
+                match self {
+                    $( &BOp::$variant(_) => $opcode, )*
+                    $( &BOp::$simple_variant => $simple_opcode, )*
+                }
+            }
+
+            /// Name of the op in disassembly.
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $( &BOp::$variant(_) => $mnemonic, )*
+                    $( &BOp::$simple_variant => $simple_mnemonic, )*
+                }
+            }
+
+            /// Returns the offset of the `idx`th relocatable (address or id) field in the op's
+            /// compiled bytecode. Each entry of an address table counts as a field.
+            pub fn addr_field_offset(&self, idx: usize, encoding: Encoding) -> u64 {
+                let offsets = match self {
+                    // Ops that can't be encoded have no fields to relocate
+                    $( &BOp::$variant(ref op) => op.relocatable_offsets(encoding).unwrap_or(vec![]), )*
+                    $( &BOp::$simple_variant => vec![], )*
+                };
+
+                match offsets.get(idx) {
+                    // 1 byte needed for the actual opcode
+                    Some(offset) => 1 + offset,
+                    None => panic!("Op has no address field {}: {:?}", idx, self),
+                }
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::bytecode::ops::{BCall, BSwitch, IntoOpConvertable};
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips_uleb() {
+        for &u in [0, 1, 127, 128, 300, 1 << 35, ::std::u64::MAX].iter() {
+            let mut bytes: Vec<u8> = vec![];
+            bytes.write_uleb(u);
+            assert_eq!(Cursor::new(bytes).read_uleb(), Ok(u));
+        }
+
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_uleb(300);
+        assert_eq!(bytes, vec![0xac, 0x02]);
+    }
+
+    #[test]
+    fn padded_uleb_has_fixed_width() {
+        let mut bytes: Vec<u8> = vec![];
+        assert_eq!(bytes.write_padded_uleb(1), Ok(()));
+        assert_eq!(bytes.len() as u64, PADDED_LEB_WIDTH);
+        assert_eq!(Cursor::new(bytes).read_uleb(), Ok(1));
+    }
+
+    #[test]
+    fn rejects_values_too_large_for_padded_fields() {
+        let mut bytes: Vec<u8> = vec![];
+        assert_eq!(bytes.write_padded_uleb(MAX_PADDED_ULEB), Ok(()));
+        assert_eq!(bytes.write_padded_uleb(1 << 35), Err(EncodeError::OperandTooLarge(1 << 35)));
+        assert_eq!(bytes.write_addr(1 << 40, Encoding::Compact), Err(EncodeError::OperandTooLarge(1 << 40)));
+        assert_eq!(bytes.len() as u64, PADDED_LEB_WIDTH);
+        assert_eq!(BCall { addr: 1 << 35, num_args: 0, }.into_op().to_binary(Encoding::Compact).unwrap_err(),
+                   EncodeError::OperandTooLarge(1 << 35));
+    }
+
+    #[test]
+    fn rejects_addr_tables_too_long_for_their_size() {
+        let len = u16::max_value() as usize + 1;
+        let mut bytes: Vec<u8> = vec![];
+        assert_eq!(bytes.write_addr_table(&vec![0; len], Encoding::Fixed), Err(EncodeError::OperandTooLarge(len as u64)));
+        assert!(bytes.is_empty());
+
+        let switch = BSwitch { default: 0, targets: vec![0; len], }.into_op();
+        assert_eq!(switch.to_binary(Encoding::Compact), Err(EncodeError::OperandTooLarge(len as u64)));
+        assert!(BSwitch { default: 0, targets: vec![0; len - 1], }.into_op().to_binary(Encoding::Fixed).is_ok());
+    }
+
+    #[test]
+    fn writes_addr_in_place() {
+        for &encoding in [Encoding::Fixed, Encoding::Compact].iter() {
+            let mut bytes: Vec<u8> = vec![0; encoding.addr_width() as usize];
+            Cursor::new(&mut bytes[..]).write_addr(0x1234, encoding).unwrap();
+            assert_eq!(Cursor::new(bytes).read_addr(encoding), Ok(0x1234));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_operands() {
+        assert_eq!(Cursor::new(vec![0x80]).read_uleb(), Err(DecodeErrorKind::UnexpectedEnd));
+        assert_eq!(Cursor::new(vec![0xff; 11]).read_uleb(), Err(DecodeErrorKind::OperandTooLarge));
+        assert_eq!(Cursor::new(vec![0x80, 0x02]).read_count(Encoding::Compact), Err(DecodeErrorKind::OperandTooLarge));
+        assert_eq!(Cursor::new(vec![1, 2]).read_addr(Encoding::Fixed), Err(DecodeErrorKind::UnexpectedEnd));
+    }
+}
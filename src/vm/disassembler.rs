@@ -0,0 +1,313 @@
+use asm_compiler::{
+    CompiledModule,
+    CompiledRelocationTarget,
+};
+use super::bytecode::ops::BOp;
+use super::bytecode::types::Addr;
+use super::bytecode::util::Encoding;
+use super::machine::{is_static_path, LinkTarget, Machine, TableValue};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// Things that contain bytecode which can be rendered as human-readable text.
+pub trait Disassemble {
+    /// Decode the bytecode and print one op per line with its address.
+    fn disassemble(&self) -> String;
+}
+
+/// Labels for addresses (eg. function names) that are printed before the op at that address.
+pub type Labels = HashMap<Addr, String>;
+
+/// Notes about the address fields of ops, keyed by the address of the field; they're printed
+/// as a comment after the op containing the field.
+pub type Annotations = HashMap<Addr, String>;
+
+/// Returns the address an op refers to, if it has one.
+fn target_addr(op: &BOp) -> Option<Addr> {
+    match op {
+        &BOp::Call(ref c)           => Some(c.addr),
+        &BOp::TailCall(ref c)       => Some(c.addr),
+        &BOp::PushAddress(ref a)    => Some(a.addr),
+        &BOp::BranchIf(ref b)       => Some(b.dest),
+        &BOp::BranchIfNot(ref b)    => Some(b.dest),
+        &BOp::Jump(ref j)           => Some(j.dest),
+        _                           => None,
+    }
+}
+
+fn format_operands(op: &BOp) -> String {
+    match op {
+        &BOp::FnEntry(ref e)        => format!("params={} locals={}", e.num_params, e.num_locals),
+        &BOp::GetArg(ref g)         => format!("{}", g.idx),
+        &BOp::GetLocal(ref g)       => format!("{}", g.idx),
+        &BOp::SetLocal(ref s)       => format!("{}", s.idx),
+        &BOp::CollectRest(ref c)    => format!("{}", c.idx),
+        &BOp::Call(ref c)           => format!("{:#010x}, {}", c.addr, c.num_args),
+        &BOp::TailCall(ref c)       => format!("{:#010x}, {}", c.addr, c.num_args),
+        &BOp::Invoke(ref i)         => format!("{}", i.num_args),
+        &BOp::TailInvoke(ref i)     => format!("{}", i.num_args),
+        &BOp::CallNative(ref c)     => format!("#{}, {}", c.id, c.num_args),
+        &BOp::TailCallNative(ref c) => format!("#{}, {}", c.id, c.num_args),
+        &BOp::PushAddress(ref a)    => format!("{:#010x}", a.addr),
+        &BOp::LoadConst(ref l)      => format!("{}", l.id),
+        &BOp::GetStatic(ref g)      => format!("{}", g.id),
+        &BOp::SetStatic(ref s)      => format!("{}", s.id),
+        &BOp::BranchIf(ref b)       => format!("{:#010x}", b.dest),
+        &BOp::BranchIfNot(ref b)    => format!("{:#010x}", b.dest),
+        &BOp::Jump(ref j)           => format!("{:#010x}", j.dest),
+        &BOp::Switch(ref s)         => {
+            let targets: Vec<String> = s.targets.iter().map(|t| format!("{:#010x}", t)).collect();
+            format!("{:#010x}, [{}]", s.default, targets.join(", "))
+        },
+        &BOp::Return |
+        &BOp::Pop    |
+        &BOp::Noop   |
+        &BOp::Throw  |
+        &BOp::PushNull              => String::new(),
+    }
+}
+
+/// Disassemble `code`, labelling addresses found in `labels` and adding `annotations` to the
+/// ops containing the annotated fields. Ops whose target address has a label also get that
+/// label as a comment. Disassembly stops at the first op that can't be decoded.
+pub fn disassemble(code: &Vec<u8>, encoding: Encoding, labels: &Labels, annotations: &Annotations) -> String {
+    let mut out = String::new();
+    let mut cursor = Cursor::new(code);
+
+    while (cursor.position() as usize) < code.len() {
+        let addr = cursor.position();
+        let op = match BOp::from_binary(&mut cursor, encoding) {
+            Ok(op) => op,
+            Err(error) => {
+                out.push_str(&format!("  {:08x}  ; invalid bytecode: {:?}\n", addr, error.kind));
+                break
+            },
+        };
+        let next_addr = cursor.position();
+
+        if let Some(label) = labels.get(&addr) {
+            out.push_str(&format!("{}:\n", label));
+        }
+
+        let mut line = format!("  {:08x}  {:<18}{}", addr, op.mnemonic(), format_operands(&op));
+
+        let mut fields: Vec<(&Addr, &String)> = annotations.iter()
+            .filter(|&(field, _)| addr < *field && *field < next_addr)
+            .collect();
+        fields.sort();
+        let mut notes: Vec<String> = fields.into_iter().map(|(_, note)| note.clone()).collect();
+
+        if let Some(label) = target_addr(&op).and_then(|target| labels.get(&target)) {
+            notes.push(format!("<{}>", label));
+        }
+
+        if !notes.is_empty() {
+            line = format!("{}  ; {}", line.trim_end(), notes.join(" "));
+        }
+
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+impl Disassemble for CompiledModule {
+    fn disassemble(&self) -> String {
+        let labels: Labels = self.functions.iter()
+            .map(|&(ref name, addr)| (addr, name.clone()))
+            .collect();
+
+        let annotations: Annotations = self.relocations.iter()
+            .map(|&(site, ref target)| {
+                let note = match target {
+                    &CompiledRelocationTarget::InternalAddress(addr) => {
+                        format!("-> {:#010x}", addr)
+                    },
+                    &CompiledRelocationTarget::ExternalFunctionPath(ref path) => {
+                        format!("-> {}", path)
+                    },
+                    &CompiledRelocationTarget::ConstPath(ref path) if is_static_path(path) => {
+                        format!("-> static {}", path)
+                    },
+                    &CompiledRelocationTarget::ConstPath(ref path) => {
+                        format!("-> const {}", path)
+                    },
+                };
+                (site, note)
+            })
+            .collect();
+
+        format!("; module {}\n{}", self.name, disassemble(&self.code, self.encoding, &labels, &annotations))
+    }
+}
+
+/// Disassembles the linked code of all modules loaded into the machine, labelling functions
+/// with the paths they're registered under in the symbol table.
+impl Disassemble for Machine {
+    fn disassemble(&self) -> String {
+        // A function can be defined under several paths (eg. a `use` alias), so label it with
+        // the first in sorted order to keep the output stable
+        let mut defns: Vec<(Addr, &String)> = self.symbol_table.iter()
+            .filter_map(|(path, value)| {
+                match value {
+                    &TableValue::Defn(addr) => Some((addr, path)),
+                    _ => None,
+                }
+            })
+            .collect();
+        defns.sort();
+
+        let mut labels = Labels::new();
+        for (addr, path) in defns {
+            labels.entry(addr).or_insert_with(|| path.clone());
+        }
+
+        let mut annotations = Annotations::new();
+        for linked in &self.links {
+            annotations.insert(linked.link.site, link_note(&linked.link.target, ""));
+        }
+        for pending in &self.pending {
+            annotations.insert(pending.link.site, link_note(&pending.link.target, "unresolved "));
+        }
+
+        disassemble(&self.code, self.encoding, &labels, &annotations)
+    }
+}
+
+fn link_note(target: &LinkTarget, prefix: &str) -> String {
+    match target {
+        &LinkTarget::Function(ref path) => format!("-> {}{}", prefix, path),
+        &LinkTarget::Const(ref path)    => format!("-> {}const {}", prefix, path),
+        &LinkTarget::Static(ref path)   => format!("-> {}static {}", prefix, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, Annotations, Disassemble, Labels};
+    use asm_compiler::CompileModule;
+    use asm::{BasicBlock, Call, Defn, Fn, Mod, Module, Path, Return, Statement, Value};
+    use vm::bytecode::ops::*;
+    use vm::bytecode::util::Encoding;
+    use vm::machine::{Link, LinkTarget, Machine, ModuleLink, TableValue};
+
+    #[test]
+    fn disassembles_one_op_per_line() {
+        let code = BOp::compile_ops(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BCall { addr: 0, num_args: 0, }.into_op(),
+            BOp::Return,
+        ], Encoding::Fixed).unwrap();
+
+        let mut labels = Labels::new();
+        labels.insert(0, "foo".to_owned());
+
+        let mut annotations = Annotations::new();
+        annotations.insert(5, "-> bar".to_owned());
+
+        assert_eq!(
+            disassemble(&code, Encoding::Fixed, &labels, &annotations),
+            "foo:\n".to_owned() +
+            "  00000000  fn_entry          params=0 locals=1\n" +
+            "  00000004  call              0x00000000, 0  ; -> bar <foo>\n" +
+            "  0000000e  return\n"
+        )
+    }
+
+    #[test]
+    fn disassembles_compiled_module() {
+        let module = Module::with_stmts(vec![
+            Statement::StatementMod(Mod::new(Path::with_name("foo".to_owned()))),
+            Statement::StatementDefn(Defn::new(
+                "bar".to_owned(),
+                vec![],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(Some(Value::Fn(Fn::new(
+                        vec![],
+                        BasicBlock::with_stmts(vec![
+                            Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                                Path::from_str("_.std.println").unwrap(),
+                                vec![]
+                            )))))
+                        ])
+                    ))))),
+                ])
+            )),
+        ]);
+        let output = module.compile().disassemble();
+
+        assert!(output.starts_with("; module foo\n"));
+        assert!(output.contains("tail_call         0x00000000, 0  ; -> _.std.println\n"));
+        // The anonymous function is compiled before `bar`, so it's at the start of the code
+        assert!(output.contains("bar:\n  0000000e  fn_entry"));
+        assert!(output.contains("push_address      0x00000000  ; -> 0x00000000\n"));
+    }
+
+    #[test]
+    fn disassembles_machine_with_symbols() {
+        let mut machine = Machine::new();
+        machine.code = BOp::compile_ops(vec![
+            BFnEntry { num_params: 0, num_locals: 0, }.into_op(),
+            BOp::Return,
+            BFnEntry { num_params: 0, num_locals: 0, }.into_op(),
+            BTailCall { addr: 0, num_args: 0, }.into_op(),
+        ], Encoding::Fixed).unwrap();
+        machine.symbol_table.set_symbol(&"foo.a".to_owned(), TableValue::Defn(0));
+        machine.symbol_table.set_symbol(&"foo.b".to_owned(), TableValue::Defn(5));
+
+        assert_eq!(
+            machine.disassemble(),
+            "foo.a:\n".to_owned() +
+            "  00000000  fn_entry          params=0 locals=0\n" +
+            "  00000004  return\n" +
+            "foo.b:\n" +
+            "  00000005  fn_entry          params=0 locals=0\n" +
+            "  00000009  tail_call         0x00000000, 0  ; <foo.a>\n"
+        )
+    }
+
+    #[test]
+    fn annotates_links_of_machine() {
+        let mut machine = Machine::new();
+        machine.code = BOp::compile_ops(vec![
+            BFnEntry { num_params: 0, num_locals: 0, }.into_op(),
+            BCall { addr: 0, num_args: 0, }.into_op(),
+            BLoadConst { id: 0, }.into_op(),
+            BTailCall { addr: 0, num_args: 0, }.into_op(),
+        ], Encoding::Fixed).unwrap();
+        // Aliases of the same function are labelled with the first path
+        for path in &["foo.z", "foo.a", "foo.m"] {
+            machine.symbol_table.set_symbol(&path.to_string(), TableValue::Defn(0));
+        }
+        for &(site, ref target) in [(5, LinkTarget::Function("foo.a".to_owned())), (15, LinkTarget::Const("bar.c".to_owned()))].iter() {
+            machine.links.push(ModuleLink { module: "foo".to_owned(), link: Link { site: site, target: target.clone() } });
+        }
+        machine.pending.push(ModuleLink {
+            module: "foo".to_owned(),
+            link: Link { site: 20, target: LinkTarget::Function("bar.f".to_owned()) },
+        });
+
+        assert_eq!(
+            machine.disassemble(),
+            "foo.a:\n".to_owned() +
+            "  00000000  fn_entry          params=0 locals=0\n" +
+            "  00000004  call              0x00000000, 0  ; -> foo.a <foo.a>\n" +
+            "  0000000e  load_const        0  ; -> const bar.c\n" +
+            "  00000013  tail_call         0x00000000, 0  ; -> unresolved bar.f <foo.a>\n"
+        )
+    }
+
+    #[test]
+    fn stops_at_invalid_bytecode() {
+        let mut code = BOp::compile_ops(vec![BOp::Return], Encoding::Fixed).unwrap();
+        code.push(0xff);
+
+        assert_eq!(
+            disassemble(&code, Encoding::Fixed, &Labels::new(), &Annotations::new()),
+            "  00000000  return\n".to_owned() +
+            "  00000001  ; invalid bytecode: InvalidOpcode(255)\n"
+        )
+    }
+}
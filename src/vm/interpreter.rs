@@ -1,37 +1,141 @@
+use asm_compiler::Handler;
+use super::builtins;
+use super::builtins::arg;
 use super::machine::{
+    BoxedPrimitiveFn,
     Frame,
-    IntoBox,
-    IntoPointer,
+    HOST_RETURN_ADDR,
     Machine,
+    NativeRegistry,
     SymbolTable,
+    TableKey,
     TableValue,
-    ValueBox,
-    ValuePointer
 };
-use super::bytecode::types::Addr;
+use super::bytecode::ops::{BOp, DecodeError};
+use super::bytecode::types::{Addr, Local};
+use super::bytecode::util::Encoding;
+use super::loader::FileResolver;
+use super::value::{GcConfig, Heap, TypeError, Value};
 
-use std::any::Any;
+use std::fmt;
 use std::io::{Cursor};
+use std::path::PathBuf;
 use std::rc::Rc;
 
+/// Reasons execution can stop.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmErrorKind {
+    /// Reached code that can't be decoded
+    InvalidBytecode(DecodeError),
+    /// An exception was thrown without a handler to catch it
+    UncaughtException(Value),
+    /// Reached a call or const load of a symbol that hasn't been linked (see `Machine::link`)
+    UnresolvedSymbol(TableKey),
+    /// `Machine::call` was given a symbol that isn't a function
+    NotAFunction(TableKey),
+    /// An op needed more values than there are on the stack
+    StackUnderflow,
+    /// An op needed a frame but the call stack is empty
+    MissingFrame,
+    /// An op referred to a local slot the frame doesn't have
+    InvalidLocal(Local),
+    /// An op referred to an entry the const pool doesn't have
+    InvalidConst(u32),
+    /// An op referred to a static slot the machine doesn't have
+    InvalidStatic(u32),
+    /// A native call referred to an id no primitive has
+    UnknownNative(u32),
+    /// An op was given a value of the wrong type (eg. invoking a value that isn't a function)
+    TypeError(TypeError),
+}
+
+/// Error that stopped execution, along with where it happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    /// Address of the op that failed
+    pub ip: Addr,
+    /// The failing op followed by the calls that led to it, innermost first. Stops at the
+    /// frame called by the host.
+    pub backtrace: Vec<BacktraceEntry>,
+}
+
+/// Code address in a backtrace, with the function and line it belongs to if known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BacktraceEntry {
+    pub addr: Addr,
+    pub function: Option<TableKey>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:?} at {:#x}", self.kind, self.ip));
+
+        for entry in self.backtrace.iter() {
+            try!(write!(f, "\n  at {}", entry.function.as_ref().map_or("<unknown>", |name| &name[..])));
+            if let Some(line) = entry.line {
+                try!(write!(f, " ({}:{})", entry.file.as_ref().map_or("<unknown>", |file| &file[..]), line));
+            }
+            try!(write!(f, " [{:#x}]", entry.addr));
+        }
+
+        Ok(())
+    }
+}
+
 pub trait Execute {
-    fn execute(&mut self);
+    /// Run the code starting at the instruction pointer until it returns to the host (see
+    /// `Machine::call`). Stops with an error if it reaches code that can't be decoded or an
+    /// exception isn't caught.
+    fn execute(&mut self) -> Result<(), VmError>;
 }
 
-fn builtin_println(_: &mut Machine, f: &Frame) {
-    let arg1 = *unsafe { f.args[0].into_box::<String>() };
+fn builtin_println(m: &mut Machine, f: &Frame) {
+    let result = m.downcast::<String>(&arg(f, 0)).map(|string| println!("{}", string));
 
-    if !(&arg1 as &Any).is::<String>() {
-        panic!("Expected argument 1 to be String, got {:?}", arg1)
+    if let Err(error) = result {
+        m.raise_error(format!("{}", error))
     }
+}
+
+/// Arguments of the function calling a primitive; none if the host called it directly.
+fn caller_args(m: &Machine) -> &[Value] {
+    m.call_stack.last().map_or(&[], |frame| &frame.args[..])
+}
 
-    println!("{}", arg1);
+/// Push the number of arguments passed to the calling function (as an int).
+fn builtin_args_count(m: &mut Machine, _: &Frame) {
+    let count = Value::Int(caller_args(m).len() as i64);
+
+    m.stack.push(count);
 }
 
-fn builtin_string_new(m: &mut Machine, f: &Frame) {
-    let arg1 = f.args[0];
+/// Push the argument of the calling function at the index given by the first argument (an
+/// int), or null if there's no argument at that index.
+fn builtin_args_get(m: &mut Machine, f: &Frame) {
+    let idx = match arg(f, 0) {
+        Value::Int(idx) => idx,
+        other => {
+            let found = other.type_name(&m.heap);
+            return m.raise_error(format!("{}", TypeError { expected: "int", found: found, }))
+        },
+    };
+
+    let arg = match caller_args(m).get(idx as usize) {
+        Some(arg) if idx >= 0 => *arg,
+        _ => Value::Null,
+    };
 
-    m.stack.push(arg1);
+    m.stack.push(arg);
+}
+
+/// Push the canonical truth value of the argument: true unless it's null.
+fn builtin_bool(m: &mut Machine, f: &Frame) {
+    let value = Value::from_bool(arg(f, 0).is_truthy());
+
+    m.stack.push(value);
 }
 
 impl Machine {
@@ -41,7 +145,23 @@ impl Machine {
             call_stack: vec![],
             ip: 0x0,
             stack: vec![],
+            heap: Heap::new(),
+            gc_config: Some(GcConfig::default()),
+            gc_threshold: GcConfig::default().initial_threshold,
+            pinned: vec![],
             symbol_table: SymbolTable::new(),
+            debug: vec![],
+            encoding: Encoding::default(),
+            natives: NativeRegistry::new(),
+            consts: vec![],
+            free_consts: vec![],
+            statics: vec![],
+            handlers: vec![],
+            exception: None,
+            modules: vec![],
+            links: vec![],
+            pending: vec![],
+            resolver: Box::new(FileResolver::new(vec![PathBuf::from(".")])),
         };
 
         m.add_std();
@@ -49,9 +169,61 @@ impl Machine {
         return m
     }
 
+    /// Create a machine whose code uses the given operand encoding.
+    pub fn with_encoding(encoding: Encoding) -> Machine {
+        let mut m = Machine::new();
+        m.encoding = encoding;
+        m
+    }
+
     pub fn add_std(&mut self) {
-        self.symbol_table.set_symbol(&"_.std.println".to_owned(),    TableValue::with_fn(Rc::new(builtin_println)));
-        self.symbol_table.set_symbol(&"_.std.string.new".to_owned(), TableValue::with_fn(Rc::new(builtin_string_new)));
+        self.symbol_table.set_symbol(&"_.std.null".to_owned(), TableValue::Const(Value::Null));
+        self.symbol_table.set_symbol(&"_.std.true".to_owned(), TableValue::Const(Value::True));
+
+        self.add_native(&"_.std.bool".to_owned(),       Rc::new(builtin_bool));
+        self.add_native(&"_.std.println".to_owned(),    Rc::new(builtin_println));
+        self.add_native(&"_.std.args.count".to_owned(), Rc::new(builtin_args_count));
+        self.add_native(&"_.std.args.get".to_owned(),   Rc::new(builtin_args_get));
+
+        builtins::int::add_int(self);
+        builtins::string::add_string(self);
+        builtins::function::add_function(self);
+    }
+
+    /// Add a primitive function to the symbol table and give it a native id.
+    pub fn add_native(&mut self, path: &String, f: BoxedPrimitiveFn) -> u32 {
+        let value = TableValue::with_fn(f);
+
+        let id = match value {
+            TableValue::Primitive(ref primitive) => self.natives.register(path, primitive),
+            _ => unreachable!(),
+        };
+        self.symbol_table.set_symbol(path, value);
+        self.resolve_pending();
+        id
+    }
+
+    /// Call the native function with the given id with `num_args` arguments popped off the
+    /// stack. Natives push their return value; if one doesn't, null is pushed in its place so
+    /// that native calls leave the stack like calls to bytecode functions do.
+    fn call_native(&mut self, id: u32, num_args: usize) -> Result<(), VmError> {
+        let primitive = match self.natives.get(id) {
+            Some(primitive) => primitive.clone(),
+            None => return Err(self.fault(VmErrorKind::UnknownNative(id))),
+        };
+        let frame = try!(self.build_frame(self.ip, num_args));
+        let depth = self.stack.len();
+
+        // The frame isn't on the call stack, so its arguments are pinned for the collector
+        let pins = self.pinned.len();
+        self.pinned.extend_from_slice(&frame.args);
+        primitive.call(self, &frame);
+        self.pinned.truncate(pins);
+
+        if self.stack.len() == depth {
+            self.stack.push(Value::Null);
+        }
+        Ok(())
     }
 
     #[inline]
@@ -64,110 +236,630 @@ impl Machine {
         self.call_stack.last().unwrap()
     }
 
+    /// The innermost frame, or an error if there isn't one.
+    #[inline]
+    fn current_frame(&mut self) -> Result<&mut Frame, VmError> {
+        if self.call_stack.is_empty() {
+            return Err(self.fault(VmErrorKind::MissingFrame))
+        }
+
+        Ok(self.get_stack_top_mut())
+    }
+
+    /// Pop the value on top of the stack, or fail if the stack is empty.
+    #[inline]
+    fn pop(&mut self) -> Result<Value, VmError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.fault(VmErrorKind::StackUnderflow)),
+        }
+    }
+
     /// Pop `num` entries off the top of the stack into a `Vec`. The first item in the vector
     /// will be the lowest item on the stack and the last item in the vector will be the highest
     /// (ie. at the top) of the stack.
     #[inline]
-    fn pop_stack_into_vec(&mut self, num: usize) -> Vec<ValuePointer> {
-        let mut out: Vec<ValuePointer> = Vec::with_capacity(num);
-
-        for idx in (0..num).rev() {
-            let value = self.stack.pop().unwrap();
-            out[idx] = value
+    fn pop_stack_into_vec(&mut self, num: usize) -> Result<Vec<Value>, VmError> {
+        if num > self.stack.len() {
+            return Err(self.fault(VmErrorKind::StackUnderflow))
         }
+        let at = self.stack.len() - num;
 
-        out
+        Ok(self.stack.split_off(at))
     }
 
     /// Pop `num_args` off the stack and build a stack frame with the given `return_addr`.
     #[inline]
-    fn build_frame(&mut self, return_addr: u64, num_args: usize) -> Frame {
-        Frame {
+    fn build_frame(&mut self, return_addr: u64, num_args: usize) -> Result<Frame, VmError> {
+        let args = try!(self.pop_stack_into_vec(num_args));
+
+        Ok(Frame {
             return_addr: return_addr,
-            args: self.pop_stack_into_vec(num_args),
+            args: args,
             slots: Vec::new(),
+            stack_base: self.stack.len(),
+        })
+    }
+
+    /// Error of the given kind at the op at the instruction pointer, with a backtrace of the
+    /// current call stack.
+    fn fault(&self, kind: VmErrorKind) -> VmError {
+        self.fault_at(kind, self.ip)
+    }
+
+    fn fault_at(&self, kind: VmErrorKind, addr: Addr) -> VmError {
+        VmError {
+            kind: kind,
+            ip: addr,
+            backtrace: self.backtrace(addr),
+        }
+    }
+
+    /// Describe `addr` and the call sites of the frames on the call stack, innermost first,
+    /// up to the frame called by the host.
+    pub fn backtrace(&self, addr: Addr) -> Vec<BacktraceEntry> {
+        let mut entries = vec![self.backtrace_entry(addr)];
+
+        for frame in self.call_stack.iter().rev() {
+            if frame.return_addr == HOST_RETURN_ADDR {
+                break
+            }
+
+            // The return address is just past the call op in the caller
+            entries.push(self.backtrace_entry(frame.return_addr.saturating_sub(1)));
+        }
+
+        entries
+    }
+
+    /// Name the function containing `addr` (the one with the closest entry at or before it) and
+    /// find its line in the debug information.
+    fn backtrace_entry(&self, addr: Addr) -> BacktraceEntry {
+        let function = self.symbol_table.iter()
+            .filter_map(|(path, value)| match value {
+                &TableValue::Defn(entry) if entry <= addr => Some((entry, path)),
+                _ => None,
+            })
+            .max_by_key(|&(entry, _)| entry)
+            .map(|(_, path)| path.clone());
+        let line = self.line_for(addr);
+
+        BacktraceEntry {
+            addr: addr,
+            function: function,
+            file: line.and_then(|(debug, _)| debug.file.clone()),
+            line: line.map(|(_, entry)| entry.line),
+        }
+    }
+
+    /// Call the function at `path` with the given arguments and return its result (null if it
+    /// doesn't return a value). Runs until the function returns, so primitives can use it to
+    /// call back into the VM; an exception the function doesn't catch is returned as an error.
+    ///
+    /// The result (or uncaught exception) is pinned like the values returned by `alloc`: until
+    /// the native call in progress returns or, when the host made the call, until it calls
+    /// `unpin_all`. Hosts that call functions repeatedly should unpin the results they're done
+    /// with, since the pins are otherwise kept for as long as the machine.
+    pub fn call(&mut self, path: &str, args: &[Value]) -> Result<Value, VmError> {
+        let path = path.to_owned();
+
+        let function = match self.symbol_table.get_symbol(&path).cloned() {
+            Some(TableValue::Defn(addr)) => Value::Addr(addr),
+            Some(TableValue::Primitive(ref primitive)) => Value::Native(self.natives.register(&path, primitive)),
+            Some(_) => return Err(self.fault(VmErrorKind::NotAFunction(path))),
+            None => return Err(self.fault(VmErrorKind::UnresolvedSymbol(path))),
+        };
+        self.call_value(function, args)
+    }
+
+    /// Like `call` but for a function value: a function's address or a primitive.
+    pub fn call_value(&mut self, function: Value, args: &[Value]) -> Result<Value, VmError> {
+        let ip = self.ip;
+        let depth = self.call_stack.len();
+        let stack_base = self.stack.len();
+
+        let result = match function {
+            Value::Native(id) => {
+                self.stack.extend_from_slice(args);
+                self.call_native(id, args.len()).and_then(|_| match self.exception.take() {
+                    Some(value) => Err(self.fault(VmErrorKind::UncaughtException(value))),
+                    None => Ok(()),
+                })
+            },
+            other => self.fn_addr(other).and_then(|addr| {
+                self.stack.extend_from_slice(args);
+                let frame = try!(self.build_frame(HOST_RETURN_ADDR, args.len()));
+                self.call_stack.push(frame);
+                self.ip = addr;
+                self.execute()
+            }),
+        };
+
+        let value = match result {
+            Ok(()) if self.stack.len() > stack_base => self.stack.pop().unwrap(),
+            _ => Value::Null,
+        };
+        self.ip = ip;
+        self.call_stack.truncate(depth);
+        self.stack.truncate(stack_base);
+
+        match result {
+            Ok(()) => self.pin(value),
+            Err(VmError { kind: VmErrorKind::UncaughtException(exception), .. }) => self.pin(exception),
+            Err(_) => (),
+        }
+        result.map(|_| value)
+    }
+
+    /// Remove the function value an invoke finds beneath its `num_args` arguments.
+    fn take_callee(&mut self, num_args: usize) -> Result<Value, VmError> {
+        if self.stack.len() <= num_args {
+            return Err(self.fault(VmErrorKind::StackUnderflow))
+        }
+
+        let idx = self.stack.len() - num_args - 1;
+        Ok(self.stack.remove(idx))
+    }
+
+    /// Call a native from the op at `op_addr` and get the address to carry on at: the handler
+    /// if it raised an exception, otherwise `next_addr` or, for a tail call, the current
+    /// function's return address.
+    fn run_native(&mut self, id: u32, num_args: usize, op_addr: Addr, next_addr: Addr, tail: bool) -> Result<Addr, VmError> {
+        try!(self.call_native(id, num_args));
+
+        if let Some(value) = self.exception.take() {
+            return self.unwind(value, op_addr)
+        }
+        if !tail {
+            return Ok(next_addr)
+        }
+
+        // Return the native's value from the current function
+        let return_addr = try!(self.current_frame()).return_addr;
+        self.call_stack.pop();
+        Ok(return_addr)
+    }
+
+    /// Address of the function a value refers to, or an error if it isn't a function.
+    fn fn_addr(&self, value: Value) -> Result<Addr, VmError> {
+        match value {
+            Value::Addr(addr) => Ok(addr),
+            other => Err(self.fault(VmErrorKind::TypeError(TypeError {
+                expected: "fn",
+                found: other.type_name(&self.heap),
+            }))),
+        }
+    }
+
+    /// Index of a local slot of the innermost frame, or an error if it doesn't have the slot.
+    fn local_index(&mut self, idx: Local) -> Result<usize, VmError> {
+        if try!(self.current_frame()).slots.len() <= idx as usize {
+            return Err(self.fault(VmErrorKind::InvalidLocal(idx)))
+        }
+
+        Ok(idx as usize)
+    }
+
+    /// Value of an entry of the const pool, or an error if the pool doesn't have the entry.
+    fn const_value(&mut self, id: u32) -> Result<Value, VmError> {
+        match self.consts.get(id as usize) {
+            Some(&value) => Ok(value),
+            None => Err(self.fault(VmErrorKind::InvalidConst(id))),
+        }
+    }
+
+    /// Index of a static slot, or an error if the machine doesn't have the slot.
+    fn static_index(&mut self, id: u32) -> Result<usize, VmError> {
+        if self.statics.len() <= id as usize {
+            return Err(self.fault(VmErrorKind::InvalidStatic(id)))
+        }
+
+        Ok(id as usize)
+    }
+
+    /// Fail if the op at `addr` refers to a symbol whose link is still pending.
+    fn check_linked(&self, addr: Addr, op: &BOp) -> Result<(), VmError> {
+        match op {
+            &BOp::Call(_) | &BOp::TailCall(_) | &BOp::LoadConst(_) |
+            &BOp::GetStatic(_) | &BOp::SetStatic(_) => (),
+            _ => return Ok(()),
+        }
+
+        let site = addr + op.addr_field_offset(0, self.encoding);
+        match self.pending.iter().find(|pending_link| pending_link.link.site == site) {
+            Some(pending_link) => Err(self.fault(VmErrorKind::UnresolvedSymbol(pending_link.link.target.path().clone()))),
+            None => Ok(()),
+        }
+    }
+
+    /// Find the innermost handler covering `addr`.
+    fn handler_for(&self, addr: Addr) -> Option<Handler> {
+        self.handlers.iter()
+            .filter(|handler| handler.covers(addr))
+            .min_by_key(|handler| handler.end - handler.start)
+            .cloned()
+    }
+
+    /// Throw `value` from the op at `addr`. Pops frames until one has a handler covering the
+    /// call it's in, resets the operand stack to that frame's and pushes the value for the
+    /// handler. Returns the address of the handler. Frames called by the host aren't unwound
+    /// past; the exception is returned to the host instead.
+    fn unwind(&mut self, value: Value, addr: Addr) -> Result<Addr, VmError> {
+        // Find the handler before popping any frames so that the backtrace of an uncaught
+        // exception shows where it was thrown
+        let mut depth = self.call_stack.len();
+        let mut site = addr;
+        let mut handler = None;
+
+        while depth > 0 {
+            handler = self.handler_for(site);
+            let return_addr = self.call_stack[depth - 1].return_addr;
+            if handler.is_some() || depth == 1 || return_addr == HOST_RETURN_ADDR {
+                break
+            }
+
+            depth -= 1;
+            // The return address is just past the call op in the caller
+            site = return_addr - 1;
+        }
+
+        match handler {
+            Some(handler) => {
+                self.call_stack.truncate(depth);
+                let stack_base = self.get_stack_top().stack_base;
+                self.stack.truncate(stack_base);
+                self.stack.push(value);
+                Ok(handler.target)
+            },
+            None => {
+                let error = self.fault_at(VmErrorKind::UncaughtException(value), addr);
+                self.call_stack.truncate(depth.saturating_sub(1));
+                Err(error)
+            },
         }
     }
 }
 
 impl Execute for Machine {
-    fn execute(&mut self) {
+    fn execute(&mut self) -> Result<(), VmError> {
         use super::bytecode::ops::*;
         use super::bytecode::ops::BOp::*;
 
-        let code = self.code.clone();
-
-        let mut cursor = Cursor::new(&code);
-        cursor.set_position(self.ip);
-
         loop {
-            let op = BOp::from_binary(&mut cursor);
-            let mut next_addr = cursor.position();
+            // Decode straight from the code rather than a copy of it: natives may call back
+            // into the machine, and loading or reloading modules adds to and relinks the code
+            let op_addr = self.ip;
+            let (op, mut next_addr) = {
+                let mut cursor = Cursor::new(&self.code);
+                cursor.set_position(op_addr);
+                (BOp::from_binary(&mut cursor, self.encoding), cursor.position())
+            };
+            let op = match op {
+                Ok(op) => op,
+                Err(error) => return Err(self.fault(VmErrorKind::InvalidBytecode(error))),
+            };
+
+            if !self.pending.is_empty() {
+                try!(self.check_linked(op_addr, &op));
+            }
 
             match op {
                 FnEntry(fn_entry) => {
-                    let mut frame = self.get_stack_top_mut();
-                    frame.slots.resize(fn_entry.num_locals as usize, 0x0 as ValuePointer);
+                    // Unverified code may have more parameters than slots
+                    if fn_entry.num_params as Local > fn_entry.num_locals {
+                        return Err(self.fault(VmErrorKind::InvalidLocal(fn_entry.num_params as Local - 1)))
+                    }
+
+                    let frame = try!(self.current_frame());
+                    frame.slots.resize(fn_entry.num_locals as usize, Value::Null);
+
+                    // Parameters live in the first slots
+                    let num_params = (fn_entry.num_params as usize).min(frame.args.len());
+                    for idx in 0..num_params {
+                        frame.slots[idx] = frame.args[idx];
+                    }
+
+                },
+                CollectRest(collect_rest) => {
+                    let idx = try!(self.local_index(collect_rest.idx));
+                    let rest: Vec<Value> = self.get_stack_top().args.iter().skip(idx).cloned().collect();
+
+                    let rest = self.alloc_unpinned(rest);
+                    self.get_stack_top_mut().slots[idx] = rest;
+                },
+                GetArg(get_arg) => {
+                    let value = {
+                        let ref args = try!(self.current_frame()).args;
+                        match get_arg.idx {
+                            ARG_COUNT => Value::Int(args.len() as i64),
+                            idx => args.get(idx as usize).cloned().unwrap_or(Value::Null),
+                        }
+                    };
+                    self.stack.push(value);
                 },
                 GetLocal(get_local) => {
-                    let value: ValuePointer;
-                    {
-                        let frame = self.get_stack_top();
-                        value = frame.slots[get_local.idx as usize];
-                    }
+                    let idx = try!(self.local_index(get_local.idx));
+                    let value = self.get_stack_top().slots[idx];
                     self.stack.push(value);
                 },
                 SetLocal(set_local) => {
-                    let value = self.stack.pop().unwrap();
-                    let frame = self.get_stack_top_mut();
-                    frame.slots[set_local.idx as usize] = value;
+                    let idx = try!(self.local_index(set_local.idx));
+                    let value = try!(self.pop());
+                    self.get_stack_top_mut().slots[idx] = value;
                 },
                 Call(call) => {
-                    let frame = self.build_frame(next_addr, call.num_args as usize);
+                    let frame = try!(self.build_frame(next_addr, call.num_args as usize));
                     self.call_stack.push(frame);
                     next_addr = call.addr;
                 },
                 Invoke(invoke) => {
-                    let frame = self.build_frame(next_addr, invoke.num_args as usize);
-                    self.call_stack.push(frame);
+                    let num_args = invoke.num_args as usize;
 
-                    // Get the boxed address value off the stack and jump to it
-                    let value = self.stack.pop().unwrap();
-                    let addr: ValueBox<Addr> = unsafe { value.into_box() };
-                    next_addr = *addr;
+                    match try!(self.take_callee(num_args)) {
+                        Value::Native(id) => {
+                            next_addr = try!(self.run_native(id, num_args, op_addr, next_addr, false));
+                        },
+                        callee => {
+                            let addr = try!(self.fn_addr(callee));
+                            let frame = try!(self.build_frame(next_addr, num_args));
+                            self.call_stack.push(frame);
+                            next_addr = addr;
+                        },
+                    }
+                },
+                TailCall(tail_call) => {
+                    // The new frame inherits our return address and takes our place
+                    let return_addr = try!(self.current_frame()).return_addr;
+                    let frame = try!(self.build_frame(return_addr, tail_call.num_args as usize));
+                    *self.get_stack_top_mut() = frame;
+                    next_addr = tail_call.addr;
+                },
+                TailInvoke(tail_invoke) => {
+                    let num_args = tail_invoke.num_args as usize;
+
+                    match try!(self.take_callee(num_args)) {
+                        Value::Native(id) => {
+                            next_addr = try!(self.run_native(id, num_args, op_addr, next_addr, true));
+                        },
+                        callee => {
+                            let addr = try!(self.fn_addr(callee));
+                            let return_addr = try!(self.current_frame()).return_addr;
+                            let frame = try!(self.build_frame(return_addr, num_args));
+                            *self.get_stack_top_mut() = frame;
+                            next_addr = addr;
+                        },
+                    }
+                },
+                CallNative(call_native) => {
+                    next_addr = try!(self.run_native(call_native.id, call_native.num_args as usize, op_addr, next_addr, false));
+                },
+                TailCallNative(call_native) => {
+                    next_addr = try!(self.run_native(call_native.id, call_native.num_args as usize, op_addr, next_addr, true));
                 },
                 PushAddress(push_address) => {
-                    let boxed: ValueBox<Addr> = ValueBox::new(push_address.addr);
-                    self.stack.push(unsafe { boxed.into_pointer() });
+                    self.stack.push(Value::Addr(push_address.addr));
+                },
+                LoadConst(load_const) => {
+                    let value = try!(self.const_value(load_const.id));
+                    self.stack.push(value);
+                },
+                GetStatic(get_static) => {
+                    let idx = try!(self.static_index(get_static.id));
+                    let value = self.statics[idx];
+                    self.stack.push(value);
+                },
+                SetStatic(set_static) => {
+                    let idx = try!(self.static_index(set_static.id));
+                    self.statics[idx] = try!(self.pop());
                 },
-                LoadConst(_) => {
-                    unreachable!();
+                PushNull => {
+                    self.stack.push(Value::Null);
                 },
                 BranchIf(branch_if) => {
-                    let value = self.stack.pop().unwrap();
-                    if value.is_null() {
+                    let value = try!(self.pop());
+                    if !value.is_truthy() {
                         next_addr = branch_if.dest
                     }
                 },
                 BranchIfNot(branch_if_not) => {
-                    let value = self.stack.pop().unwrap();
-                    if !value.is_null() {
+                    let value = try!(self.pop());
+                    if value.is_truthy() {
                         next_addr = branch_if_not.dest
                     }
                 },
+                Jump(jump) => {
+                    next_addr = jump.dest
+                },
+                Switch(switch) => {
+                    let test  = try!(self.pop());
+                    let cases = try!(self.pop_stack_into_vec(switch.targets.len()));
+
+                    next_addr = match cases.iter().position(|case| *case == test) {
+                        Some(idx) => switch.targets[idx],
+                        None      => switch.default,
+                    };
+                },
                 Return => {
-                    let frame = self.call_stack.pop().unwrap();
-                    next_addr = frame.return_addr;
+                    next_addr = try!(self.current_frame()).return_addr;
+                    self.call_stack.pop();
+                },
+                Throw => {
+                    let value = try!(self.pop());
+                    next_addr = try!(self.unwind(value, op_addr));
                 },
                 Pop => {
-                    self.stack.pop().unwrap();
+                    try!(self.pop());
                 },
                 Noop => {},
             };
 
+            if next_addr == HOST_RETURN_ADDR {
+                return Ok(())
+            }
+
             self.ip = next_addr;
-            cursor.set_position(next_addr);
         } // loop
     }
 
 } // impl Execute for Machine
+
+#[cfg(test)]
+mod tests {
+    use asm_compiler::Handler;
+    use super::{Execute, VmErrorKind};
+    use super::super::machine::{Frame, Machine};
+    use super::super::value::Value;
+
+    fn frame(return_addr: u64, stack_base: usize) -> Frame {
+        Frame { return_addr: return_addr, args: vec![], slots: vec![], stack_base: stack_base }
+    }
+
+    #[test]
+    fn unwinds_to_innermost_handler_of_a_caller() {
+        let mut machine = Machine::new();
+        machine.handlers = vec![
+            Handler { start: 10, end: 40, target: 50 },
+            Handler { start: 12, end: 20, target: 60 },
+        ];
+        // The callee was called from the op ending at 16 and has no handler of its own
+        machine.call_stack = vec![frame(0, 1), frame(16, 3)];
+        machine.stack = (1..6).map(Value::Int).collect();
+
+        let value = Value::Int(8);
+        assert_eq!(machine.unwind(value, 100), Ok(60));
+        assert_eq!(machine.call_stack.len(), 1);
+        assert_eq!(machine.stack, vec![Value::Int(1), value]);
+    }
+
+    #[test]
+    fn std_args_reads_the_callers_arguments() {
+        let mut machine = Machine::new();
+        let args = vec![Value::Int(8), Value::Int(16)];
+        machine.call_stack = vec![Frame { return_addr: 0, args: args.clone(), slots: vec![], stack_base: 0 }];
+
+        let count = machine.natives.id_for(&"_.std.args.count".to_owned()).unwrap();
+        machine.call_native(count, 0).unwrap();
+        assert_eq!(machine.stack, vec![Value::Int(2)]);
+
+        let get = machine.natives.id_for(&"_.std.args.get".to_owned()).unwrap();
+        machine.stack = vec![Value::Int(1)];
+        machine.call_native(get, 1).unwrap();
+        assert_eq!(machine.stack, vec![args[1]]);
+
+        machine.stack = vec![Value::Int(2)];
+        machine.call_native(get, 1).unwrap();
+        assert!(machine.stack[0].is_null());
+    }
+
+    #[test]
+    fn builtins_treat_missing_arguments_as_null() {
+        let mut machine = Machine::new();
+
+        for &(path, expected) in [("_.std.println", "Expected string, got null"), ("_.std.args.get", "Expected int, got null")].iter() {
+            match machine.call(path, &[]).map_err(|error| error.kind) {
+                Err(VmErrorKind::UncaughtException(message)) => {
+                    assert_eq!(machine.downcast::<String>(&message).unwrap(), expected)
+                },
+                other => panic!("Expected an exception: {:?}", other),
+            }
+        }
+        assert_eq!(machine.call("_.std.bool", &[]), Ok(Value::Null));
+        // Called by the host, so there's no calling function to get arguments from
+        assert_eq!(machine.call("_.std.args.count", &[]), Ok(Value::Int(0)));
+        assert_eq!(machine.call("_.std.args.get", &[Value::Int(0)]), Ok(Value::Null));
+    }
+
+    #[test]
+    fn reports_more_params_than_locals() {
+        use super::super::bytecode::ops::*;
+
+        let mut machine = Machine::new();
+        machine.code = BOp::compile_ops(vec![
+            BFnEntry { num_params: 2, num_locals: 1, }.into_op(),
+            BOp::Return,
+        ], machine.encoding).unwrap();
+
+        let error = machine.call_value(Value::Addr(0), &[Value::Int(1), Value::Int(2)]).unwrap_err();
+        assert_eq!((error.kind, error.ip), (VmErrorKind::InvalidLocal(1), 0));
+    }
+
+    #[test]
+    fn reports_consts_and_statics_out_of_range() {
+        use super::super::bytecode::ops::*;
+
+        let mut machine = Machine::new();
+        machine.consts = vec![Value::Int(1)];
+        machine.statics = vec![Value::Null];
+        machine.code = BOp::compile_ops(vec![
+            BFnEntry { num_params: 0, num_locals: 0, }.into_op(),
+            BLoadConst { id: 0, }.into_op(),
+            BSetStatic { id: 0, }.into_op(),
+            BGetStatic { id: 0, }.into_op(),
+            BOp::Return,
+            BLoadConst { id: 1, }.into_op(),
+            BGetStatic { id: 1, }.into_op(),
+            BSetStatic { id: 1, }.into_op(),
+        ], machine.encoding).unwrap();
+        // The ops with bad ids come last and all have the same size
+        let size = BLoadConst { id: 1, }.into_op().to_binary(machine.encoding).unwrap().len() as u64;
+        let load_const = machine.code.len() as u64 - 3 * size;
+
+        assert_eq!(machine.call_value(Value::Addr(0), &[]), Ok(Value::Int(1)));
+        for (idx, kind) in vec![VmErrorKind::InvalidConst(1), VmErrorKind::InvalidStatic(1), VmErrorKind::InvalidStatic(1)].into_iter().enumerate() {
+            let addr = load_const + idx as u64 * size;
+            machine.call_stack = vec![frame(0, 0)];
+            machine.stack = vec![Value::Null];
+            machine.ip = addr;
+
+            let error = machine.execute().unwrap_err();
+            assert_eq!((error.kind, error.ip), (kind, addr));
+        }
+        assert_eq!(machine.statics, vec![Value::Int(1)]);
+    }
+
+    #[test]
+    fn gets_arguments_and_their_count() {
+        use super::super::bytecode::ops::*;
+
+        let mut machine = Machine::new();
+        let mut ops = vec![];
+        for &idx in [1, 5, ARG_COUNT].iter() {
+            ops.extend(vec![BFnEntry { num_params: 0, num_locals: 0, }.into_op(), BGetArg { idx: idx, }.into_op(), BOp::Return]);
+        }
+        machine.code = BOp::compile_ops(ops, machine.encoding).unwrap();
+
+        let args = [Value::Int(8), Value::Int(16)];
+        assert_eq!(machine.call_value(Value::Addr(0), &args), Ok(Value::Int(16)));
+        assert_eq!(machine.call_value(Value::Addr(7), &args), Ok(Value::Null));
+        assert_eq!(machine.call_value(Value::Addr(14), &args), Ok(Value::Int(2)));
+    }
+
+    #[test]
+    fn reports_stack_underflow_instead_of_panicking() {
+        use super::super::bytecode::ops::BOp;
+
+        let mut machine = Machine::new();
+        machine.code = BOp::Pop.to_binary(machine.encoding).unwrap();
+        machine.call_stack = vec![frame(0, 0)];
+
+        let error = machine.execute().unwrap_err();
+        assert_eq!((error.kind, error.ip), (VmErrorKind::StackUnderflow, 0));
+
+        machine.call_stack.clear();
+        machine.code = BOp::Return.to_binary(machine.encoding).unwrap();
+        assert_eq!(machine.execute().unwrap_err().kind, VmErrorKind::MissingFrame);
+    }
+
+    #[test]
+    fn reports_uncaught_exceptions() {
+        let mut machine = Machine::new();
+        machine.handlers = vec![Handler { start: 10, end: 20, target: 30 }];
+        machine.call_stack = vec![frame(0, 0), frame(5, 0)];
+
+        let value = Value::Int(8);
+        let error = machine.unwind(value, 100).unwrap_err();
+        assert_eq!(error.kind, VmErrorKind::UncaughtException(value));
+        assert_eq!(error.backtrace.iter().map(|entry| entry.addr).collect::<Vec<_>>(), vec![100, 4, 0]);
+        assert!(machine.call_stack.is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+use asm_compiler::{CompileModule, CompiledModule};
+use asm_compiler::hbc::HbcError;
+use asm_parser::parser::pmodule;
+use super::bytecode::util::Encoding;
+use super::machine::{Machine, ModuleError, ModuleLoad};
+use super::verifier::VerifyError;
+
+use nom::IResult;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+/// Ways loading a module by name can fail.
+#[derive(Debug)]
+pub enum LoadError {
+    /// No resolver root has a file for the module
+    NotFound(String),
+    /// Error reading the module's file
+    Io(io::Error),
+    /// The `.hasm` file at the path couldn't be parsed
+    Parse(PathBuf),
+    /// The `.hbc` file couldn't be read
+    Hbc(HbcError),
+    /// The file for a module declares a different module name
+    NameMismatch { expected: String, found: String },
+    /// A `.hbc` file uses a different operand encoding than the machine
+    EncodingMismatch { module: String, encoding: Encoding },
+    /// The module's bytecode is malformed
+    Verify(String, Vec<VerifyError>),
+    /// The module couldn't be loaded into the machine
+    Module(ModuleError),
+}
+
+impl From<ModuleError> for LoadError {
+    fn from(error: ModuleError) -> LoadError {
+        match error {
+            ModuleError::EncodingMismatch { module, encoding } => {
+                LoadError::EncodingMismatch { module: module, encoding: encoding, }
+            },
+            ModuleError::Verify(module, errors) => LoadError::Verify(module, errors),
+            other => LoadError::Module(other),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> LoadError {
+        LoadError::Io(error)
+    }
+}
+
+impl From<HbcError> for LoadError {
+    fn from(error: HbcError) -> LoadError {
+        LoadError::Hbc(error)
+    }
+}
+
+pub type LoadResult<T> = Result<T, LoadError>;
+
+/// Finds modules by their fully-qualified name (eg. `a.b.c`) and compiles them.
+pub trait ModuleResolver {
+    /// Find and compile the named module using the given operand encoding. Already-compiled
+    /// modules may use a different encoding; the machine rejects those when loading them.
+    fn resolve(&self, name: &str, encoding: Encoding) -> LoadResult<CompiledModule>;
+}
+
+/// Resolves `a.b.c` to `a/b/c.hasm` or else `a/b/c.hbc` under each of its roots in turn.
+pub struct FileResolver {
+    pub roots: Vec<PathBuf>,
+}
+
+impl FileResolver {
+    pub fn new(roots: Vec<PathBuf>) -> FileResolver {
+        FileResolver { roots: roots, }
+    }
+
+    fn relative_path(name: &str, extension: &str) -> PathBuf {
+        let mut path: PathBuf = name.split('.').collect();
+        path.set_extension(extension);
+        path
+    }
+
+    fn compile_source(path: PathBuf, encoding: Encoding) -> LoadResult<CompiledModule> {
+        let mut source = vec![];
+        try!(try!(File::open(&path)).read_to_end(&mut source));
+
+        let mut module = match pmodule(&source) {
+            IResult::Done(_, module) => module,
+            _ => return Err(LoadError::Parse(path)),
+        };
+        module.file = Some(path.to_string_lossy().into_owned());
+
+        Ok(module.compile_with_encoding(encoding))
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, name: &str, encoding: Encoding) -> LoadResult<CompiledModule> {
+        for root in self.roots.iter() {
+            let source_path = root.join(FileResolver::relative_path(name, "hasm"));
+            if source_path.is_file() {
+                return FileResolver::compile_source(source_path, encoding)
+            }
+
+            let compiled_path = root.join(FileResolver::relative_path(name, "hbc"));
+            if compiled_path.is_file() {
+                let mut file = try!(File::open(&compiled_path));
+                return Ok(try!(CompiledModule::read_from(&mut file)))
+            }
+        }
+
+        Err(LoadError::NotFound(name.to_owned()))
+    }
+}
+
+impl Machine {
+    /// Use the given resolver to find modules loaded by `load_path`.
+    pub fn set_resolver(&mut self, resolver: Box<ModuleResolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Find the named module with the machine's resolver and load it, after first loading
+    /// the modules it declares as `extern`. Modules that are already loaded are skipped.
+    /// Modules whose externs form a cycle are loaded with their references to each other
+    /// pending until the last of them is loaded.
+    pub fn load_path(&mut self, name: &str) -> LoadResult<()> {
+        let mut loading = vec![];
+        self.load_path_from(name, &mut loading)
+    }
+
+    /// Load the named module, with `loading` holding the modules whose externs are being loaded.
+    fn load_path_from(&mut self, name: &str, loading: &mut Vec<String>) -> LoadResult<()> {
+        if self.modules.iter().any(|module| module == name) {
+            return Ok(())
+        }
+        if loading.iter().any(|module| module == name) {
+            return Ok(())
+        }
+
+        let compiled = try!(self.resolve_module(name));
+        try!(self.load_externs(&compiled, loading));
+
+        self.load_verified_module(&compiled).map_err(LoadError::from)
+    }
+
+    /// Find the named module again and replace the loaded version of it (see
+    /// `Machine::reload_module`). Externs it now declares that aren't loaded yet are loaded
+    /// first.
+    pub fn reload_path(&mut self, name: &str) -> LoadResult<()> {
+        let compiled = try!(self.resolve_module(name));
+        try!(self.load_externs(&compiled, &mut vec![]));
+
+        self.reload_module(&compiled).map_err(LoadError::from)
+    }
+
+    fn resolve_module(&self, name: &str) -> LoadResult<CompiledModule> {
+        let compiled = try!(self.resolver.resolve(name, self.encoding));
+
+        if compiled.name != name {
+            return Err(LoadError::NameMismatch {
+                expected: name.to_owned(),
+                found: compiled.name,
+            })
+        }
+        if compiled.encoding != self.encoding {
+            return Err(LoadError::EncodingMismatch {
+                module: compiled.name,
+                encoding: compiled.encoding,
+            })
+        }
+
+        Ok(compiled)
+    }
+
+    fn load_externs(&mut self, compiled: &CompiledModule, loading: &mut Vec<String>) -> LoadResult<()> {
+        loading.push(compiled.name.clone());
+        for extern_name in compiled.externs.iter() {
+            try!(self.load_path_from(extern_name, loading));
+        }
+        loading.pop();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileResolver, LoadError, LoadResult, ModuleResolver};
+    use asm_compiler::{CompileModule, CompiledModule};
+    use asm_parser::parser::pmodule;
+    use vm::bytecode::util::Encoding;
+    use vm::machine::Machine;
+
+    use nom::IResult;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    /// Resolves modules from sources held in memory and notes which it was asked for.
+    struct SourceResolver {
+        sources: HashMap<String, String>,
+        resolved: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl ModuleResolver for SourceResolver {
+        fn resolve(&self, name: &str, encoding: Encoding) -> LoadResult<CompiledModule> {
+            self.resolved.borrow_mut().push(name.to_owned());
+
+            match self.sources.get(name) {
+                Some(source) => match pmodule(source.as_bytes()) {
+                    IResult::Done(_, module) => Ok(module.compile_with_encoding(encoding)),
+                    _ => Err(LoadError::Parse(PathBuf::from(name))),
+                },
+                None => Err(LoadError::NotFound(name.to_owned())),
+            }
+        }
+    }
+
+    fn machine_with_sources(sources: Vec<(&str, &str)>) -> (Machine, Rc<RefCell<Vec<String>>>) {
+        let resolved = Rc::new(RefCell::new(vec![]));
+        let resolver = SourceResolver {
+            sources: sources.into_iter().map(|(name, source)| (name.to_owned(), source.to_owned())).collect(),
+            resolved: resolved.clone(),
+        };
+
+        let mut machine = Machine::new();
+        machine.set_resolver(Box::new(resolver));
+        (machine, resolved)
+    }
+
+    #[test]
+    fn loads_externs_first_and_only_once() {
+        let (mut machine, resolved) = machine_with_sources(vec![
+            ("a", "mod a\nextern b\nextern c\n"),
+            ("b", "mod b\nextern c\n"),
+            ("c", "mod c\n"),
+        ]);
+
+        assert!(machine.load_path("a").is_ok());
+        assert!(machine.load_path("b").is_ok());
+        assert_eq!(machine.modules, vec!["c", "b", "a"]);
+        assert_eq!(*resolved.borrow(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn loads_cyclic_externs() {
+        let (mut machine, _) = machine_with_sources(vec![
+            ("a", "mod a\nextern b\nexport defn ping() {\n  return call b.pong()\n}\n"),
+            ("b", "mod b\nextern a\nexport defn pong() {\n  return call a.ping()\n}\n"),
+        ]);
+
+        assert!(machine.load_path("a").is_ok());
+        assert_eq!(machine.modules, vec!["b", "a"]);
+        assert!(machine.link().is_ok());
+    }
+
+    #[test]
+    fn reports_missing_modules() {
+        let (mut machine, _) = machine_with_sources(vec![
+            ("c", "mod c\nextern d\n"),
+            ("e", "mod f\n"),
+        ]);
+
+        match machine.load_path("c") {
+            Err(LoadError::NotFound(name)) => assert_eq!(name, "d"),
+            other => panic!("Expected a missing module: {:?}", other),
+        }
+        match machine.load_path("e") {
+            Err(LoadError::NameMismatch { expected, found }) => assert_eq!((expected, found), ("e".to_owned(), "f".to_owned())),
+            other => panic!("Expected a name mismatch: {:?}", other),
+        }
+        assert!(machine.modules.is_empty());
+    }
+
+    #[test]
+    fn resolves_files_under_roots() {
+        let root = ::std::env::temp_dir().join(format!("hivm2-loader-{}", ::std::process::id()));
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a").join("b.hasm"), "mod a.b\nextern c\n").unwrap();
+
+        let compiled = match pmodule(b"mod c\n") {
+            IResult::Done(_, module) => module.compile(),
+            other => panic!("Failed to parse module: {:?}", other),
+        };
+        let mut file = fs::File::create(root.join("c.hbc")).unwrap();
+        compiled.write_to(&mut file).unwrap();
+
+        let mut machine = Machine::new();
+        machine.set_resolver(Box::new(FileResolver::new(vec![PathBuf::from("/nonexistent"), root.clone()])));
+        let result = machine.load_path("a.b");
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(machine.modules, vec!["c", "a.b"]);
+        assert_eq!(machine.debug[1].file, Some(root.join("a").join("b.hasm").to_string_lossy().into_owned()));
+    }
+}
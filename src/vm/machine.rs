@@ -2,42 +2,24 @@ use asm_compiler::{
     CompiledConst,
     CompiledModule,
     CompiledRelocationTarget,
+    DebugInfo,
+    Handler,
+    LineEntry,
 };
+use super::bytecode::ops::{BCallNative, BOp, BTailCallNative, IntoOpConvertable};
 use super::bytecode::types::Addr;
-use super::bytecode::util::NativeEndianWriteExt;
+use super::bytecode::util::{Encoding, ReadTypesExt, WriteTypesExt};
+use super::loader::{FileResolver, ModuleResolver};
+use super::value::{GcConfig, Heap, Trace, TypeError, Value};
+use super::verifier::{verify, VerifyError};
 
+use std::any::Any;
 use std::collections::HashMap;
+use std::collections::hash_map::Iter;
 use std::fmt;
 use std::io::Cursor;
-use std::any::Any;
 use std::mem;
 
-pub type ValueBox<T> = Box<T>;
-
-/// Untyped pointer to a value
-pub type ValuePointer = *mut usize;
-
-/// Convert a thing into a typed `ValueBox`.
-pub trait IntoBox {
-    unsafe fn into_box<T: Any + Sized>(self) -> ValueBox<T>;
-}
-impl IntoBox for ValuePointer {
-    /// Take an untyped raw pointer and convert it into a box with a given expected type.
-    unsafe fn into_box<T: Any + Sized>(self) -> ValueBox<T> {
-        Box::from_raw(self as *mut T)
-    }
-}
-
-pub trait IntoPointer {
-    unsafe fn into_pointer(self) -> ValuePointer;
-}
-impl<T: Any> IntoPointer for ValueBox<T> {
-    /// Get the untyped raw pointer for a given typed, boxed value.
-    unsafe fn into_pointer(self) -> ValuePointer {
-        mem::transmute(self)
-    }
-}
-
 use std::rc::Rc;
 
 /// Primitive functions must be wrapped in `Box` since the size of `Fn` is not known at
@@ -49,7 +31,7 @@ pub type BoxedPrimitiveFn = Rc<Fn(&mut Machine, &Frame)>;
 pub struct PrimitiveFn(BoxedPrimitiveFn);
 
 impl PrimitiveFn {
-    fn call(&self, machine: &mut Machine, frame: &Frame) {
+    pub fn call(&self, machine: &mut Machine, frame: &Frame) {
         let ref f = self.0;
 
         f(machine, frame)
@@ -66,10 +48,10 @@ pub type TableKey = String;
 
 #[derive(Clone, Debug)]
 pub enum TableValue {
-    /// Pointer to the constant value in memory
-    Const(ValuePointer),
-    /// Pointer to the static value in memory
-    Static(ValuePointer),
+    /// Value of the constant
+    Const(Value),
+    /// Slot of the static in the machine's `statics`
+    Static(u32),
     /// Address in the machine's code for the function
     Defn(Addr),
     /// Primitive function
@@ -77,13 +59,6 @@ pub enum TableValue {
 }
 
 impl TableValue {
-    fn as_addr(&self) -> u64 {
-        match *self {
-            TableValue::Defn(ptr) => ptr,
-            _ => panic!("Cannot convert {:?} to Addr", self)
-        }
-    }
-
     pub fn with_fn(f: BoxedPrimitiveFn) -> Self {
         TableValue::Primitive(PrimitiveFn(f))
     }
@@ -94,12 +69,15 @@ impl TableValue {
 #[derive(Clone)]
 pub struct SymbolTable {
     table: HashMap<TableKey, TableValue>,
+    /// Modules that own the symbols private to them
+    owners: HashMap<TableKey, String>,
 }
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable {
             table: HashMap::new(),
+            owners: HashMap::new(),
         }
     }
 
@@ -107,6 +85,11 @@ impl SymbolTable {
         self.table.contains_key(symbol)
     }
 
+    /// Look up a symbol without panicking if it isn't defined.
+    pub fn get_symbol(&self, symbol: &TableKey) -> Option<&TableValue> {
+        self.table.get(symbol)
+    }
+
     pub fn lookup_symbol(&self, symbol: &TableKey) -> &TableValue {
         let value = self.table.get(symbol);
 
@@ -118,6 +101,75 @@ impl SymbolTable {
 
     pub fn set_symbol(&mut self, symbol: &TableKey, value: TableValue) {
         self.table.insert(symbol.clone(), value);
+        self.owners.remove(symbol);
+    }
+
+    /// Set a symbol that only code in the `owner` module may refer to.
+    pub fn set_private_symbol(&mut self, symbol: &TableKey, value: TableValue, owner: &str) {
+        self.table.insert(symbol.clone(), value);
+        self.owners.insert(symbol.clone(), owner.to_owned());
+    }
+
+    pub fn remove_symbol(&mut self, symbol: &TableKey) {
+        self.table.remove(symbol);
+        self.owners.remove(symbol);
+    }
+
+    /// Whether code in the given module may refer to the symbol.
+    pub fn is_visible_from(&self, symbol: &TableKey, module: &str) -> bool {
+        match self.owners.get(symbol) {
+            Some(owner) => owner == module,
+            None => true,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<TableKey, TableValue> {
+        self.table.iter()
+    }
+}
+
+/// Gives every primitive function that code can call a stable numeric id; `CallNative` ops
+/// refer to primitives by these ids. Ids are handed out in the order the functions are
+/// registered.
+#[derive(Clone)]
+pub struct NativeRegistry {
+    fns: Vec<(TableKey, PrimitiveFn)>,
+    ids: HashMap<TableKey, u32>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+        NativeRegistry {
+            fns: vec![],
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Register the primitive under the given path and return its id. Registering a path again
+    /// replaces its primitive but keeps its id, so code already calling it calls the new one.
+    pub fn register(&mut self, path: &TableKey, f: &PrimitiveFn) -> u32 {
+        if let Some(&id) = self.ids.get(path) {
+            self.fns[id as usize].1 = f.clone();
+            return id
+        }
+
+        let id = self.fns.len() as u32;
+        self.fns.push((path.clone(), f.clone()));
+        self.ids.insert(path.clone(), id);
+        id
+    }
+
+    pub fn id_for(&self, path: &TableKey) -> Option<u32> {
+        self.ids.get(path).cloned()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&PrimitiveFn> {
+        self.fns.get(id as usize).map(|&(_, ref f)| f)
+    }
+
+    /// Path the primitive with the given id was registered under.
+    pub fn path_for(&self, id: u32) -> Option<&TableKey> {
+        self.fns.get(id as usize).map(|&(ref path, _)| path)
     }
 }
 
@@ -131,16 +183,149 @@ pub struct Machine {
     /// Instruction pointer (address of the instruction to be/being executed)
     pub ip: Addr,
 
-    pub stack: Vec<ValuePointer>,
+    pub stack: Vec<Value>,
+
+    /// Objects the values on the stack, in frames, consts and statics refer to
+    pub heap: Heap,
+
+    /// When to collect garbage automatically; `None` only collects when `gc` is called
+    pub gc_config: Option<GcConfig>,
+
+    /// Number of objects the heap may hold before the next automatic collection
+    pub gc_threshold: usize,
+
+    /// Values kept alive while natives (or the host) hold them outside the stack and frames
+    pub pinned: Vec<Value>,
 
     pub symbol_table: SymbolTable,
+
+    /// Debug information of every loaded module, rebased to addresses in `code`
+    pub debug: Vec<DebugInfo>,
+
+    /// Encoding of the operands in `code`; all loaded modules must use it
+    pub encoding: Encoding,
+
+    /// Primitive functions called by `CallNative` ops
+    pub natives: NativeRegistry,
+
+    /// Values loaded by `LoadConst` ops; the loader writes indices into this into the ops
+    pub consts: Vec<Value>,
+
+    /// Entries of `consts` no linked site refers to anymore, reused for new values
+    pub free_consts: Vec<u32>,
+
+    /// Values of the statics of every loaded module, read and written by `GetStatic` and
+    /// `SetStatic` ops; the loader writes indices into this into the ops
+    pub statics: Vec<Value>,
+
+    /// Exception handlers of every loaded module, rebased to addresses in `code`
+    pub handlers: Vec<Handler>,
+
+    /// Exception raised by a native function, thrown once the native returns
+    pub exception: Option<Value>,
+
+    /// Names of the loaded modules in the order they were loaded
+    pub modules: Vec<String>,
+
+    /// Sites in `code` that refer to symbols by path, re-linked when a module is reloaded
+    pub links: Vec<ModuleLink>,
+
+    /// Sites referring to symbols that haven't been defined yet
+    pub pending: Vec<ModuleLink>,
+
+    /// Finds the modules loaded by `load_path`
+    pub resolver: Box<ModuleResolver>,
+}
+
+/// Symbol a `Link` refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkTarget {
+    /// Function whose address is written into the site
+    Function(TableKey),
+    /// Const (or function, as a value) whose index in the const pool is written into the site
+    Const(TableKey),
+    /// Static whose slot in `Machine::statics` is written into the site
+    Static(TableKey),
+}
+
+impl LinkTarget {
+    pub fn path(&self) -> &TableKey {
+        match *self {
+            LinkTarget::Function(ref path) => path,
+            LinkTarget::Const(ref path) => path,
+            LinkTarget::Static(ref path) => path,
+        }
+    }
+
+    /// Whether the site can refer to the symbol: calls need functions, const loads need consts
+    /// or functions and static accesses need statics.
+    pub fn accepts(&self, value: &TableValue) -> bool {
+        match (self, value) {
+            (&LinkTarget::Function(_), &TableValue::Defn(_)) |
+            (&LinkTarget::Function(_), &TableValue::Primitive(_)) |
+            (&LinkTarget::Const(_), &TableValue::Const(_)) |
+            (&LinkTarget::Const(_), &TableValue::Defn(_)) |
+            (&LinkTarget::Const(_), &TableValue::Primitive(_)) |
+            (&LinkTarget::Static(_), &TableValue::Static(_)) => true,
+            _ => false,
+        }
+    }
 }
 
+/// A field in the machine's code that was filled in from the symbol table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    pub site: Addr,
+    pub target: LinkTarget,
+}
+
+/// A link from a module's code to a symbol. Pending ones (see `Machine::pending`) are filled in
+/// once a module defining the symbol is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleLink {
+    /// Module whose code refers to the symbol
+    pub module: String,
+    pub link: Link,
+}
+
+/// A reference that couldn't be linked (see `Machine::link`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError {
+    /// No loaded module or primitive defines the symbol
+    Unresolved { module: String, site: Addr, path: TableKey },
+    /// The symbol is private to another module
+    Private { module: String, site: Addr, path: TableKey },
+    /// The symbol isn't the kind of symbol the site refers to (e.g. a call to a const)
+    Mismatch { module: String, site: Addr, path: TableKey },
+}
+
+/// Ways loading a compiled module into a machine can fail (see `ModuleLoad`). Nothing is
+/// loaded when it does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleError {
+    /// The module's operands are encoded differently than the machine's
+    EncodingMismatch { module: String, encoding: Encoding },
+    /// The machine's code would grow beyond the addresses its encoding can hold
+    CodeTooLarge { module: String, size: u64 },
+    /// The module's bytecode is malformed
+    Verify(String, Vec<VerifyError>),
+    /// A const's constructor is missing, raised an exception or didn't push a value
+    Const { module: String, name: String, message: String },
+}
+
+pub type ModuleResult = Result<(), ModuleError>;
+
+/// Return address of frames pushed by `Machine::call`; returning to it hands control back to
+/// the host.
+pub const HOST_RETURN_ADDR: Addr = ::std::u64::MAX;
+
 /// Frame on the call stack
 pub struct Frame {
     pub return_addr: Addr,
-    pub args: Vec<ValuePointer>,
-    pub slots: Vec<ValuePointer>,
+    pub args: Vec<Value>,
+    pub slots: Vec<Value>,
+    /// Height of the operand stack when the frame was entered
+    pub stack_base: usize,
 }
 
 /// Ways for modules to be loaded into machines.
@@ -148,9 +333,18 @@ pub trait ModuleLoad {
     /// Load a compiled module into a machine. Performs the following operations:
     ///
     /// - Adds module's bytecode to the machine's program data storage
-    /// - Adds module's exported symbols (functions, consts, statics) to machine's symbol table
-    /// - Resolves the modules relocations into concrete addresses/indices
-    fn load_module(&mut self, compiled: &CompiledModule);
+    /// - Adds module's symbols (functions, consts) to machine's symbol table as
+    ///   `module.name`; symbols that aren't exported are private to the module
+    /// - Resolves the modules relocations into concrete addresses/indices; references to
+    ///   symbols that aren't defined yet are left pending until they are (see `Machine::link`)
+    fn load_module(&mut self, compiled: &CompiledModule) -> ModuleResult;
+
+    /// Verify the module's bytecode (see `verifier::verify`) and only load it if it is
+    /// well-formed.
+    fn load_verified_module(&mut self, compiled: &CompiledModule) -> ModuleResult {
+        try!(verify(compiled).map_err(|errors| ModuleError::Verify(compiled.name.clone(), errors)));
+        self.load_module(compiled)
+    }
 }
 
 type ConstConstructor<'a> = (String, &'a PrimitiveFn, Option<String>);
@@ -162,108 +356,529 @@ impl Machine {
             call_stack: vec![],
             ip: 0,
             stack: vec![],
+            heap: Heap::new(),
+            gc_config: None,
+            gc_threshold: 0,
+            pinned: vec![],
             symbol_table: SymbolTable::new(),
+            debug: vec![],
+            encoding: Encoding::default(),
+            natives: NativeRegistry::new(),
+            consts: vec![],
+            free_consts: vec![],
+            statics: vec![],
+            handlers: vec![],
+            exception: None,
+            modules: vec![],
+            links: vec![],
+            pending: vec![],
+            resolver: Box::new(FileResolver::new(vec![])),
         }
     }
 
-    fn load_consts(&mut self, compiled_module: &CompiledModule) {
-        let ref consts = compiled_module.consts;
-        let ref module_name = compiled_module.name;
+    /// Raise an exception from a native function. It's thrown at the native call once the
+    /// native returns.
+    pub fn raise(&mut self, value: Value) {
+        self.exception = Some(value);
+    }
 
-        // Constructors are called on an empty machine instance because it's unsafe to let
-        // them work with ourselves
-        let mut empty = Machine::empty();
+    /// Raise an exception carrying a message (as a string).
+    pub fn raise_error(&mut self, message: String) {
+        let value = self.alloc(message);
+        self.raise(value);
+    }
 
-        // Immutable copy of the symbol table for resolving currently-existing symbols
-        let static_symbol_table = self.symbol_table.clone();
+    /// Move a Rust value onto the heap and get a value referring to it, collecting garbage
+    /// first if it's due. The value is pinned until the native call in progress returns, so
+    /// natives can allocate several values before storing them anywhere; values the host
+    /// allocates stay pinned until it calls `unpin_all`.
+    pub fn alloc<T: Trace + Any>(&mut self, value: T) -> Value {
+        let value = self.alloc_unpinned(value);
+        self.pin(value);
+        value
+    }
 
-        let calls = Machine::resolve_const_constructors(&static_symbol_table, consts.clone());
+    /// Keep the value from being collected (see `alloc`). Only heap objects need pinning.
+    pub fn pin(&mut self, value: Value) {
+        if let Value::Object(_) = value {
+            self.pinned.push(value);
+        }
+    }
 
-        for call in calls {
-            let (const_name, constructor, argument) = call;
+    /// Like `alloc` but without pinning the value; it must be made reachable before anything
+    /// else is allocated.
+    pub fn alloc_unpinned<T: Trace + Any>(&mut self, value: T) -> Value {
+        let due = self.gc_config.is_some() && self.heap.len() >= self.gc_threshold;
+        if due {
+            self.gc();
+        }
 
-            // Build a fully-qualified name
-            let mut name = String::new();
-            name.push_str(&module_name);
-            name.push_str(".");
-            name.push_str(&const_name);
+        Value::Object(self.heap.alloc(value))
+    }
 
-            let boxed_argument = ValueBox::new(argument);
+    /// Unpin the values pinned by the host (see `alloc`). Once collected, objects the host
+    /// still holds values for can't be borrowed anymore: `downcast` fails with a `TypeError`.
+    pub fn unpin_all(&mut self) {
+        self.pinned.clear();
+    }
 
-            let frame = Frame {
-                return_addr: 0,
-                slots: vec![],
-                args: vec![
-                    unsafe { boxed_argument.into_pointer() },
-                ],
-            };
+    /// Use the given settings for automatic collection, or only collect when `gc` is called.
+    pub fn set_gc_config(&mut self, config: Option<GcConfig>) {
+        self.gc_config = config;
+        self.gc_threshold = config.map_or(0, |config| config.initial_threshold);
+    }
 
-            constructor.call(&mut empty, &frame);
+    /// Free every heap object that isn't reachable from the stack, frames, consts, statics,
+    /// the exception being raised or the pinned values. Returns the number of objects freed.
+    pub fn gc(&mut self) -> usize {
+        let roots = self.gc_roots();
+        let freed = self.heap.collect(roots);
 
-            let value = match empty.stack.pop() {
-                Some(v) => v,
-                None => panic!("Const constructor did not push a value for {:?}", name)
-            };
+        if let Some(config) = self.gc_config {
+            self.gc_threshold = config.initial_threshold.max(self.heap.len() * config.growth_factor);
+        }
+        freed
+    }
+
+    fn gc_roots(&self) -> Vec<Value> {
+        let mut roots = self.stack.clone();
+
+        for frame in self.call_stack.iter() {
+            roots.extend_from_slice(&frame.args);
+            roots.extend_from_slice(&frame.slots);
+        }
+        roots.extend_from_slice(&self.consts);
+        roots.extend_from_slice(&self.statics);
+        for (_, value) in self.symbol_table.iter() {
+            if let &TableValue::Const(value) = value {
+                roots.push(value);
+            }
+        }
+        roots.extend(self.exception);
+        roots.extend_from_slice(&self.pinned);
+
+        roots
+    }
+
+    /// Borrow the object a value refers to if it's a `T`.
+    pub fn downcast<T: Trace + Any>(&self, value: &Value) -> Result<&T, TypeError> {
+        self.heap.downcast(value)
+    }
+
+    /// Find the line table entry for the code at `addr` along with the debug information of
+    /// the module it belongs to (for the file name).
+    pub fn line_for(&self, addr: Addr) -> Option<(&DebugInfo, &LineEntry)> {
+        for debug in self.debug.iter() {
+            if let Some(entry) = debug.line_for(addr) {
+                return Some((debug, entry))
+            }
+        }
+
+        None
+    }
+
+    /// Find the local slot names of the function whose entry is at `addr`.
+    pub fn locals_for(&self, addr: Addr) -> Option<&Vec<String>> {
+        self.debug.iter().filter_map(|debug| debug.locals_for(addr)).next()
+    }
+
+    /// Rewrite the call whose address field is at `site` into a native call of the primitive
+    /// at `path`. Native calls are the same size as calls, so this is done in place. Fails
+    /// with `LinkError::Mismatch` if the site isn't the address of a call.
+    fn rewrite_native_call(&mut self, module: &str, site: Addr, path: &TableKey, primitive: &PrimitiveFn) -> Result<(), LinkError> {
+        let mismatch = || LinkError::Mismatch { module: module.to_owned(), site: site, path: path.clone(), };
+
+        // The address field directly follows the opcode
+        let op_addr = site - 1;
+        let op = {
+            let mut cursor = Cursor::new(&self.code);
+            cursor.set_position(op_addr);
+            BOp::from_binary(&mut cursor, self.encoding)
+        };
+
+        let native_op = match op {
+            Ok(BOp::Call(call)) => {
+                BCallNative { id: self.natives.register(path, primitive), num_args: call.num_args, }.into_op()
+            },
+            Ok(BOp::TailCall(call)) => {
+                BTailCallNative { id: self.natives.register(path, primitive), num_args: call.num_args, }.into_op()
+            },
+            _ => return Err(mismatch()),
+        };
+
+        let bytes = try!(native_op.to_binary(self.encoding).map_err(|_| mismatch()));
+        let end = op_addr as usize + bytes.len();
+        self.code[op_addr as usize..end].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Write an address into the code. Addresses always fit since `load_module` doesn't let
+    /// the code grow beyond `Encoding::max_addr`.
+    fn write_addr_at(&mut self, site: Addr, addr: Addr) {
+        let encoding = self.encoding;
+        let mut writer = Cursor::new(&mut self.code[..]);
+        writer.set_position(site);
+        writer.write_addr(addr, encoding).expect("Address fits in the machine's encoding");
+    }
+
+    /// Write an id into the code; every `u32` fits in an id field.
+    fn write_id_at(&mut self, site: Addr, id: u32) {
+        let encoding = self.encoding;
+        let mut writer = Cursor::new(&mut self.code[..]);
+        writer.set_position(site);
+        writer.write_id(id, encoding).expect("Id fits in the machine's encoding");
+    }
+
+    /// Fill in the link's site if its symbol is defined, visible to the module and the kind of
+    /// symbol the site refers to, otherwise leave it pending.
+    fn link_or_defer(&mut self, module: &str, link: Link) {
+        let linkable = {
+            let path = link.target.path();
+            match self.symbol_table.get_symbol(path) {
+                Some(value) => self.symbol_table.is_visible_from(path, module) && link.target.accepts(value),
+                None => false,
+            }
+        };
+
+        if !linkable {
+            self.pending.push(ModuleLink { module: module.to_owned(), link: link, });
+            return
+        }
+
+        // Calls to primitives become native calls rather than links; other references to a
+        // primitive's address stay pending and `link` reports them as mismatches
+        if let LinkTarget::Function(ref path) = link.target {
+            if let TableValue::Primitive(primitive) = self.symbol_table.lookup_symbol(path).clone() {
+                if self.rewrite_native_call(module, link.site, path, &primitive).is_err() {
+                    self.pending.push(ModuleLink { module: module.to_owned(), link: link.clone(), });
+                }
+                return
+            }
+        }
+
+        self.write_link(&link);
+        self.links.push(ModuleLink { module: module.to_owned(), link: link, });
+    }
+
+    /// Fill in the pending links whose symbols have since been defined.
+    pub fn resolve_pending(&mut self) {
+        let pending = mem::replace(&mut self.pending, vec![]);
+
+        for pending_link in pending {
+            self.link_or_defer(&pending_link.module, pending_link.link);
+        }
+    }
+
+    /// Fill in the pending links that can be and report every one that still can't. Code
+    /// that runs into a pending link without this fails with `VmErrorKind::UnresolvedSymbol`.
+    pub fn link(&mut self) -> Result<(), Vec<LinkError>> {
+        self.resolve_pending();
+
+        if self.pending.is_empty() {
+            return Ok(())
+        }
+
+        Err(self.pending.iter().map(|pending_link| {
+            let module = pending_link.module.clone();
+            let site = pending_link.link.site;
+            let path = pending_link.link.target.path().clone();
+
+            if !self.symbol_table.has_symbol(&path) {
+                LinkError::Unresolved { module: module, site: site, path: path, }
+            } else if !self.symbol_table.is_visible_from(&path, &module) {
+                LinkError::Private { module: module, site: site, path: path, }
+            } else {
+                LinkError::Mismatch { module: module, site: site, path: path, }
+            }
+        }).collect())
+    }
+
+    /// Write the current value of the symbol the link refers to into its site. The link's
+    /// target must accept the symbol (see `link_or_defer`).
+    fn write_link(&mut self, link: &Link) {
+        let value = self.symbol_table.lookup_symbol(link.target.path()).clone();
+
+        match (&link.target, value) {
+            (&LinkTarget::Function(_), TableValue::Defn(addr)) => {
+                self.write_addr_at(link.site, addr);
+            },
+            (&LinkTarget::Const(_), TableValue::Const(value)) => {
+                let id = self.const_id(value);
+                self.write_id_at(link.site, id);
+            },
+            (&LinkTarget::Const(_), TableValue::Defn(addr)) => {
+                let id = self.const_id(Value::Addr(addr));
+                self.write_id_at(link.site, id);
+            },
+            (&LinkTarget::Const(ref path), TableValue::Primitive(ref primitive)) => {
+                let value = Value::Native(self.natives.register(path, primitive));
+                let id = self.const_id(value);
+                self.write_id_at(link.site, id);
+            },
+            (&LinkTarget::Static(_), TableValue::Static(slot)) => {
+                self.write_id_at(link.site, slot);
+            },
+            (target, value) => unreachable!("{:?} can't refer to {:?}", target, value),
+        }
+    }
 
-            println!("Adding const: {:?}", name);
+    /// Rewrite every linked site with the current values of the symbols they refer to. Sites
+    /// whose symbols are no longer defined (or visible) become pending again.
+    pub fn relink(&mut self) {
+        let links = mem::replace(&mut self.links, vec![]);
+
+        for module_link in links {
+            self.link_or_defer(&module_link.module, module_link.link);
+        }
+    }
 
-            self.symbol_table.set_symbol(&name, TableValue::Const(value));
+    /// Replace a loaded module with a new version of it. The new code is added alongside the
+    /// old, the module's symbols are repointed at it (symbols the new version doesn't define
+    /// are removed), and every site referring to a symbol by path is re-linked. Frames still
+    /// running the old code carry on running the old code, so its sites are left as they are
+    /// and no longer reported by `link`. Const pool entries only the old code refers to are
+    /// freed once no code is running.
+    pub fn reload_module(&mut self, compiled: &CompiledModule) -> ModuleResult {
+        let prefix = compiled.name.clone() + ".";
+        let old_paths: Vec<TableKey> = self.symbol_table.iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.starts_with(&prefix) && !path[prefix.len()..].contains('.'))
+            .cloned()
+            .collect();
+        let base_addr = self.code.len() as Addr;
+
+        try!(self.load_verified_module(compiled));
+
+        // Sites in the old code are no longer linked, nor waiting to be
+        let is_current = |module_link: &ModuleLink| module_link.module != compiled.name || module_link.link.site >= base_addr;
+        self.links.retain(&is_current);
+        self.pending.retain(&is_current);
+
+        for path in old_paths {
+            if !compiled.defines(&path[prefix.len()..]) {
+                self.symbol_table.remove_symbol(&path);
+            }
+        }
+
+        self.relink();
+        self.free_unlinked_consts();
+        Ok(())
+    }
+
+    /// Index of the value in the const pool, adding it (in a freed entry if there is one) if
+    /// it isn't there yet.
+    fn const_id(&mut self, value: Value) -> u32 {
+        let found = {
+            let free_consts = &self.free_consts;
+            self.consts.iter().enumerate()
+                .position(|(idx, c)| *c == value && !free_consts.contains(&(idx as u32)))
+        };
+        if let Some(idx) = found {
+            return idx as u32
+        }
 
+        match self.free_consts.pop() {
+            Some(idx) => {
+                self.consts[idx as usize] = value;
+                idx
+            },
+            None => {
+                self.consts.push(value);
+                (self.consts.len() - 1) as u32
+            },
         }
     }
 
-    fn resolve_const_constructors(symbol_table: &SymbolTable, consts: Vec<CompiledConst>) -> Vec<ConstConstructor> {
+    /// Free the entries of the const pool no linked site refers to, so that the values in
+    /// them can be collected and the entries reused. Frames running code that was replaced
+    /// may still refer to them, so nothing is freed while code is running.
+    fn free_unlinked_consts(&mut self) {
+        if !self.call_stack.is_empty() {
+            return
+        }
+
+        let mut linked = vec![false; self.consts.len()];
+        for module_link in self.links.iter() {
+            if let LinkTarget::Const(_) = module_link.link.target {
+                let mut reader = Cursor::new(&self.code);
+                reader.set_position(module_link.link.site);
+                if let Some(linked) = reader.read_id(self.encoding).ok().and_then(|id| linked.get_mut(id as usize)) {
+                    *linked = true;
+                }
+            }
+        }
+
+        for (idx, linked) in linked.into_iter().enumerate() {
+            let idx = idx as u32;
+            if !linked && !self.free_consts.contains(&idx) {
+                self.consts[idx as usize] = Value::Null;
+                self.free_consts.push(idx);
+            }
+        }
+    }
+
+    /// Call the constructors of the module's consts, returning their names and values. Nothing
+    /// is added to the symbol table so that a failing constructor leaves the machine as it was.
+    fn load_consts(&mut self, compiled_module: &CompiledModule) -> Result<Vec<(String, Value)>, ModuleError> {
+        let ref consts = compiled_module.consts;
+        let ref module_name = compiled_module.name;
+
+        // Constructors are called on an empty machine instance because it's unsafe to let
+        // them work with ourselves; it borrows our heap so that the values they make are ours
+        let mut empty = Machine::empty();
+        mem::swap(&mut empty.heap, &mut self.heap);
+
+        let result = Machine::resolve_const_constructors(&self.symbol_table, module_name, consts.clone())
+            .and_then(|calls| {
+                let mut values = vec![];
+
+                for (const_name, constructor, argument) in calls {
+                    let value = try!(Machine::construct_const(&mut empty, module_name, &const_name, constructor, argument));
+                    values.push((const_name, value));
+                }
+                Ok(values)
+            });
+
+        mem::swap(&mut empty.heap, &mut self.heap);
+        result
+    }
+
+    /// Call a const's constructor on `machine` with the const's argument.
+    fn construct_const(machine: &mut Machine, module_name: &str, const_name: &str, constructor: &PrimitiveFn, argument: Option<String>) -> Result<Value, ModuleError> {
+        let error = |message: String| {
+            ModuleError::Const { module: module_name.to_owned(), name: const_name.to_owned(), message: message, }
+        };
+
+        let argument = match argument {
+            Some(argument) => machine.alloc(argument),
+            None => Value::Null,
+        };
+
+        let frame = Frame {
+            return_addr: 0,
+            slots: vec![],
+            args: vec![argument],
+            stack_base: 0,
+        };
+
+        constructor.call(machine, &frame);
+
+        if let Some(exception) = machine.exception.take() {
+            let message = machine.downcast::<String>(&exception).map(|message| message.clone());
+            return Err(error(message.unwrap_or(format!("{:?}", exception))))
+        }
+
+        match machine.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(error("Constructor did not push a value".to_owned())),
+        }
+    }
+
+    fn resolve_const_constructors<'a>(symbol_table: &'a SymbolTable, module_name: &str, consts: Vec<CompiledConst>) -> Result<Vec<ConstConstructor<'a>>, ModuleError> {
         let mut constructors = vec![];
 
         for compiled_const in consts {
             let (name, constructor_path, argument) = compiled_const;
 
-            let constructor = match symbol_table.lookup_symbol(&constructor_path) {
-                &TableValue::Primitive(ref primitive_fn) => primitive_fn,
+            let constructor = match symbol_table.get_symbol(&constructor_path) {
+                Some(&TableValue::Primitive(ref primitive_fn)) => primitive_fn,
                 _ => {
-                    panic!("Const constructor not found: {:?}", constructor_path)
+                    return Err(ModuleError::Const {
+                        module: module_name.to_owned(),
+                        name: name,
+                        message: format!("Constructor not found: {}", constructor_path),
+                    })
                 },
             };
 
             constructors.push((name, constructor, argument));
         }
 
-        return constructors
+        Ok(constructors)
     }
 }
 
 impl ModuleLoad for Machine {
-    fn load_module(&mut self, compiled: &CompiledModule) {
+    fn load_module(&mut self, compiled: &CompiledModule) -> ModuleResult {
         use super::super::asm_compiler::CompiledRelocationTarget::*;
 
-        self.load_consts(compiled);
+        if compiled.encoding != self.encoding {
+            return Err(ModuleError::EncodingMismatch {
+                module: compiled.name.clone(),
+                encoding: compiled.encoding,
+            })
+        }
+
+        let size = self.code.len() as u64 + compiled.code.len() as u64;
+        if size > self.encoding.max_addr() {
+            return Err(ModuleError::CodeTooLarge { module: compiled.name.clone(), size: size, })
+        }
+
+        let consts = try!(self.load_consts(compiled));
+        for (name, value) in consts {
+            let path = compiled.name.clone() + "." + &name;
+
+            if compiled.exports.contains(&name) {
+                self.symbol_table.set_symbol(&path, TableValue::Const(value));
+            } else {
+                self.symbol_table.set_private_symbol(&path, TableValue::Const(value), &compiled.name);
+            }
+        }
+
+        if !self.modules.contains(&compiled.name) {
+            self.modules.push(compiled.name.clone());
+        }
 
         let ref relocations = compiled.relocations;
 
         let base_addr = self.code.len() as u64;
         self.code.extend(compiled.code.clone());
+        self.debug.push(compiled.debug.rebase(base_addr));
+        self.handlers.extend(compiled.handlers.iter().map(|handler| handler.rebase(base_addr)));
 
-        let mut writer = Cursor::new(&mut self.code[..]);
+        for &(ref name, addr) in compiled.functions.iter() {
+            let path = compiled.name.clone() + "." + name;
+            let value = TableValue::Defn(base_addr + addr);
+
+            if compiled.exports.contains(name) {
+                self.symbol_table.set_symbol(&path, value);
+            } else {
+                self.symbol_table.set_private_symbol(&path, value, &compiled.name);
+            }
+        }
+
+        // Statics start out null; reloading a module keeps their slots and so their values
+        for name in compiled.statics.iter() {
+            let path = compiled.name.clone() + "." + name;
+            let slot = match self.symbol_table.get_symbol(&path) {
+                Some(&TableValue::Static(slot)) => slot,
+                _ => {
+                    self.statics.push(Value::Null);
+                    (self.statics.len() - 1) as u32
+                },
+            };
+
+            if compiled.exports.contains(name) {
+                self.symbol_table.set_symbol(&path, TableValue::Static(slot));
+            } else {
+                self.symbol_table.set_private_symbol(&path, TableValue::Static(slot), &compiled.name);
+            }
+        }
 
         for relocation in relocations {
             let module_addr = relocation.0;
             let final_addr  = base_addr + module_addr;
-            writer.set_position(final_addr);
 
             let ref target: CompiledRelocationTarget = relocation.1;
 
             match target {
                 &InternalAddress(target_module_addr) => {
                     let target_final_addr = base_addr + target_module_addr;
-                    writer.write_hu64(target_final_addr);
+                    self.write_addr_at(final_addr, target_final_addr);
                 },
                 &ExternalFunctionPath(ref path) => {
-                    if self.symbol_table.has_symbol(path) {
-                        let target = self.symbol_table.lookup_symbol(path);
-                        writer.write_hu64(target.as_addr());
-                    } else {
-                        panic!("Symbol not found in symbol table: {:?}", path)
-                    }
+                    let link = Link { site: final_addr, target: LinkTarget::Function(path.clone()), };
+                    self.link_or_defer(&compiled.name, link);
                 },
                 &ConstPath(ref path) => {
                     let is_local = path.starts_with("@") || path.starts_with("$");
@@ -275,11 +890,26 @@ impl ModuleLoad for Machine {
                             path.clone()
                         };
 
-                    let _ = self.symbol_table.lookup_symbol(&path);
+                    let target =
+                        if is_static_path(&path) {
+                            LinkTarget::Static(path)
+                        } else {
+                            LinkTarget::Const(path)
+                        };
 
-                    // TODO: Write the address of the symbol
+                    let link = Link { site: final_addr, target: target, };
+                    self.link_or_defer(&compiled.name, link);
                 }
             }
         }
+
+        // The module may define symbols that modules loaded before it are waiting for
+        self.resolve_pending();
+        Ok(())
     }// fn load_module
 }
+
+/// Whether the path names a static (its last segment starts with `$`).
+pub fn is_static_path(path: &str) -> bool {
+    path.rsplit('.').next().map_or(false, |name| name.starts_with("$"))
+}
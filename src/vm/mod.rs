@@ -1,6 +1,11 @@
+pub mod builtins;
 pub mod bytecode;
+pub mod disassembler;
 pub mod interpreter;
+pub mod loader;
 pub mod machine;
+pub mod value;
+pub mod verifier;
 
 pub use self::machine::{
     Machine,
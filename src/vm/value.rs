@@ -0,0 +1,374 @@
+use super::bytecode::types::Addr;
+
+use std::any::Any;
+use std::fmt;
+use std::mem;
+
+/// Value the machine works with: either an immediate or a handle to an object on the heap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    /// Canonical true value; any value other than null counts as true though
+    True,
+    Int(i64),
+    /// Address of a function in the machine's code
+    Addr(Addr),
+    /// Primitive function, by its native id
+    Native(u32),
+    /// Object on the machine's heap; compares by identity
+    Object(Handle),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+
+    /// Whether a `test` of the value passes: every value except null is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !self.is_null()
+    }
+
+    /// The canonical value for a truth value: `True` or `Null`.
+    pub fn from_bool(value: bool) -> Value {
+        if value { Value::True } else { Value::Null }
+    }
+
+    /// Name of the value's type for error messages. Objects are named by the heap.
+    pub fn type_name(&self, heap: &Heap) -> &'static str {
+        match *self {
+            Value::Null => "null",
+            Value::True => "true",
+            Value::Int(_) => "int",
+            Value::Addr(_) | Value::Native(_) => "fn",
+            Value::Object(handle) => heap.type_name(handle),
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Value {
+        Value::Null
+    }
+}
+
+/// Handle to an object on a `Heap`. Handles are only meaningful to the heap that allocated
+/// them. The generation tells a handle to a collected object apart from a handle to an object
+/// allocated in its place later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle {
+    idx: usize,
+    generation: u64,
+}
+
+/// Type name reported for handles to objects that were collected.
+pub const COLLECTED_TYPE_NAME: &'static str = "collected object";
+
+/// A value didn't have the type an operation expected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected {}, got {}", self.expected, self.found)
+    }
+}
+
+/// Objects that live on the heap. Objects report the values they hold so that the collector
+/// keeps what they refer to alive.
+pub trait Trace {
+    /// Add every value the object refers to.
+    fn trace(&self, values: &mut Vec<Value>);
+
+    /// Name of the type in error messages; defaults to the name of the Rust type.
+    fn type_name() -> &'static str where Self: Sized {
+        short_type_name::<Self>()
+    }
+}
+
+impl Trace for String {
+    fn trace(&self, _: &mut Vec<Value>) {}
+
+    fn type_name() -> &'static str { "string" }
+}
+
+impl Trace for Vec<Value> {
+    fn trace(&self, values: &mut Vec<Value>) {
+        values.extend_from_slice(self)
+    }
+
+    fn type_name() -> &'static str { "list" }
+}
+
+/// Object-safe view of a `Trace` object that can also be downcast.
+trait HeapValue: Trace {
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+}
+
+impl<T: Trace + Any> HeapValue for T {
+    fn as_any(&self) -> &Any { self }
+    fn as_any_mut(&mut self) -> &mut Any { self }
+}
+
+/// Object on the heap along with the name of its Rust type; the type itself is checked through
+/// `Any` when downcasting.
+struct HeapObject {
+    type_name: &'static str,
+    marked: bool,
+    value: Box<HeapValue>,
+}
+
+/// When the machine collects garbage. A collection runs before an allocation once the heap
+/// holds `threshold` objects; the threshold then becomes the number of surviving objects times
+/// `growth_factor`, but never less than `initial_threshold`. A threshold of zero collects on
+/// every allocation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GcConfig {
+    pub initial_threshold: usize,
+    pub growth_factor: usize,
+}
+
+impl GcConfig {
+    /// Collect before every allocation (for testing that everything in use is reachable).
+    pub fn stress() -> GcConfig {
+        GcConfig { initial_threshold: 0, growth_factor: 0, }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> GcConfig {
+        GcConfig { initial_threshold: 1024, growth_factor: 2, }
+    }
+}
+
+/// Storage for the objects values refer to. Objects are owned by the heap, so any number of
+/// values may share one; they're freed by `collect` once no root reaches them.
+pub struct Heap {
+    objects: Vec<Option<HeapObject>>,
+    /// Generation of each object's slot, bumped whenever the object in it is freed
+    generations: Vec<u64>,
+    /// Indices of freed objects, reused by later allocations
+    free: Vec<usize>,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap { objects: vec![], generations: vec![], free: vec![], }
+    }
+
+    /// Move a value onto the heap.
+    pub fn alloc<T: Trace + Any>(&mut self, value: T) -> Handle {
+        let object = HeapObject {
+            type_name: T::type_name(),
+            marked: false,
+            value: Box::new(value),
+        };
+
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = Some(object);
+                idx
+            },
+            None => {
+                self.objects.push(Some(object));
+                self.generations.push(0);
+                self.objects.len() - 1
+            },
+        };
+
+        Handle { idx: idx, generation: self.generations[idx], }
+    }
+
+    /// The object behind the handle, or `None` if it was collected.
+    fn object(&self, handle: Handle) -> Option<&HeapObject> {
+        if self.generations.get(handle.idx) != Some(&handle.generation) {
+            return None
+        }
+
+        self.objects[handle.idx].as_ref()
+    }
+
+    fn object_mut(&mut self, handle: Handle) -> Option<&mut HeapObject> {
+        if self.generations.get(handle.idx) != Some(&handle.generation) {
+            return None
+        }
+
+        self.objects[handle.idx].as_mut()
+    }
+
+    /// Borrow the object behind the handle if it's a `T`.
+    pub fn get<T: Trace + Any>(&self, handle: Handle) -> Result<&T, TypeError> {
+        let object = try!(self.object(handle).ok_or_else(collected::<T>));
+
+        object.value.as_any().downcast_ref::<T>().ok_or_else(|| TypeError {
+            expected: T::type_name(),
+            found: object.type_name,
+        })
+    }
+
+    /// Mutably borrow the object behind the handle if it's a `T`.
+    pub fn get_mut<T: Trace + Any>(&mut self, handle: Handle) -> Result<&mut T, TypeError> {
+        let object = try!(self.object_mut(handle).ok_or_else(collected::<T>));
+        let found = object.type_name;
+
+        object.value.as_any_mut().downcast_mut::<T>().ok_or_else(|| TypeError {
+            expected: T::type_name(),
+            found: found,
+        })
+    }
+
+    /// Borrow the object a value refers to if it's a `T`.
+    pub fn downcast<T: Trace + Any>(&self, value: &Value) -> Result<&T, TypeError> {
+        match *value {
+            Value::Object(handle) => self.get(handle),
+            _ => Err(TypeError { expected: T::type_name(), found: value.type_name(self), }),
+        }
+    }
+
+    pub fn type_name(&self, handle: Handle) -> &'static str {
+        self.object(handle).map_or(COLLECTED_TYPE_NAME, |object| object.type_name)
+    }
+
+    pub fn is<T: Any>(&self, handle: Handle) -> bool {
+        self.object(handle).map_or(false, |object| object.value.as_any().is::<T>())
+    }
+
+    /// Number of objects on the heap.
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    /// Free every object that can't be reached from the roots. Returns the number of objects
+    /// freed.
+    pub fn collect(&mut self, roots: Vec<Value>) -> usize {
+        let mut pending = roots;
+
+        while let Some(value) = pending.pop() {
+            if let Value::Object(handle) = value {
+                let object = match self.object_mut(handle) {
+                    Some(object) => object,
+                    None => continue,
+                };
+                if !object.marked {
+                    object.marked = true;
+                    object.value.trace(&mut pending);
+                }
+            }
+        }
+
+        let mut freed = 0;
+        for (idx, slot) in self.objects.iter_mut().enumerate() {
+            let live = match *slot {
+                Some(ref mut object) => mem::replace(&mut object.marked, false),
+                None => continue,
+            };
+
+            if !live {
+                *slot = None;
+                self.generations[idx] += 1;
+                self.free.push(idx);
+                freed += 1;
+            }
+        }
+
+        freed
+    }
+}
+
+/// Error for a handle to a collected object where a `T` was expected.
+fn collected<T: Trace>() -> TypeError {
+    TypeError { expected: T::type_name(), found: COLLECTED_TYPE_NAME, }
+}
+
+/// Short name of a Rust type (eg. `Foo` for `a::b::Foo`); generic types keep their full name.
+fn short_type_name<T>() -> &'static str {
+    let name = ::std::any::type_name::<T>();
+
+    match name.rfind("::") {
+        Some(idx) if !name.contains('<') => &name[idx + 2..],
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Heap, TypeError, Value};
+
+    fn object(value: Value) -> super::Handle {
+        match value {
+            Value::Object(handle) => handle,
+            other => panic!("Expected an object: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checks_types_when_downcasting() {
+        let mut heap = Heap::new();
+        let handle = heap.alloc("a".to_owned());
+        let value = Value::Object(handle);
+
+        assert_eq!(heap.downcast::<String>(&value), Ok(&"a".to_owned()));
+        assert_eq!(heap.downcast::<Vec<Value>>(&value).unwrap_err(), TypeError { expected: "list", found: "string", });
+        assert_eq!(heap.downcast::<String>(&Value::Int(1)), Err(TypeError { expected: "string", found: "int", }));
+        assert!(heap.is::<String>(handle));
+    }
+
+    #[test]
+    fn shares_objects_between_values() {
+        let mut heap = Heap::new();
+        let value = Value::Object(heap.alloc(vec![Value::Null]));
+        let copy = value;
+
+        if let Value::Object(handle) = copy {
+            heap.get_mut::<Vec<Value>>(handle).unwrap().push(Value::Int(2));
+        }
+        assert_eq!(heap.downcast::<Vec<Value>>(&value).unwrap().len(), 2);
+        assert_eq!(value, copy);
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut heap = Heap::new();
+        let kept = Value::Object(heap.alloc("kept".to_owned()));
+        let list = Value::Object(heap.alloc(vec![kept]));
+        heap.alloc("garbage".to_owned());
+
+        // Lists refer to themselves through a cycle
+        let cycle = heap.alloc(vec![]);
+        heap.get_mut::<Vec<Value>>(cycle).unwrap().push(Value::Object(cycle));
+
+        assert_eq!(heap.collect(vec![list, Value::Int(1)]), 2);
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.downcast::<String>(&kept), Ok(&"kept".to_owned()));
+
+        // Freed slots are reused
+        let reused = heap.alloc("new".to_owned());
+        assert!(object(kept) != reused && object(list) != reused);
+        assert_eq!(heap.collect(vec![]), 3);
+        assert_eq!(heap.len(), 0);
+    }
+
+    #[test]
+    fn rejects_handles_to_collected_objects() {
+        let mut heap = Heap::new();
+        let stale = Value::Object(heap.alloc("old".to_owned()));
+        assert_eq!(heap.collect(vec![]), 1);
+
+        // The new object takes the collected one's slot, but not its handles
+        let fresh = Value::Object(heap.alloc("new".to_owned()));
+        let collected = TypeError { expected: "string", found: "collected object", };
+        assert_eq!(heap.downcast::<String>(&stale), Err(collected.clone()));
+        assert_eq!(heap.get_mut::<String>(object(stale)), Err(collected));
+        assert_eq!(stale.type_name(&heap), "collected object");
+        assert!(!heap.is::<String>(object(stale)));
+        assert_eq!(heap.downcast::<String>(&fresh), Ok(&"new".to_owned()));
+
+        // Collecting with a stale root leaves the live objects alone
+        assert_eq!(heap.collect(vec![stale, fresh]), 0);
+        assert_eq!(heap.len(), 1);
+    }
+}
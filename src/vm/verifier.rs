@@ -0,0 +1,544 @@
+use asm_compiler::{
+    CompiledModule,
+    CompiledRelocationTarget,
+};
+use super::bytecode::ops::{BOp, DecodeError};
+use super::bytecode::types::Addr;
+use super::bytecode::util::Encoding;
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// Ways in which a compiled module's bytecode can be malformed. Addresses are relative to the
+/// start of the module's code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyError {
+    /// A function listed in the module doesn't start with a `FnEntry` op
+    MissingFnEntry { addr: Addr },
+    /// A branch lands outside its function or in the middle of an op
+    InvalidBranchTarget { addr: Addr, target: Addr },
+    /// A `GetLocal`, `SetLocal` or `CollectRest` (or a parameter of a `FnEntry`) refers to a
+    /// slot the function doesn't have
+    LocalOutOfRange { addr: Addr, idx: u16, num_locals: u16 },
+    /// An op needs more values than are on the operand stack
+    StackUnderflow { addr: Addr, depth: usize, needed: usize },
+    /// Two paths reach an op with different operand stack depths
+    InconsistentStackDepth { addr: Addr, expected: usize, found: usize },
+    /// A call passes a different number of arguments than the callee has parameters (or fewer,
+    /// if the callee has a rest parameter)
+    ArityMismatch { addr: Addr, expected: u8, found: u8 },
+    /// Execution can run past the end of the function
+    FallsOffEnd { addr: Addr },
+    /// The code can't be decoded into ops
+    InvalidBytecode(DecodeError),
+}
+
+pub type VerifyResult = Result<(), Vec<VerifyError>>;
+
+/// A single decoded op along with its address and the address of the following op.
+struct DecodedOp {
+    addr: Addr,
+    next_addr: Addr,
+    op: BOp,
+}
+
+/// A function's ops; the first op is always its `FnEntry`.
+struct FunctionCode {
+    entry: Addr,
+    end: Addr,
+    num_params: u8,
+    /// Whether the `FnEntry` is followed by a `CollectRest`
+    rest: bool,
+    num_locals: u16,
+    ops: Vec<DecodedOp>,
+}
+
+impl FunctionCode {
+    fn index_of(&self, addr: Addr) -> Option<usize> {
+        self.ops.binary_search_by(|decoded| decoded.addr.cmp(&addr)).ok()
+    }
+}
+
+/// Checks the bytecode of a compiled module before it's loaded into a machine:
+///
+/// - Every function starts with a `FnEntry`
+/// - Branch targets land on op boundaries inside the same function
+/// - `GetLocal`/`SetLocal`/`CollectRest` indices and parameter counts are within the function's
+///   `num_locals`
+/// - The operand stack depth is the same on every path to an op and never underflows
+/// - Calls to functions in the same module pass as many arguments as the callee has parameters
+///   (at least as many, for callees with a rest parameter)
+///
+/// All violations are collected rather than stopping at the first, except for code that can't
+/// be decoded at all.
+pub fn verify(module: &CompiledModule) -> VerifyResult {
+    let mut errors = vec![];
+
+    let encoding = module.encoding;
+    let functions = match split_functions(&module.code, encoding) {
+        Ok(functions) => functions,
+        Err(error) => return Err(vec![VerifyError::InvalidBytecode(error)]),
+    };
+
+    for &(_, addr) in module.functions.iter() {
+        if !functions.iter().any(|f| f.entry == addr) {
+            errors.push(VerifyError::MissingFnEntry { addr: addr })
+        }
+    }
+
+    // Relocated addresses of the sites in the code (the address fields of ops)
+    let mut internal_targets: HashMap<Addr, Addr> = HashMap::new();
+    let mut path_targets: HashMap<Addr, &String> = HashMap::new();
+
+    for &(site, ref target) in module.relocations.iter() {
+        match target {
+            &CompiledRelocationTarget::InternalAddress(addr) => {
+                internal_targets.insert(site, addr);
+            },
+            &CompiledRelocationTarget::ExternalFunctionPath(ref path) => {
+                path_targets.insert(site, path);
+            },
+            _ => (),
+        }
+    }
+
+    // Parameter counts (and whether there's a rest parameter) of the module's functions by entry
+    // address and by name
+    let params_by_addr: HashMap<Addr, (u8, bool)> = functions.iter()
+        .map(|f| (f.entry, (f.num_params, f.rest)))
+        .collect();
+    let mut params_by_path: HashMap<String, (u8, bool)> = HashMap::new();
+    for &(ref name, addr) in module.functions.iter() {
+        if let Some(num_params) = params_by_addr.get(&addr) {
+            params_by_path.insert(name.clone(), *num_params);
+            params_by_path.insert(module.name.clone() + "." + name, *num_params);
+        }
+    }
+
+    let callee_params = |decoded: &DecodedOp| -> Option<(u8, bool)> {
+        let site = decoded.addr + decoded.op.addr_field_offset(0, encoding);
+
+        if let Some(target) = internal_targets.get(&site) {
+            return params_by_addr.get(target).cloned()
+        }
+        if let Some(path) = path_targets.get(&site) {
+            return params_by_path.get(*path).cloned()
+        }
+        None
+    };
+
+    for function in functions.iter() {
+        for decoded in function.ops.iter() {
+            let addr = decoded.addr;
+
+            match decoded.op {
+                BOp::FnEntry(ref entry) if entry.num_params as u16 > entry.num_locals => {
+                    errors.push(VerifyError::LocalOutOfRange {
+                        addr: addr,
+                        idx: entry.num_params as u16 - 1,
+                        num_locals: entry.num_locals,
+                    })
+                },
+                BOp::GetLocal(ref get_local) if get_local.idx >= function.num_locals => {
+                    errors.push(VerifyError::LocalOutOfRange {
+                        addr: addr,
+                        idx: get_local.idx,
+                        num_locals: function.num_locals,
+                    })
+                },
+                BOp::SetLocal(ref set_local) if set_local.idx >= function.num_locals => {
+                    errors.push(VerifyError::LocalOutOfRange {
+                        addr: addr,
+                        idx: set_local.idx,
+                        num_locals: function.num_locals,
+                    })
+                },
+                BOp::CollectRest(ref collect_rest) if collect_rest.idx >= function.num_locals => {
+                    errors.push(VerifyError::LocalOutOfRange {
+                        addr: addr,
+                        idx: collect_rest.idx,
+                        num_locals: function.num_locals,
+                    })
+                },
+                BOp::Call(ref call) => {
+                    check_arity(&mut errors, addr, callee_params(decoded), call.num_args)
+                },
+                BOp::TailCall(ref call) => {
+                    check_arity(&mut errors, addr, callee_params(decoded), call.num_args)
+                },
+                _ => (),
+            }
+        }
+
+        // Handlers are entered with the exception on the stack
+        let mut entries = vec![(0, 0)];
+        for handler in module.handlers.iter() {
+            if handler.target < function.entry || handler.target >= function.end {
+                continue
+            }
+
+            match function.index_of(handler.target) {
+                Some(idx) if idx > 0 => entries.push((idx, 1)),
+                _ => errors.push(VerifyError::InvalidBranchTarget {
+                    addr: handler.start,
+                    target: handler.target,
+                }),
+            }
+        }
+
+        verify_stack_depths(function, encoding, entries, &internal_targets, &mut errors);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_arity(errors: &mut Vec<VerifyError>, addr: Addr, expected: Option<(u8, bool)>, found: u8) {
+    if let Some((expected, rest)) = expected {
+        if found < expected || (!rest && found != expected) {
+            errors.push(VerifyError::ArityMismatch {
+                addr: addr,
+                expected: expected,
+                found: found,
+            })
+        }
+    }
+}
+
+/// Decode the code and split it into functions at each `FnEntry`. Ops before the first
+/// `FnEntry` (module-level code) aren't part of any function.
+fn split_functions(code: &Vec<u8>, encoding: Encoding) -> Result<Vec<FunctionCode>, DecodeError> {
+    let mut functions: Vec<FunctionCode> = vec![];
+    let mut cursor = Cursor::new(code);
+
+    while (cursor.position() as usize) < code.len() {
+        let addr = cursor.position();
+        let op = try!(BOp::from_binary(&mut cursor, encoding));
+        let next_addr = cursor.position();
+
+        if let BOp::FnEntry(ref entry) = op {
+            functions.push(FunctionCode {
+                entry: addr,
+                end: addr,
+                num_params: entry.num_params,
+                rest: false,
+                num_locals: entry.num_locals,
+                ops: vec![],
+            })
+        }
+
+        if let Some(function) = functions.last_mut() {
+            // A rest parameter is collected right after the entry
+            if let BOp::CollectRest(_) = op {
+                if function.ops.len() == 1 {
+                    function.rest = true
+                }
+            }
+
+            function.end = next_addr;
+            function.ops.push(DecodedOp {
+                addr: addr,
+                next_addr: next_addr,
+                op: op,
+            })
+        }
+    }
+
+    Ok(functions)
+}
+
+/// Returns how many values the op pops, how many it pushes, and whether execution can
+/// continue to the following op.
+fn stack_effect(op: &BOp) -> (usize, usize, bool) {
+    match op {
+        &BOp::FnEntry(_)            => (0, 0, true),
+        &BOp::GetArg(_)             => (0, 1, true),
+        &BOp::GetLocal(_)           => (0, 1, true),
+        &BOp::SetLocal(_)           => (1, 0, true),
+        &BOp::CollectRest(_)        => (0, 0, true),
+        &BOp::Call(ref c)           => (c.num_args as usize, 1, true),
+        &BOp::Invoke(ref i)         => (i.num_args as usize + 1, 1, true),
+        &BOp::TailCall(ref c)       => (c.num_args as usize, 0, false),
+        &BOp::TailInvoke(ref i)     => (i.num_args as usize + 1, 0, false),
+        &BOp::CallNative(ref c)     => (c.num_args as usize, 1, true),
+        &BOp::TailCallNative(ref c) => (c.num_args as usize, 0, false),
+        &BOp::PushAddress(_)        => (0, 1, true),
+        &BOp::LoadConst(_)          => (0, 1, true),
+        &BOp::GetStatic(_)          => (0, 1, true),
+        &BOp::SetStatic(_)          => (1, 0, true),
+        &BOp::BranchIf(_)           => (1, 0, true),
+        &BOp::BranchIfNot(_)        => (1, 0, true),
+        &BOp::Jump(_)               => (0, 0, false),
+        &BOp::Switch(ref s)         => (s.targets.len() + 1, 0, false),
+        &BOp::Return                => (0, 0, false),
+        &BOp::Pop                   => (1, 0, true),
+        &BOp::Noop                  => (0, 0, true),
+        &BOp::Throw                 => (1, 0, false),
+        &BOp::PushNull              => (0, 1, true),
+    }
+}
+
+/// Walks every path through the function from the given entries (op indices and stack depths)
+/// tracking the operand stack depth.
+fn verify_stack_depths(function: &FunctionCode, encoding: Encoding, entries: Vec<(usize, usize)>, internal_targets: &HashMap<Addr, Addr>, errors: &mut Vec<VerifyError>) {
+    let mut depths: Vec<Option<usize>> = vec![None; function.ops.len()];
+    let mut worklist: Vec<(usize, usize)> = entries;
+
+    while let Some((idx, depth)) = worklist.pop() {
+        let ref decoded = function.ops[idx];
+
+        match depths[idx] {
+            Some(expected) => {
+                if expected != depth {
+                    errors.push(VerifyError::InconsistentStackDepth {
+                        addr: decoded.addr,
+                        expected: expected,
+                        found: depth,
+                    })
+                }
+                continue
+            },
+            None => depths[idx] = Some(depth),
+        }
+
+        let (pops, pushes, continues) = stack_effect(&decoded.op);
+
+        if pops > depth {
+            errors.push(VerifyError::StackUnderflow {
+                addr: decoded.addr,
+                depth: depth,
+                needed: pops,
+            });
+            continue
+        }
+        let next_depth = depth - pops + pushes;
+
+        // Destinations in the order of the op's address fields
+        let branch_dests = match decoded.op {
+            BOp::BranchIf(ref b)    => vec![b.dest],
+            BOp::BranchIfNot(ref b) => vec![b.dest],
+            BOp::Jump(ref j)        => vec![j.dest],
+            BOp::Switch(ref s)      => {
+                let mut dests = vec![s.default];
+                dests.extend(s.targets.iter().cloned());
+                dests
+            },
+            _                       => vec![],
+        };
+        for (field, dest) in branch_dests.into_iter().enumerate() {
+            let site = decoded.addr + decoded.op.addr_field_offset(field, encoding);
+            let target = internal_targets.get(&site).cloned().unwrap_or(dest);
+
+            // The entry isn't a valid target since it would re-run `FnEntry`
+            match function.index_of(target) {
+                Some(target_idx) if target_idx > 0 => worklist.push((target_idx, next_depth)),
+                _ => {
+                    errors.push(VerifyError::InvalidBranchTarget {
+                        addr: decoded.addr,
+                        target: target,
+                    })
+                },
+            }
+        }
+
+        if continues {
+            if decoded.next_addr >= function.end {
+                errors.push(VerifyError::FallsOffEnd { addr: decoded.addr })
+            } else {
+                worklist.push((idx + 1, next_depth))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, VerifyError};
+    use asm_compiler::{
+        CompileModule,
+        CompiledModule,
+        CompiledRelocationTarget,
+        DebugInfo,
+    };
+    use asm::{BasicBlock, Call, Defn, Mod, Module, Path, Return, Statement, Value};
+    use vm::bytecode::ops::*;
+    use vm::bytecode::util::Encoding;
+
+    fn module_with_code(ops: Vec<BOp>, relocations: Vec<(u64, CompiledRelocationTarget)>) -> CompiledModule {
+        CompiledModule {
+            name: "foo".to_owned(),
+            code: BOp::compile_ops(ops, Encoding::Fixed).unwrap(),
+            functions: vec![("a".to_owned(), 0)],
+            consts: vec![],
+            statics: vec![],
+            externs: vec![],
+            exports: vec![],
+            relocations: relocations,
+            debug: DebugInfo { file: None, lines: vec![], locals: vec![] },
+            encoding: Encoding::Fixed,
+            handlers: vec![],
+        }
+    }
+
+    #[test]
+    fn accepts_compiled_module() {
+        let module = Module::with_stmts(vec![
+            Statement::StatementMod(Mod::new(Path::with_name("foo".to_owned()))),
+            Statement::StatementDefn(Defn::new(
+                "a".to_owned(),
+                vec!["b".to_owned()],
+                BasicBlock::with_stmts(vec![
+                    Statement::StatementReturn(Return::new(Some(Value::Call(Call::new(
+                        Path::with_name("a".to_owned()),
+                        vec!["b".to_owned()]
+                    )))))
+                ])
+            )),
+        ]);
+
+        assert_eq!(verify(&module.compile()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_missing_fn_entry() {
+        let module = module_with_code(vec![BGetLocal { idx: 0, }.into_op(), BOp::Return], vec![]);
+
+        assert_eq!(verify(&module), Err(vec![VerifyError::MissingFnEntry { addr: 0 }]));
+    }
+
+    #[test]
+    fn rejects_out_of_range_locals() {
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BGetLocal { idx: 1, }.into_op(),
+            BOp::Return,
+        ], vec![]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::LocalOutOfRange { addr: 4, idx: 1, num_locals: 1 },
+        ]));
+    }
+
+    #[test]
+    fn rejects_more_params_than_locals() {
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 2, num_locals: 1, }.into_op(),
+            BOp::PushNull,
+            BOp::Return,
+        ], vec![]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::LocalOutOfRange { addr: 0, idx: 1, num_locals: 1 },
+        ]));
+    }
+
+    #[test]
+    fn rejects_branch_into_middle_of_op() {
+        // fn_entry(0) get_local(4) branch_if(7) return(16)
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BBranchIf { dest: 0, }.into_op(),
+            BOp::Return,
+        ], vec![
+            (8, CompiledRelocationTarget::InternalAddress(5)),
+        ]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::InvalidBranchTarget { addr: 7, target: 5 },
+        ]));
+    }
+
+    #[test]
+    fn rejects_inconsistent_stack_depth() {
+        // The branch skips the `get_local`, so the `return` is reached at depths 0 and 1
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BBranchIf { dest: 0, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BOp::Return,
+        ], vec![
+            (8, CompiledRelocationTarget::InternalAddress(19)),
+        ]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::InconsistentStackDepth { addr: 19, expected: 1, found: 0 },
+        ]));
+    }
+
+    #[test]
+    fn rejects_stack_underflow_and_falling_off_end() {
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BSetLocal { idx: 0, }.into_op(),
+            BOp::Return,
+        ], vec![]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::StackUnderflow { addr: 4, depth: 0, needed: 1 },
+        ]));
+
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 1, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+        ], vec![]);
+
+        assert_eq!(verify(&module), Err(vec![VerifyError::FallsOffEnd { addr: 4 }]));
+    }
+
+    #[test]
+    fn rejects_arity_mismatch() {
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 1, num_locals: 1, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BCall { addr: 0, num_args: 2, }.into_op(),
+            BOp::Return,
+        ], vec![
+            (11, CompiledRelocationTarget::ExternalFunctionPath("foo.a".to_owned())),
+        ]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::ArityMismatch { addr: 10, expected: 1, found: 2 },
+        ]));
+    }
+
+    #[test]
+    fn allows_extra_arguments_for_rest_parameters() {
+        let module = module_with_code(vec![
+            BFnEntry { num_params: 1, num_locals: 2, }.into_op(),
+            BCollectRest { idx: 1, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BGetLocal { idx: 0, }.into_op(),
+            BCall { addr: 0, num_args: 2, }.into_op(),
+            BCall { addr: 0, num_args: 0, }.into_op(),
+            BOp::Return,
+        ], vec![
+            (14, CompiledRelocationTarget::ExternalFunctionPath("foo.a".to_owned())),
+            (24, CompiledRelocationTarget::ExternalFunctionPath("foo.a".to_owned())),
+        ]);
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::ArityMismatch { addr: 23, expected: 1, found: 0 },
+        ]));
+    }
+
+    #[test]
+    fn rejects_truncated_code() {
+        use vm::bytecode::ops::DecodeErrorKind;
+
+        let mut module = module_with_code(vec![
+            BFnEntry { num_params: 0, num_locals: 0, }.into_op(),
+            BOp::Return,
+        ], vec![]);
+        module.code.push(BCall { addr: 0, num_args: 0, }.into_op().opcode());
+
+        assert_eq!(verify(&module), Err(vec![
+            VerifyError::InvalidBytecode(DecodeError { addr: 5, kind: DecodeErrorKind::UnexpectedEnd, }),
+        ]));
+    }
+}
@@ -1,6 +1,22 @@
 extern crate hivm2;
+extern crate nom;
 
 use hivm2::asm_compiler::CompileModule;
+use hivm2::asm_parser::parser::pmodule;
+use hivm2::vm::{
+    Machine,
+    ModuleLoad
+};
+use hivm2::vm::disassembler::Disassemble;
+use hivm2::vm::value::Value;
+use nom::IResult;
+
+fn parse_module(source: &str) -> hivm2::asm::Module {
+    match pmodule(source.as_bytes()) {
+        IResult::Done(_, module) => module,
+        other => panic!("Failed to parse module: {:?}", other),
+    }
+}
 
 #[test]
 fn compiles_asm() {
@@ -17,11 +33,6 @@ fn compiles_asm() {
         Return,
         Value
     };
-    use hivm2::vm::{
-        Machine,
-        ModuleLoad
-    };
-
     let function_defn = Defn::new(
         "bar".to_owned(),
         vec!["baz".to_owned()],
@@ -68,5 +79,976 @@ fn compiles_asm() {
     let compiled = module.compile();
 
     let mut machine = Machine::new();
-    machine.load_module(&compiled);
+    machine.load_module(&compiled).unwrap();
+}
+
+#[test]
+fn maps_machine_addresses_to_source_lines() {
+    let mut foo = parse_module("mod foo\ndefn bar(a) {\n  b := a\n  return b\n}\n");
+    foo.file = Some("foo.hasm".to_owned());
+    let compiled_foo = foo.compile();
+
+    let mut baz = parse_module("mod baz\ndefn qux(a) {\n  return a\n}\n");
+    baz.file = Some("baz.hasm".to_owned());
+    let compiled_baz = baz.compile();
+
+    let mut machine = Machine::new();
+    machine.load_module(&compiled_foo).unwrap();
+    machine.load_module(&compiled_baz).unwrap();
+
+    // Second module's addresses are rebased past the end of the first module's code
+    let base_addr = compiled_foo.code.len() as u64;
+    let ref baz_entry = compiled_baz.debug.lines[0];
+
+    let (debug, entry) = machine.line_for(base_addr + baz_entry.start).unwrap();
+    assert_eq!(debug.file, Some("baz.hasm".to_owned()));
+    assert_eq!((entry.line, entry.column), (3, 3));
+    assert_eq!(entry.function, "qux");
+
+    let (debug, entry) = machine.line_for(compiled_foo.debug.lines[1].start).unwrap();
+    assert_eq!(debug.file, Some("foo.hasm".to_owned()));
+    assert_eq!(entry.line, 4);
+
+    assert_eq!(machine.locals_for(base_addr), Some(&vec!["a".to_owned()]));
+}
+
+#[test]
+fn loads_module_read_from_hbc() {
+    use hivm2::asm_compiler::CompiledModule;
+
+    let compiled = parse_module("mod foo\ndefn bar(a) {\n  return a\n}\n").compile();
+
+    let mut bytes = vec![];
+    compiled.write_to(&mut bytes).unwrap();
+    let read = CompiledModule::read_from(&mut &bytes[..]).unwrap();
+    assert_eq!(read, compiled);
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&read).is_ok());
+    assert_eq!(machine.code, compiled.code);
+}
+
+#[test]
+fn loads_compact_module() {
+    use hivm2::vm::bytecode::util::Encoding;
+
+    let foo = parse_module("mod foo\ndefn bar(a) {\n  b := fn() {\n    return\n  }\n  return b\n}\n");
+    let compiled = foo.compile_with_encoding(Encoding::Compact);
+
+    // Load after some other code so that the relocation is rebased
+    let mut machine = Machine::with_encoding(Encoding::Compact);
+    machine.load_module(&parse_module("mod qux\ndefn a() {\n  return\n}\n").compile_with_encoding(Encoding::Compact)).unwrap();
+    let base_addr = machine.code.len() as u64;
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    // The anonymous function is compiled first, so it's at the start of the module's code
+    assert!(machine.disassemble().contains(&format!("push_address      {:#010x}\n", base_addr)));
+}
+
+#[test]
+fn rejects_module_with_other_encoding() {
+    use hivm2::vm::bytecode::util::Encoding;
+    use hivm2::vm::machine::ModuleError;
+
+    let compiled = parse_module("mod foo\ndefn bar() {\n  return\n}\n").compile_with_encoding(Encoding::Compact);
+    let mismatch = Err(ModuleError::EncodingMismatch { module: "foo".to_owned(), encoding: Encoding::Compact, });
+
+    let mut machine = Machine::new();
+    assert_eq!(machine.load_module(&compiled), mismatch);
+    assert_eq!(machine.load_verified_module(&compiled), mismatch);
+    assert!(machine.code.is_empty());
+}
+
+#[test]
+fn rejects_module_whose_const_constructors_fail() {
+    use hivm2::vm::machine::ModuleError;
+
+    let error = |name: &str, message: &str| {
+        Err(ModuleError::Const { module: "foo".to_owned(), name: name.to_owned(), message: message.to_owned(), })
+    };
+    let invalid = parse_module("mod foo\nconst @ok = _.std.int.from_string \"1\"\nconst @x = _.std.int.from_string \"abc\"\ndefn bar() {\n  return\n}\n").compile();
+    let missing = parse_module("mod foo\nconst @y = foo.missing \"a\"\n").compile();
+
+    let mut machine = Machine::new();
+    assert_eq!(machine.load_module(&invalid), error("@x", "Invalid int: \"abc\""));
+    assert_eq!(machine.load_module(&missing), error("@y", "Constructor not found: foo.missing"));
+
+    // Nothing is loaded, not even the consts constructed before the failing one
+    assert!(machine.code.is_empty());
+    assert!(machine.modules.is_empty());
+    assert!(machine.symbol_table.get_symbol(&"foo.@ok".to_owned()).is_none());
+}
+
+#[test]
+fn execute_reports_invalid_bytecode() {
+    use hivm2::vm::bytecode::ops::{DecodeError, DecodeErrorKind};
+    use hivm2::vm::interpreter::{Execute, VmErrorKind};
+
+    let mut machine = Machine::new();
+    machine.code = vec![0xff];
+
+    let error = DecodeError { addr: 0, kind: DecodeErrorKind::InvalidOpcode(0xff), };
+    assert_eq!(machine.execute().map_err(|error| error.kind), Err(VmErrorKind::InvalidBytecode(error)));
+}
+
+#[test]
+fn replaces_natives_registered_again() {
+    use hivm2::vm::machine::Frame;
+    use std::rc::Rc;
+
+    let compiled = parse_module("mod foo\ndefn bar(a) {\n  return call test.value(a)\n}\n").compile();
+
+    let mut machine = Machine::new();
+    let id = machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    machine.load_module(&compiled).unwrap();
+    assert_eq!(machine.call("foo.bar", &[Value::Int(1)]), Ok(Value::Int(1)));
+
+    let new_id = machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, _: &Frame| {
+        m.stack.push(Value::Int(2))
+    }));
+    assert_eq!(new_id, id);
+    assert_eq!(machine.call("foo.bar", &[Value::Int(1)]), Ok(Value::Int(2)));
+    assert_eq!(machine.call("test.value", &[Value::Int(1)]), Ok(Value::Int(2)));
+}
+
+#[test]
+fn calls_primitives_by_native_id() {
+    use hivm2::vm::disassembler::Disassemble;
+    use hivm2::vm::machine::Frame;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let compiled = parse_module("mod foo\ndefn bar(a) {\n  return call test.record(a)\n}\n").compile();
+
+    let calls = Rc::new(Cell::new(0));
+    let mut machine = Machine::new();
+    let recorded = calls.clone();
+    let id = machine.add_native(&"test.record".to_owned(), Rc::new(move |_, frame: &Frame| {
+        recorded.set(recorded.get() + frame.args.len());
+    }));
+    machine.load_module(&compiled).unwrap();
+    assert!(machine.disassemble().contains(&format!("tail_call_native  #{}, 1\n", id)));
+
+    assert_eq!(machine.call("foo.bar", &[Value::Null]), Ok(Value::Null));
+    assert_eq!(calls.get(), 1);
+}
+
+#[test]
+fn switches_on_consts() {
+    use hivm2::vm::machine::{Frame, TableValue};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "mod foo\n\
+                  const @a = test.value \"a\"\n\
+                  const @b = test.value \"b\"\n\
+                  defn pick(x) {\n\
+                  \x20 switch x {\n\
+                  \x20   case @a {\n\
+                  \x20     return call test.first()\n\
+                  \x20   }\n\
+                  \x20   case @b {\n\
+                  \x20     return call test.second()\n\
+                  \x20   }\n\
+                  \x20   default {\n\
+                  \x20     return call test.other()\n\
+                  \x20   }\n\
+                  \x20 }\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let picked: Rc<RefCell<Vec<&'static str>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = Machine::new();
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    for &(path, name) in [("test.first", "first"), ("test.second", "second"), ("test.other", "other")].iter() {
+        let picked = picked.clone();
+        machine.add_native(&path.to_owned(), Rc::new(move |_: &mut Machine, _: &Frame| {
+            picked.borrow_mut().push(name)
+        }));
+    }
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let b = match machine.symbol_table.lookup_symbol(&"foo.@b".to_owned()) {
+        &TableValue::Const(value) => value,
+        other => panic!("Expected a const: {:?}", other),
+    };
+
+    for &arg in [b, Value::Null].iter() {
+        assert!(machine.call("foo.pick", &[arg]).is_ok());
+    }
+
+    assert_eq!(*picked.borrow(), vec!["second", "other"]);
+}
+
+#[test]
+fn catches_exceptions() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "mod foo\n\
+                  defn run(x) {\n\
+                  \x20 try {\n\
+                  \x20   call test.raise(x)\n\
+                  \x20 } catch e {\n\
+                  \x20   call test.caught(e)\n\
+                  \x20 }\n\
+                  \x20 throw x\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+    assert_eq!(compiled.handlers.len(), 1);
+
+    let caught: Rc<RefCell<Vec<Value>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = Machine::new();
+    machine.add_native(&"test.raise".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.raise(frame.args[0])
+    }));
+    let recorded = caught.clone();
+    machine.add_native(&"test.caught".to_owned(), Rc::new(move |_: &mut Machine, frame: &Frame| {
+        recorded.borrow_mut().push(frame.args[0])
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    // The exception raised by the native is caught; the one thrown after the `try` isn't
+    let value = Value::Int(8);
+    assert_eq!(machine.call("foo.run", &[value]).map_err(|error| error.kind), Err(VmErrorKind::UncaughtException(value)));
+    assert_eq!(*caught.borrow(), vec![value]);
+    assert!(machine.call_stack.is_empty());
+}
+
+#[test]
+fn collects_rest_arguments() {
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let compiled = parse_module("mod foo\ndefn bar(a, ...rest) {\n  call test.record(rest)\n  return a\n}\n").compile();
+
+    let recorded: Rc<RefCell<Vec<Vec<Value>>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = Machine::new();
+    let record = recorded.clone();
+    machine.add_native(&"test.record".to_owned(), Rc::new(move |m: &mut Machine, frame: &Frame| {
+        let rest = m.downcast::<Vec<Value>>(&frame.args[0]).unwrap();
+        record.borrow_mut().push(rest.clone())
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let args: Vec<Value> = (1..4).map(Value::Int).collect();
+    assert_eq!(machine.call("foo.bar", &args), Ok(args[0]));
+    assert_eq!(*recorded.borrow(), vec![args[1..].to_vec()]);
+}
+
+#[test]
+fn reloads_module_while_its_old_code_is_running() {
+    use hivm2::vm::machine::{Frame, LinkError, TableValue};
+    use std::rc::Rc;
+
+    let first = parse_module("mod lib\nconst @greeting = test.value \"hello\"\nexport defn old() {\n  return\n}\nexport defn run() {\n  call test.reload()\n  x := lib.@greeting\n  return x\n}\n").compile();
+    let second = parse_module("mod lib\nconst @farewell = test.value \"goodbye\"\nexport defn run() {\n  x := lib.@farewell\n  return x\n}\n").compile();
+    let app = parse_module("mod app\nextern lib\ndefn main() {\n  return call lib.old()\n}\n").compile();
+
+    let mut machine = Machine::new();
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    // Replaces `lib` while `lib.run` is still running
+    machine.add_native(&"test.reload".to_owned(), Rc::new(move |m: &mut Machine, _: &Frame| {
+        m.reload_module(&second).unwrap()
+    }));
+    assert!(machine.load_verified_module(&first).is_ok());
+    assert!(machine.load_verified_module(&app).is_ok());
+    let old_site = machine.links.iter().find(|linked| linked.module == "app").unwrap().link.site;
+    let old_run = match machine.symbol_table.get_symbol(&"lib.run".to_owned()) {
+        Some(&TableValue::Defn(addr)) => addr,
+        other => panic!("Expected a function: {:?}", other),
+    };
+
+    // The running frame finishes with the old code and the old const
+    let greeting = machine.call("lib.run", &[]).unwrap();
+    assert_eq!(machine.downcast::<String>(&greeting).unwrap(), "hello");
+
+    let farewell = machine.call("lib.run", &[]).unwrap();
+    assert_eq!(machine.downcast::<String>(&farewell).unwrap(), "goodbye");
+
+    // The old code's sites weren't re-linked, so it still works
+    let greeting = machine.call_value(Value::Addr(old_run), &[]).unwrap();
+    assert_eq!(machine.downcast::<String>(&greeting).unwrap(), "hello");
+
+    // Symbols the new version doesn't define are gone, and sites referring to them are pending
+    assert!(machine.symbol_table.get_symbol(&"lib.@greeting".to_owned()).is_none());
+    assert!(machine.symbol_table.get_symbol(&"lib.old".to_owned()).is_none());
+    assert_eq!(machine.link(), Err(vec![
+        LinkError::Unresolved { module: "app".to_owned(), site: old_site, path: "lib.old".to_owned(), },
+    ]));
+}
+
+#[test]
+fn reloads_modules_without_leaking_consts_or_pending_links() {
+    let broken = parse_module("mod lib\nextern nowhere\nexport defn get() {\n  return call nowhere.f()\n}\n").compile();
+    let fixed = parse_module("mod lib\nconst @big = _.std.int.from_string \"123456789012345678901234567890\"\nexport defn get() {\n  x := lib.@big\n  return x\n}\n").compile();
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&broken).is_ok());
+    assert!(machine.link().is_err());
+
+    let mut sizes = vec![];
+    for _ in 0..5 {
+        assert!(machine.reload_module(&fixed).is_ok());
+        assert_eq!(machine.link(), Ok(()));
+        assert!(machine.call("lib.get", &[]).is_ok());
+        machine.unpin_all();
+        machine.gc();
+
+        sizes.push((machine.consts.len(), machine.heap.len()));
+    }
+
+    // Each reload makes a new big int, but the old ones are freed
+    assert!(sizes.windows(2).skip(1).all(|pair| pair[0] == pair[1]), "{:?}", sizes);
+}
+
+#[test]
+fn reloads_modules_in_place() {
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let library = |greeting: &str| {
+        parse_module(&format!("mod lib\nexport const @greeting = test.value \"{}\"\n", greeting)).compile()
+    };
+    let app = parse_module("mod app\nextern lib\ndefn get() {\n  x := lib.@greeting\n  return call test.record(x)\n}\n").compile();
+
+    let recorded: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = Machine::new();
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    let record = recorded.clone();
+    machine.add_native(&"test.record".to_owned(), Rc::new(move |m: &mut Machine, frame: &Frame| {
+        let greeting = m.downcast::<String>(&frame.args[0]).unwrap();
+        record.borrow_mut().push(greeting.clone())
+    }));
+
+    assert!(machine.load_verified_module(&library("hello")).is_ok());
+    assert!(machine.load_verified_module(&app).is_ok());
+
+    assert!(machine.call("app.get", &[]).is_ok());
+    assert!(machine.reload_module(&library("goodbye")).is_ok());
+    assert!(machine.call("app.get", &[]).is_ok());
+
+    assert_eq!(*recorded.borrow(), vec!["hello", "goodbye"]);
+    assert_eq!(machine.modules, vec!["lib", "app"]);
+}
+
+#[test]
+fn rejects_references_to_private_consts() {
+    use hivm2::vm::machine::{Frame, LinkError};
+    use std::rc::Rc;
+
+    let library = parse_module("mod lib\nconst @secret = test.value \"a\"\nexport const @public = test.value \"b\"\n").compile();
+    let app = parse_module("mod app\nextern lib\ndefn get() {\n  x := lib.@secret\n  return x\n}\n").compile();
+    assert_eq!(library.exports, vec!["@public"]);
+
+    let mut machine = Machine::new();
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    machine.load_module(&library).unwrap();
+    machine.load_module(&app).unwrap();
+
+    match machine.link() {
+        Err(errors) => match &errors[..] {
+            [LinkError::Private { ref module, ref path, .. }] => assert_eq!((&module[..], &path[..]), ("app", "lib.@secret")),
+            other => panic!("Expected a private symbol: {:?}", other),
+        },
+        Ok(()) => panic!("Expected a link error"),
+    }
+}
+
+#[test]
+fn rejects_references_to_private_functions() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::LinkError;
+
+    let library = parse_module("mod lib\ndefn secret() {\n  return\n}\n").compile();
+    let app = parse_module("mod app\nextern lib\nexport defn main() {\n  return call lib.secret()\n}\nexport defn get() {\n  f := lib.secret\n  return f\n}\n").compile();
+
+    // Whether the private function is loaded before or after the module referring to it
+    for modules in [[&library, &app], [&app, &library]].iter() {
+        let mut machine = Machine::new();
+        for module in modules.iter() {
+            assert!(machine.load_verified_module(module).is_ok());
+        }
+
+        let paths: Vec<(String, String)> = match machine.link() {
+            Err(errors) => errors.into_iter().map(|error| match error {
+                LinkError::Private { module, path, .. } => (module, path),
+                other => panic!("Expected a private symbol: {:?}", other),
+            }).collect(),
+            Ok(()) => panic!("Expected a link error"),
+        };
+        assert_eq!(paths, vec![("app".to_owned(), "lib.secret".to_owned()); 2]);
+
+        let unresolved = VmErrorKind::UnresolvedSymbol("lib.secret".to_owned());
+        assert_eq!(machine.call("app.main", &[]).map_err(|error| error.kind), Err(unresolved.clone()));
+        assert_eq!(machine.call("app.get", &[]).map_err(|error| error.kind), Err(unresolved));
+    }
+}
+
+#[test]
+fn reads_and_writes_statics() {
+    let library = |extra: &str| {
+        parse_module(&format!("mod lib\nexport static $count\nexport defn set(x) {{\n  $count = x\n  return\n}}\nexport defn get() {{\n  return $count\n}}\nexport defn show() {{\n  return call _.std.string.from_int($count)\n}}\n{}", extra)).compile()
+    };
+    let app = parse_module("mod app\nextern lib\nexport defn peek() {\n  x := lib.$count\n  return x\n}\nexport defn poke(x) {\n  call lib.set(x)\n  return lib.$count\n}\n").compile();
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&library("")).is_ok());
+    assert!(machine.load_verified_module(&app).is_ok());
+    assert_eq!(machine.link(), Ok(()));
+
+    assert_eq!(machine.call("lib.get", &[]), Ok(Value::Null));
+    assert_eq!(machine.call("lib.set", &[Value::Int(5)]), Ok(Value::Null));
+    assert_eq!(machine.call("lib.get", &[]), Ok(Value::Int(5)));
+    assert_eq!(machine.call("app.peek", &[]), Ok(Value::Int(5)));
+    assert_eq!(machine.call("app.poke", &[Value::Int(6)]), Ok(Value::Int(6)));
+    let shown = machine.call("lib.show", &[]).unwrap();
+    assert_eq!(machine.downcast::<String>(&shown).map(|s| &s[..]), Ok("6"));
+
+    // Reloading the module keeps the values of its statics
+    assert!(machine.reload_module(&library("defn other() {\n  return\n}\n")).is_ok());
+    assert_eq!(machine.call("lib.get", &[]), Ok(Value::Int(6)));
+    assert_eq!(machine.call("app.peek", &[]), Ok(Value::Int(6)));
+    assert_eq!(machine.statics.len(), 1);
+}
+
+#[test]
+fn rejects_links_to_the_wrong_kind_of_symbol() {
+    use hivm2::asm_compiler::CompiledRelocationTarget;
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::{Frame, LinkError};
+    use std::rc::Rc;
+
+    let library = parse_module("mod lib\nexport static $count\nexport const @name = test.value \"lib\"\n").compile();
+    let mut app = parse_module("mod app\nextern lib\nexport defn count() {\n  return call lib.count()\n}\nexport defn name() {\n  return call lib.name()\n}\n").compile();
+
+    // The parser doesn't allow calls to consts and statics, but compiled modules can have them
+    for &mut (_, ref mut target) in app.relocations.iter_mut() {
+        if let &mut CompiledRelocationTarget::ExternalFunctionPath(ref mut path) = target {
+            *path = if path == "lib.count" { "lib.$count" } else { "lib.@name" }.to_owned();
+        }
+    }
+
+    let mut machine = Machine::new();
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    assert!(machine.load_verified_module(&library).is_ok());
+    assert!(machine.load_verified_module(&app).is_ok());
+
+    let paths: Vec<String> = match machine.link() {
+        Err(errors) => errors.into_iter().map(|error| match error {
+            LinkError::Mismatch { module, path, .. } => { assert_eq!(module, "app"); path },
+            other => panic!("Expected a mismatched symbol: {:?}", other),
+        }).collect(),
+        Ok(()) => panic!("Expected a link error"),
+    };
+    assert_eq!(paths, vec!["lib.$count", "lib.@name"]);
+
+    let unresolved = VmErrorKind::UnresolvedSymbol("lib.$count".to_owned());
+    assert_eq!(machine.call("app.count", &[]).map_err(|error| error.kind), Err(unresolved));
+}
+
+#[test]
+fn rejects_references_to_primitives_that_are_not_calls() {
+    use hivm2::asm_compiler::CompiledRelocationTarget;
+    use hivm2::vm::machine::LinkError;
+
+    let mut app = parse_module("mod app\nexport defn get() {\n  f := _.std.bool\n  return f\n}\n").compile();
+
+    // Compiled modules can refer to a primitive's address from an op that isn't a call
+    for &mut (_, ref mut target) in app.relocations.iter_mut() {
+        *target = CompiledRelocationTarget::ExternalFunctionPath("_.std.bool".to_owned());
+    }
+
+    let mut machine = Machine::new();
+    assert!(machine.load_module(&app).is_ok());
+
+    match machine.link() {
+        Err(errors) => match &errors[..] {
+            [LinkError::Mismatch { ref module, ref path, .. }] => assert_eq!((&module[..], &path[..]), ("app", "_.std.bool")),
+            other => panic!("Expected a mismatched symbol: {:?}", other),
+        },
+        Ok(()) => panic!("Expected a link error"),
+    }
+}
+
+#[test]
+fn calls_functions_in_other_modules() {
+    use hivm2::vm::machine::{Frame, TableValue};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let library = |native: &str| {
+        parse_module(&format!("mod lib\nexport defn get() {{\n  return call helper()\n}}\ndefn helper() {{\n  return call test.{}()\n}}\n", native)).compile()
+    };
+    let app = parse_module("mod app\nextern lib\ndefn run() {\n  return call lib.get()\n}\n").compile();
+
+    let called: Rc<RefCell<Vec<&'static str>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = Machine::new();
+    for &name in ["first", "second"].iter() {
+        let called = called.clone();
+        machine.add_native(&format!("test.{}", name), Rc::new(move |_: &mut Machine, _: &Frame| {
+            called.borrow_mut().push(name)
+        }));
+    }
+
+    let first = library("first");
+    assert!(machine.load_verified_module(&first).is_ok());
+    assert!(machine.load_verified_module(&app).is_ok());
+
+    match machine.symbol_table.lookup_symbol(&"lib.get".to_owned()) {
+        &TableValue::Defn(addr) => assert_eq!(addr, first.functions[0].1),
+        other => panic!("Expected a function: {:?}", other),
+    }
+    assert!(!machine.symbol_table.is_visible_from(&"lib.helper".to_owned(), "app"));
+
+    assert!(machine.call("app.run", &[]).is_ok());
+    assert!(machine.reload_module(&library("second")).is_ok());
+    assert!(machine.call("app.run", &[]).is_ok());
+
+    assert_eq!(*called.borrow(), vec!["first", "second"]);
+}
+
+#[test]
+fn links_modules_loaded_in_any_order() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let ping = parse_module("mod ping\nextern pong\nexport defn run(n) {\n  call test.note(n)\n  return call pong.run(n)\n}\n").compile();
+    let pong = parse_module("mod pong\nextern ping\nexport defn run(n) {\n  call test.note(n)\n  return call test.stop(n)\n}\n").compile();
+
+    let noted: Rc<RefCell<usize>> = Rc::new(RefCell::new(0));
+    let mut machine = Machine::new();
+    machine.load_module(&ping).unwrap();
+    assert!(machine.link().is_err());
+    machine.load_module(&pong).unwrap();
+
+    {
+        let noted = noted.clone();
+        machine.add_native(&"test.note".to_owned(), Rc::new(move |_: &mut Machine, _: &Frame| {
+            *noted.borrow_mut() += 1
+        }));
+    }
+    assert!(machine.link().is_err());
+    machine.add_native(&"test.stop".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.raise(frame.args[0])
+    }));
+    assert!(machine.link().is_ok());
+
+    let value = Value::Int(8);
+    assert_eq!(machine.call("ping.run", &[value]).map_err(|error| error.kind), Err(VmErrorKind::UncaughtException(value)));
+    assert_eq!(*noted.borrow(), 2);
+}
+
+#[test]
+fn reports_and_traps_unresolved_symbols() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::LinkError;
+
+    let app = parse_module("mod app\ndefn run() {\n  x := missing.@value\n  call missing.first()\n  return call missing.second()\n}\n").compile();
+
+    let mut machine = Machine::new();
+    machine.load_module(&app).unwrap();
+
+    match machine.link() {
+        Err(errors) => {
+            let paths: Vec<&str> = errors.iter().map(|error| match error {
+                &LinkError::Unresolved { ref path, .. } => &path[..],
+                other => panic!("Expected an unresolved symbol: {:?}", other),
+            }).collect();
+            assert_eq!(paths, vec!["missing.@value", "missing.first", "missing.second"]);
+        },
+        Ok(()) => panic!("Expected link errors"),
+    }
+
+    assert_eq!(machine.call("app.run", &[]).map_err(|error| error.kind), Err(VmErrorKind::UnresolvedSymbol("missing.@value".to_owned())));
+}
+
+#[test]
+fn calls_functions_from_the_host() {
+    use hivm2::vm::interpreter::{VmError, VmErrorKind};
+    use hivm2::vm::machine::Frame;
+    use std::rc::Rc;
+
+    let source = "mod app\n\
+                  export defn outer(x) {\n\
+                  \x20 return call test.callback(x)\n\
+                  }\n\
+                  defn inner(x) {\n\
+                  \x20 return x\n\
+                  }\n\
+                  defn guarded(x) {\n\
+                  \x20 try {\n\
+                  \x20   call test.rethrow(x)\n\
+                  \x20 } catch e {\n\
+                  \x20   return e\n\
+                  \x20 }\n\
+                  \x20 return x\n\
+                  }\n\
+                  defn fails(x) {\n\
+                  \x20 throw x\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let mut machine = Machine::new();
+    // Natives call back into the VM and pass on the results
+    machine.add_native(&"test.callback".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        let value = m.call("app.inner", &frame.args).unwrap();
+        m.stack.push(value)
+    }));
+    machine.add_native(&"test.rethrow".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        match m.call("app.fails", &frame.args) {
+            Err(VmError { kind: VmErrorKind::UncaughtException(value), .. }) => m.raise(value),
+            other => panic!("Expected an exception: {:?}", other),
+        }
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let value = Value::Int(8);
+    assert_eq!(machine.call("app.outer", &[value]), Ok(value));
+    assert_eq!(machine.call("app.guarded", &[value]), Ok(value));
+    assert_eq!(machine.call("app.fails", &[value]).map_err(|error| error.kind), Err(VmErrorKind::UncaughtException(value)));
+    assert_eq!(machine.call("app.missing", &[]).map_err(|error| error.kind), Err(VmErrorKind::UnresolvedSymbol("app.missing".to_owned())));
+    assert!(machine.call_stack.is_empty());
+    assert!(machine.stack.is_empty());
+}
+
+#[test]
+fn reports_errors_with_a_backtrace() {
+    use hivm2::vm::interpreter::VmErrorKind;
+
+    let source = "mod app\n\
+                  export defn run(x) {\n\
+                  \x20 call helper(x)\n\
+                  \x20 return x\n\
+                  }\n\
+                  defn helper(x) {\n\
+                  \x20 throw x\n\
+                  }\n";
+    let mut module = parse_module(source);
+    module.file = Some("app.hasm".to_owned());
+    let compiled = module.compile();
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let value = Value::Int(8);
+    let error = machine.call("app.run", &[value]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::UncaughtException(value));
+    assert_eq!(error.backtrace[0].addr, error.ip);
+
+    let functions: Vec<Option<&str>> = error.backtrace.iter().map(|entry| entry.function.as_ref().map(|name| &name[..])).collect();
+    assert_eq!(functions, vec![Some("app.helper"), Some("app.run")]);
+    let lines: Vec<Option<u32>> = error.backtrace.iter().map(|entry| entry.line).collect();
+    assert_eq!(lines, vec![Some(7), Some(3)]);
+
+    let report = format!("{}", error);
+    assert!(report.contains("at app.helper (app.hasm:7)"));
+    assert!(report.contains("at app.run (app.hasm:3)"));
+}
+
+/// Machine that collects garbage before every allocation, with natives that allocate.
+fn stress_machine() -> Machine {
+    use hivm2::vm::machine::Frame;
+    use hivm2::vm::value::GcConfig;
+    use std::rc::Rc;
+
+    let mut machine = Machine::new();
+    machine.set_gc_config(Some(GcConfig::stress()));
+
+    machine.add_native(&"test.value".to_owned(), Rc::new(|m: &mut Machine, frame: &Frame| {
+        m.stack.push(frame.args[0])
+    }));
+    // Allocates several values before any of them is reachable from the stack
+    machine.add_native(&"test.make".to_owned(), Rc::new(|m: &mut Machine, _: &Frame| {
+        let items: Vec<Value> = (0..3).map(|n| m.alloc(format!("item{}", n))).collect();
+        let list = m.alloc(items);
+        m.stack.push(list)
+    }));
+    machine.add_native(&"test.fail".to_owned(), Rc::new(|m: &mut Machine, _: &Frame| {
+        m.raise_error("failed".to_owned())
+    }));
+    machine
+}
+
+fn strings(machine: &Machine, list: &Value) -> Vec<String> {
+    machine.downcast::<Vec<Value>>(list).unwrap().iter().map(|item| {
+        machine.downcast::<String>(item).unwrap().clone()
+    }).collect()
+}
+
+#[test]
+fn keeps_uncaught_exceptions_returned_to_the_host() {
+    use hivm2::vm::interpreter::VmErrorKind;
+
+    let mut machine = stress_machine();
+    let exception = match machine.call("test.fail", &[]).map_err(|error| error.kind) {
+        Err(VmErrorKind::UncaughtException(value)) => value,
+        other => panic!("Expected an exception: {:?}", other),
+    };
+
+    // Each allocation collects, and the exception is only held by its pin
+    for n in 0..3 {
+        machine.alloc(format!("garbage{}", n));
+    }
+    assert_eq!(machine.downcast::<String>(&exception), Ok(&"failed".to_owned()));
+
+    // Results that aren't heap objects don't need pinning
+    machine.unpin_all();
+    assert_eq!(machine.call("test.value", &[Value::Int(1)]), Ok(Value::Int(1)));
+    assert!(machine.pinned.is_empty());
+
+    // Once released the exception is collected, and its handle doesn't reach the object
+    // allocated in its place
+    machine.gc();
+    let replacement = machine.alloc("replacement".to_owned());
+    assert_eq!(machine.downcast::<String>(&exception).unwrap_err().found, "collected object");
+    assert_eq!(machine.downcast::<String>(&replacement), Ok(&"replacement".to_owned()));
+}
+
+#[test]
+fn keeps_reachable_values_when_collecting_on_every_allocation() {
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "mod app\n\
+                  const @greeting = test.value \"hello\"\n\
+                  export defn run(a, ...rest) {\n\
+                  \x20 x := call test.make()\n\
+                  \x20 y := call test.make()\n\
+                  \x20 call test.check(x, rest)\n\
+                  \x20 g := app.@greeting\n\
+                  \x20 return g\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let checked: Rc<RefCell<Vec<Vec<String>>>> = Rc::new(RefCell::new(vec![]));
+    let mut machine = stress_machine();
+    let record = checked.clone();
+    machine.add_native(&"test.check".to_owned(), Rc::new(move |m: &mut Machine, frame: &Frame| {
+        // Allocating here collects again while the arguments are only held by this native
+        m.alloc("garbage".to_owned());
+        record.borrow_mut().push(strings(m, &frame.args[0]));
+        record.borrow_mut().push(strings(m, &frame.args[1]));
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let a = machine.alloc("a".to_owned());
+    let b = machine.alloc("b".to_owned());
+    let result = machine.call("app.run", &[a, b]).unwrap();
+    assert_eq!(machine.downcast::<String>(&result), Ok(&"hello".to_owned()));
+    assert_eq!(*checked.borrow(), vec![vec!["item0", "item1", "item2"], vec!["b"]]);
+
+    // Arguments passed straight to a native are only held by its frame
+    let item = machine.alloc("c".to_owned());
+    let list = machine.alloc(vec![item]);
+    machine.unpin_all();
+    assert!(machine.call("test.check", &[list, list]).is_ok());
+    assert_eq!(checked.borrow()[3], vec!["c"]);
+
+    // Only the const survives once the host lets go of its values
+    machine.unpin_all();
+    machine.gc();
+    assert_eq!(machine.heap.len(), 1);
+}
+
+#[test]
+fn keeps_exceptions_alive_when_collecting_on_every_allocation() {
+    let source = "mod app\n\
+                  export defn run() {\n\
+                  \x20 try {\n\
+                  \x20   call test.fail()\n\
+                  \x20 } catch e {\n\
+                  \x20   x := call test.make()\n\
+                  \x20   return e\n\
+                  \x20 }\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let mut machine = stress_machine();
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let result = machine.call("app.run", &[]).unwrap();
+    machine.alloc("garbage".to_owned());
+    assert_eq!(machine.downcast::<String>(&result), Ok(&"failed".to_owned()));
+}
+
+#[test]
+fn produces_null_values() {
+    use hivm2::vm::machine::TableValue;
+
+    let source = "mod app\n\
+                  static $count\n\
+                  export defn empty() {\n\
+                  \x20 return\n\
+                  }\n\
+                  export defn fallsoff() {\n\
+                  \x20 call empty()\n\
+                  }\n\
+                  export defn fresh() {\n\
+                  \x20 local x\n\
+                  \x20 return x\n\
+                  }\n\
+                  export defn canonical() {\n\
+                  \x20 x := _.std.null\n\
+                  \x20 return x\n\
+                  }\n\
+                  export defn truth(x) {\n\
+                  \x20 return call _.std.bool(x)\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    for &path in ["app.empty", "app.fallsoff", "app.fresh", "app.canonical"].iter() {
+        assert_eq!(machine.call(path, &[]), Ok(Value::Null));
+    }
+    assert_eq!(machine.call("app.truth", &[Value::Int(0)]), Ok(Value::True));
+    assert_eq!(machine.call("app.truth", &[Value::Null]), Ok(Value::Null));
+
+    match machine.symbol_table.lookup_symbol(&"app.$count".to_owned()) {
+        &TableValue::Static(slot) => assert!(machine.statics[slot as usize].is_null()),
+        other => panic!("Expected a static: {:?}", other),
+    }
+    assert!(machine.stack.is_empty());
+}
+
+#[test]
+fn computes_with_ints_of_any_size() {
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "mod app\n\
+                  const @big = _.std.int.from_string \"123456789012345678901234567890\"\n\
+                  export defn scale(n) {\n\
+                  \x20 big := app.@big\n\
+                  \x20 product := call _.std.int.mul(big, n)\n\
+                  \x20 small := call _.std.int.div(product, big)\n\
+                  \x20 call test.record(small)\n\
+                  \x20 return call _.std.int.to_string(product)\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let mut machine = stress_machine();
+    let results: Rc<RefCell<Vec<Value>>> = Rc::new(RefCell::new(vec![]));
+    let record = results.clone();
+    machine.add_native(&"test.record".to_owned(), Rc::new(move |_: &mut Machine, frame: &Frame| {
+        record.borrow_mut().push(frame.args[0])
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    let result = machine.call("app.scale", &[Value::Int(-1000)]).unwrap();
+    assert_eq!(machine.downcast::<String>(&result), Ok(&"-123456789012345678901234567890000".to_owned()));
+    assert_eq!(*results.borrow(), vec![Value::Int(-1000)]);
+
+    let result = machine.call("app.scale", &[Value::Int(0)]).unwrap();
+    assert_eq!(machine.downcast::<String>(&result), Ok(&"0".to_owned()));
+}
+
+#[test]
+fn invokes_function_values() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::value::TypeError;
+
+    let source = "mod app\n\
+                  defn square(x) {\n\
+                  \x20 return call _.std.int.mul(x, x)\n\
+                  }\n\
+                  defn collect(...xs) {\n\
+                  \x20 return xs\n\
+                  }\n\
+                  export defn run(n) {\n\
+                  \x20 add := _.std.int.add\n\
+                  \x20 a := call _.std.fn.call(add, n, n)\n\
+                  \x20 square := app.square\n\
+                  \x20 b := call square(a)\n\
+                  \x20 inc := fn(x) {\n\
+                  \x20   one := call _.std.args.count()\n\
+                  \x20   return call _.std.int.add(x, one)\n\
+                  \x20 }\n\
+                  \x20 c := call inc(b)\n\
+                  \x20 list := call collect(c, n)\n\
+                  \x20 d := call _.std.fn.apply(add, list)\n\
+                  \x20 return call add(d, n)\n\
+                  }\n\
+                  export defn pick(f) {\n\
+                  \x20 return call f()\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    let mut machine = Machine::new();
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    // ((3 + 3)^2 + 1) + 3 + 3
+    assert_eq!(machine.call("app.run", &[Value::Int(3)]), Ok(Value::Int(43)));
+    assert!(machine.stack.is_empty());
+
+    let error = machine.call("app.pick", &[Value::Int(1)]).map_err(|error| error.kind);
+    assert_eq!(error, Err(VmErrorKind::TypeError(TypeError { expected: "fn", found: "int", })));
+}
+
+#[test]
+fn runs_tail_recursion_in_constant_stack_space() {
+    use hivm2::vm::interpreter::VmErrorKind;
+    use hivm2::vm::machine::Frame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "mod app\n\
+                  export defn count(n) {\n\
+                  \x20 m := call test.step(n)\n\
+                  \x20 return call count(m)\n\
+                  }\n\
+                  defn through(f, n) {\n\
+                  \x20 m := call test.step(n)\n\
+                  \x20 return call f(f, m)\n\
+                  }\n\
+                  export defn start(n) {\n\
+                  \x20 f := app.through\n\
+                  \x20 return call f(f, n)\n\
+                  }\n";
+    let compiled = parse_module(source).compile();
+
+    // Records the depth of the call stack on every step and stops the recursion after 10000
+    let depths: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(vec![]));
+    let record = depths.clone();
+    let mut machine = Machine::new();
+    machine.add_native(&"test.step".to_owned(), Rc::new(move |m: &mut Machine, frame: &Frame| {
+        record.borrow_mut().push(m.call_stack.len());
+        match frame.args[0] {
+            Value::Int(n) if n < 10000 => m.stack.push(Value::Int(n + 1)),
+            _ => m.raise(Value::Null),
+        }
+    }));
+    assert!(machine.load_verified_module(&compiled).is_ok());
+
+    for &path in ["app.count", "app.start"].iter() {
+        depths.borrow_mut().clear();
+
+        let result = machine.call(path, &[Value::Int(0)]).map_err(|error| error.kind);
+        assert_eq!(result, Err(VmErrorKind::UncaughtException(Value::Null)));
+        assert_eq!(depths.borrow().len(), 10001);
+        assert!(depths.borrow().iter().all(|&depth| depth == 1));
+        assert!(machine.call_stack.is_empty());
+    }
 }